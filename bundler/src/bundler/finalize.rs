@@ -112,7 +112,7 @@ where
             }
 
             new = new.move_map(|bundle| {
-                let path = match self.scope.get_module(bundle.id).unwrap().fm.name {
+                let path = match *self.scope.get_module(bundle.id).unwrap().fm.name {
                     FileName::Real(ref v) => v.clone(),
                     _ => {
                         log::error!("Cannot rename: not a real file");
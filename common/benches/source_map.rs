@@ -0,0 +1,174 @@
+#![feature(test)]
+
+extern crate test;
+
+use std::hint::black_box;
+use swc_common::{BytePos, FileName, FilePathMapping, SourceMap, Span, DUMMY_SP};
+use test::Bencher;
+
+fn init() -> (SourceMap, Vec<Span>) {
+    let cm = SourceMap::new(FilePathMapping::empty());
+    let mut spans = vec![];
+
+    for i in 0..3 {
+        let src = "const foo = bar(baz, '€');\n".repeat(100);
+        let fm = cm.new_source_file(FileName::Custom(format!("file{}.js", i)), src);
+
+        for j in 0..100 {
+            let lo = fm.start_pos + BytePos(j * 29);
+            spans.push(Span::new(lo, lo + BytePos(9), DUMMY_SP.ctxt));
+        }
+    }
+
+    (cm, spans)
+}
+
+fn init_one_file() -> (SourceMap, Vec<Span>) {
+    let cm = SourceMap::new(FilePathMapping::empty());
+    let src = "const foo = bar(baz, '€');\n".repeat(100);
+    let fm = cm.new_source_file(FileName::Custom("file.js".into()), src);
+
+    let spans = (0..100)
+        .rev()
+        .map(|i| {
+            let lo = fm.start_pos + BytePos(i * 29);
+            Span::new(lo, lo + BytePos(9), DUMMY_SP.ctxt)
+        })
+        .collect();
+
+    (cm, spans)
+}
+
+#[bench]
+fn span_to_snippet_loop(b: &mut Bencher) {
+    let (cm, spans) = init();
+
+    b.iter(|| {
+        for &sp in &spans {
+            black_box(cm.span_to_snippet(sp).unwrap());
+        }
+    });
+}
+
+#[bench]
+fn snippets_of_spans(b: &mut Bencher) {
+    let (cm, spans) = init();
+
+    b.iter(|| {
+        black_box(cm.snippets_of_spans(&spans));
+    });
+}
+
+#[bench]
+fn span_to_snippet_loop_one_file(b: &mut Bencher) {
+    let (cm, spans) = init_one_file();
+
+    b.iter(|| {
+        for &sp in &spans {
+            black_box(cm.span_to_snippet(sp).unwrap());
+        }
+    });
+}
+
+#[bench]
+fn with_snippets_of_spans_one_file(b: &mut Bencher) {
+    let (cm, spans) = init_one_file();
+
+    b.iter(|| {
+        cm.with_snippets_of_spans(&spans, |_, snippet| {
+            black_box(snippet.unwrap());
+        });
+    });
+}
+
+#[bench]
+fn lookup_char_pos_live(b: &mut Bencher) {
+    let (cm, spans) = init();
+
+    b.iter(|| {
+        for sp in &spans {
+            black_box(cm.lookup_char_pos(sp.lo()));
+        }
+    });
+}
+
+#[bench]
+fn lookup_char_pos_frozen(b: &mut Bencher) {
+    let (cm, spans) = init();
+    let cm = cm.freeze();
+
+    b.iter(|| {
+        for sp in &spans {
+            black_box(cm.lookup_char_pos(sp.lo()));
+        }
+    });
+}
+
+#[cfg(feature = "concurrent")]
+#[bench]
+fn new_source_file_concurrent(b: &mut Bencher) {
+    use rayon::prelude::*;
+
+    let src = "const foo = bar(baz, '€');\n".repeat(1000);
+
+    b.iter(|| {
+        let cm = SourceMap::new(FilePathMapping::empty());
+
+        (0..64).into_par_iter().for_each(|i| {
+            let fm = cm.new_source_file(FileName::Custom(format!("file{}.js", i)), src.clone());
+            black_box(cm.lookup_char_pos(fm.start_pos + BytePos(29)));
+        });
+    });
+}
+
+#[bench]
+fn lookup_char_pos_ascii(b: &mut Bencher) {
+    let cm = SourceMap::new(FilePathMapping::empty());
+    let src = "const foo = bar(baz, 'x');\n".repeat(1000);
+    let fm = cm.new_source_file(FileName::Custom("file.js".into()), src);
+    let spans = (0..1000)
+        .map(|i| {
+            let lo = fm.start_pos + BytePos(i * 27);
+            Span::new(lo, lo + BytePos(9), DUMMY_SP.ctxt)
+        })
+        .collect::<Vec<_>>();
+
+    b.iter(|| {
+        for &sp in &spans {
+            black_box(cm.lookup_char_pos(sp.hi()));
+        }
+    });
+}
+
+fn init_multibyte() -> (SourceMap, Vec<BytePos>) {
+    let cm = SourceMap::new(FilePathMapping::empty());
+    let src = "const 가나 = bar('€€€', \"다라\");\n".repeat(1000);
+    let fm = cm.new_source_file(FileName::Custom("file.js".into()), src.clone());
+    let positions = src
+        .char_indices()
+        .step_by(7)
+        .map(|(idx, _)| fm.start_pos + BytePos(idx as u32))
+        .collect();
+
+    (cm, positions)
+}
+
+#[bench]
+fn lookup_char_pos_loop_multibyte(b: &mut Bencher) {
+    let (cm, positions) = init_multibyte();
+
+    b.iter(|| {
+        for &pos in &positions {
+            black_box(cm.lookup_char_pos(pos));
+        }
+    });
+}
+
+#[bench]
+fn lookup_char_pos_many_multibyte(b: &mut Bencher) {
+    let (cm, positions) = init_multibyte();
+
+    b.iter(|| {
+        black_box(cm.lookup_char_pos_many(&positions));
+    });
+}
@@ -13,13 +13,16 @@ pub use self::{
     diagnostic::{Diagnostic, DiagnosticId, DiagnosticStyledString, SubDiagnostic},
     diagnostic_builder::DiagnosticBuilder,
     emitter::{ColorConfig, Emitter, EmitterWriter},
+    export::{export_diagnostic, DiagnosticExport, LabelKind},
 };
 #[cfg(feature = "tty-emitter")]
 use crate::sync::Lrc;
 use crate::{
     rustc_data_structures::stable_hasher::StableHasher,
     sync::{Lock, LockCell},
-    syntax_pos::{BytePos, FileLinesResult, FileName, Loc, MultiSpan, Span, NO_EXPANSION},
+    syntax_pos::{
+        BytePos, FileLinesResult, FileName, Loc, MultiSpan, SourceFile, Span, NO_EXPANSION,
+    },
 };
 use std::{
     borrow::Cow,
@@ -34,6 +37,7 @@ use termcolor::{Color, ColorSpec};
 mod diagnostic;
 mod diagnostic_builder;
 pub mod emitter;
+mod export;
 mod lock;
 mod snippet;
 mod styled_buffer;
@@ -110,6 +114,23 @@ pub trait SourceMapper: crate::sync::Send + crate::sync::Sync {
     fn merge_spans(&self, sp_lhs: Span, sp_rhs: Span) -> Option<Span>;
     fn call_span_if_macro(&self, sp: Span) -> Span;
     fn doctest_offset_line(&self, line: usize) -> usize;
+
+    /// Returns the 0-based line `line_index` of `file`, without the line
+    /// break.
+    ///
+    /// Unlike [SourceFile::get_line], this loads the source code of files
+    /// added by [crate::SourceMap::load_file_metadata_only].
+    fn source_line(&self, file: &SourceFile, line_index: usize) -> Option<String> {
+        file.get_line(line_index).map(Cow::into_owned)
+    }
+
+    /// `line` is the 1-based line of `col`, before applying
+    /// [SourceMapper::doctest_offset_line].
+    ///
+    /// The default implementation doesn't offset columns.
+    fn doctest_offset_col(&self, _line: usize, col: usize) -> usize {
+        col
+    }
 }
 
 impl CodeSuggestion {
@@ -179,7 +200,9 @@ impl CodeSuggestion {
                 let mut prev_hi = cm.lookup_char_pos(bounding_span.lo());
                 prev_hi.col = CharPos::from_usize(0);
 
-                let mut prev_line = fm.get_line(lines.lines[0].line_index);
+                let mut prev_line = cm
+                    .source_line(fm, lines.lines[0].line_index)
+                    .map(Cow::Owned);
                 let mut buf = String::new();
 
                 for part in &substitution.parts {
@@ -190,18 +213,18 @@ impl CodeSuggestion {
                         push_trailing(&mut buf, prev_line.as_ref(), &prev_hi, None);
                         // push lines between the previous and current span (if any)
                         for idx in prev_hi.line..(cur_lo.line - 1) {
-                            if let Some(line) = fm.get_line(idx) {
-                                buf.push_str(line.as_ref());
+                            if let Some(line) = cm.source_line(fm, idx) {
+                                buf.push_str(&line);
                                 buf.push('\n');
                             }
                         }
-                        if let Some(cur_line) = fm.get_line(cur_lo.line - 1) {
+                        if let Some(cur_line) = cm.source_line(fm, cur_lo.line - 1) {
                             buf.push_str(&cur_line[..cur_lo.col.to_usize()]);
                         }
                     }
                     buf.push_str(&part.snippet);
                     prev_hi = cm.lookup_char_pos(part.span.hi());
-                    prev_line = fm.get_line(prev_hi.line - 1);
+                    prev_line = cm.source_line(fm, prev_hi.line - 1).map(Cow::Owned);
                 }
                 // if the replacement already ends with a newline, don't print the next line
                 if !buf.ends_with('\n') {
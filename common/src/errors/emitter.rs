@@ -15,6 +15,7 @@ use super::{
     CodeSuggestion, DiagnosticBuilder, DiagnosticId, Level, SourceMapperDyn, SubDiagnostic,
 };
 use crate::{
+    source_map::SnippetWindow,
     sync::Lrc,
     syntax_pos::{MultiSpan, SourceFile, Span},
 };
@@ -138,6 +139,7 @@ pub struct EmitterWriter {
     short_message: bool,
     teach: bool,
     ui_testing: bool,
+    max_line_width: Option<usize>,
 }
 
 struct FileWithAnnotatedLines {
@@ -161,6 +163,7 @@ impl EmitterWriter {
             short_message,
             teach,
             ui_testing: false,
+            max_line_width: None,
         }
     }
 }
@@ -178,6 +181,7 @@ impl EmitterWriter {
             short_message,
             teach,
             ui_testing: false,
+            max_line_width: None,
         }
     }
 
@@ -186,6 +190,41 @@ impl EmitterWriter {
         self
     }
 
+    /// Truncates source lines wider than `max_line_width` columns, keeping the
+    /// annotated part of the line visible.
+    pub fn max_line_width(mut self, max_line_width: usize) -> Self {
+        self.max_line_width = Some(max_line_width);
+        self
+    }
+
+    /// Returns the part of `source_string` to print along with the
+    /// annotations of `line` adjusted to it, if `source_string` is too wide.
+    fn truncate_line(&self, source_string: &str, line: &Line) -> Option<(String, Line)> {
+        let max_width = self.max_line_width?;
+
+        let cols = line
+            .annotations
+            .iter()
+            .filter(|a| !a.is_line())
+            .map(|a| (a.start_col, a.end_col));
+        let start_col = cols.clone().map(|c| c.0).min().unwrap_or(0);
+        let end_col = cols.map(|c| c.1).max().unwrap_or(0);
+
+        let window = SnippetWindow::new(source_string, start_col, end_col, max_width);
+        if !window.truncated_left && !window.truncated_right {
+            return None;
+        }
+
+        let mut line = line.clone();
+        for ann in &mut line.annotations {
+            if !ann.is_line() {
+                ann.start_col = window.map_col(ann.start_col);
+                ann.end_col = window.map_col(ann.end_col);
+            }
+        }
+        Some((window.line, line))
+    }
+
     fn maybe_anonymized(&self, line_num: usize) -> String {
         if self.ui_testing {
             ANONYMIZED_LINE_NUM.to_string()
@@ -335,10 +374,19 @@ impl EmitterWriter {
             return Vec::new();
         }
 
-        let source_string = match file.get_line(line.line_index - 1) {
-            Some(s) => s,
+        let source_string = match self
+            .sm
+            .as_ref()
+            .and_then(|sm| sm.source_line(&file, line.line_index - 1))
+        {
+            Some(s) => Cow::Owned(s),
             None => return Vec::new(),
         };
+        let truncated = self.truncate_line(&source_string, line);
+        let (source_string, line) = match truncated {
+            Some((ref s, ref l)) => (Cow::Borrowed(&**s), l),
+            None => (source_string, line),
+        };
 
         let line_offset = buffer.num_lines();
 
@@ -1017,7 +1065,7 @@ impl EmitterWriter {
                             "{}:{}:{}",
                             loc.file.name,
                             sm.doctest_offset_line(loc.line),
-                            loc.col.0 + 1
+                            sm.doctest_offset_col(loc.line, loc.col.0) + 1
                         ),
                         Style::LineAndColumn,
                     );
@@ -1031,7 +1079,7 @@ impl EmitterWriter {
                             "{}:{}:{}: ",
                             loc.file.name,
                             sm.doctest_offset_line(loc.line),
-                            loc.col.0 + 1
+                            sm.doctest_offset_col(loc.line, loc.col.0) + 1
                         ),
                         Style::LineAndColumn,
                     );
@@ -1047,7 +1095,9 @@ impl EmitterWriter {
                 buffer.prepend(buffer_msg_line_offset + 1, "::: ", Style::LineNumber);
                 let loc = if let Some(first_line) = annotated_file.lines.first() {
                     let col = if let Some(first_annotation) = first_line.annotations.first() {
-                        format!(":{}", first_annotation.start_col + 1)
+                        let col = sm
+                            .doctest_offset_col(first_line.line_index, first_annotation.start_col);
+                        format!(":{}", col + 1)
                     } else {
                         String::new()
                     };
@@ -1134,10 +1184,19 @@ impl EmitterWriter {
                                 );
                             }
                         } else if line_idx_delta == 2 {
-                            let unannotated_line = annotated_file
-                                .file
-                                .get_line(annotated_file.lines[line_idx].line_index)
+                            let unannotated_line = sm
+                                .source_line(
+                                    &annotated_file.file,
+                                    annotated_file.lines[line_idx].line_index,
+                                )
+                                .map(Cow::Owned)
                                 .unwrap_or_else(|| Cow::from(""));
+                            let unannotated_line = match self.max_line_width {
+                                Some(max_width) => Cow::Owned(
+                                    SnippetWindow::new(&unannotated_line, 0, 0, max_width).line,
+                                ),
+                                None => unannotated_line,
+                            };
 
                             let last_buffer_line_num = buffer.num_lines();
 
@@ -1636,3 +1695,113 @@ impl<'a> Drop for WritableDst<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{errors::Handler, BytePos, FileName, SourceMap, NO_EXPANSION};
+    use std::{
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn truncate_long_line() {
+        let cm = Lrc::new(SourceMap::default());
+        let src = format!("{}target{}", "a".repeat(1000), "b".repeat(1000));
+        let fm = cm.new_source_file(FileName::Anon, src);
+        let lo = fm.start_pos + BytePos(1000);
+
+        let buf = Buf::default();
+        let emitter = EmitterWriter::new(Box::new(buf.clone()), Some(cm.clone()), false, false)
+            .max_line_width(20);
+        let handler = Handler::with_emitter_and_flags(Box::new(emitter), Default::default());
+        handler
+            .struct_span_err(Span::new(lo, lo + BytePos(6), NO_EXPANSION), "bad")
+            .emit();
+
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("1 | …aaaaaaatargetbbbbb…\n"), "{}", output);
+        assert!(output.contains("  |         ^^^^^^\n"), "{}", output);
+    }
+
+    #[test]
+    fn doctest_offset_col() {
+        let cm = Lrc::new(SourceMap::default().with_doctest_offset(
+            FileName::Custom("index.html".into()),
+            9,
+            4,
+        ));
+        let src = "let a;\nlet b;";
+        let fm = cm.new_source_file(FileName::Custom("script".into()), src.into());
+
+        let emit = |pos: usize| {
+            let buf = Buf::default();
+            let emitter = EmitterWriter::new(Box::new(buf.clone()), Some(cm.clone()), false, false);
+            let handler = Handler::with_emitter_and_flags(Box::new(emitter), Default::default());
+            let lo = fm.start_pos + BytePos(pos as u32);
+            handler
+                .struct_span_err(Span::new(lo, lo + BytePos(1), NO_EXPANSION), "bad")
+                .emit();
+            let output = buf.0.lock().unwrap().clone();
+            String::from_utf8(output).unwrap()
+        };
+
+        let output = emit(src.find('a').unwrap());
+        assert!(output.contains("--> <script>:10:9\n"), "{}", output);
+        let output = emit(src.find('b').unwrap());
+        assert!(output.contains("--> <script>:11:5\n"), "{}", output);
+    }
+
+    #[test]
+    fn metadata_only_file() {
+        struct Loader;
+
+        impl crate::source_map::FileLoader for Loader {
+            fn file_exists(&self, _: &Path) -> bool {
+                true
+            }
+
+            fn abs_path(&self, path: &Path) -> Option<PathBuf> {
+                Some(path.to_path_buf())
+            }
+
+            fn read_file(&self, _: &Path) -> io::Result<String> {
+                Ok("let a;\nlet b = c;\n".into())
+            }
+        }
+
+        let cm = Lrc::new(SourceMap::with_file_loader(
+            Box::new(Loader),
+            crate::source_map::FilePathMapping::empty(),
+        ));
+        let fm = cm.load_file_metadata_only(Path::new("a.js")).unwrap();
+        assert!(!fm.has_src());
+
+        let buf = Buf::default();
+        let emitter = EmitterWriter::new(Box::new(buf.clone()), Some(cm.clone()), false, false);
+        let handler = Handler::with_emitter_and_flags(Box::new(emitter), Default::default());
+        let lo = fm.start_pos + BytePos(15);
+        handler
+            .struct_span_err(Span::new(lo, lo + BytePos(1), NO_EXPANSION), "bad")
+            .emit();
+
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("--> a.js:2:9\n"), "{}", output);
+        assert!(output.contains("2 | let b = c;\n"), "{}", output);
+        assert!(output.contains("^"), "{}", output);
+    }
+}
@@ -0,0 +1,301 @@
+//! Conversion of [Diagnostic]s into plain data, which can be fed into
+//! external reporters without depending on them.
+
+use super::{Diagnostic, DiagnosticId, Level};
+use crate::{
+    source_map::SourceMap,
+    sync::Lrc,
+    syntax_pos::{BytePos, FileName, Pos, SourceFile, Span},
+};
+use std::ops::Range;
+
+/// Kind of a label of a [DiagnosticExport].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LabelKind {
+    Primary,
+    Secondary,
+}
+
+/// A diagnostic for a single file.
+///
+/// Ranges of the labels are byte offsets into `source_text`.
+#[derive(Debug, Clone)]
+pub struct DiagnosticExport {
+    pub source_name: FileName,
+    /// `None` if the diagnostic does not point to any file or the source code
+    /// of the file is not available.
+    pub source_text: Option<Lrc<String>>,
+    pub labels: Vec<(Range<usize>, String, LabelKind)>,
+    pub message: String,
+    pub code: Option<String>,
+    pub severity: Level,
+}
+
+/// Converts `diag` into one [DiagnosticExport] per file it points to.
+///
+/// The export of the file with the primary span comes first. If `diag` points
+/// to multiple files, the message of each export mentions the other files.
+/// Notes and helps with spans are exported as secondary labels.
+pub fn export_diagnostic(diag: &Diagnostic, cm: &SourceMap) -> Vec<DiagnosticExport> {
+    let message = diag.message();
+    let code = diag.code.as_ref().map(|code| match code {
+        DiagnosticId::Error(s) | DiagnosticId::Lint(s) => s.clone(),
+    });
+
+    let mut labels = diag
+        .span
+        .span_labels()
+        .into_iter()
+        .map(|l| {
+            let kind = if l.is_primary {
+                LabelKind::Primary
+            } else {
+                LabelKind::Secondary
+            };
+            (l.span, l.label.unwrap_or_default(), kind)
+        })
+        .collect::<Vec<_>>();
+    // Primary labels determine the order of files.
+    labels.sort_by_key(|l| l.2 != LabelKind::Primary);
+    for child in &diag.children {
+        for &span in child.span.primary_spans() {
+            labels.push((span, child.message(), LabelKind::Secondary));
+        }
+    }
+
+    let mut exports: Vec<DiagnosticExport> = vec![];
+    let mut files: Vec<BytePos> = vec![];
+
+    for (span, label, kind) in labels {
+        let (f, range) = match range_in_file(cm, span) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let idx = match files.iter().position(|&start| start == f.start_pos) {
+            Some(idx) => idx,
+            None => {
+                files.push(f.start_pos);
+                exports.push(DiagnosticExport {
+                    source_name: (*f.name).clone(),
+                    // The source of files added by `load_file_metadata_only`
+                    // is loaded on demand.
+                    source_text: cm.source_of(&f).ok(),
+                    labels: vec![],
+                    message: message.clone(),
+                    code: code.clone(),
+                    severity: diag.level,
+                });
+                exports.len() - 1
+            }
+        };
+
+        exports[idx].labels.push((range, label, kind));
+    }
+
+    if exports.is_empty() {
+        return vec![DiagnosticExport {
+            source_name: FileName::Anon,
+            source_text: None,
+            labels: vec![],
+            message,
+            code,
+            severity: diag.level,
+        }];
+    }
+
+    if exports.len() > 1 {
+        let names = exports
+            .iter()
+            .map(|e| e.source_name.to_string())
+            .collect::<Vec<_>>();
+
+        for (idx, export) in exports.iter_mut().enumerate() {
+            let others = names
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != idx)
+                .map(|(_, name)| &**name)
+                .collect::<Vec<_>>();
+            export.message = format!("{} (see also: {})", export.message, others.join(", "));
+        }
+    }
+
+    exports
+}
+
+/// Returns the file containing `span` and the byte range of `span` in it.
+fn range_in_file(cm: &SourceMap, span: Span) -> Option<(Lrc<SourceFile>, Range<usize>)> {
+    if span.is_dummy() {
+        return None;
+    }
+
+    let lo = cm.try_lookup_byte_offset(span.lo())?;
+    if span.hi() > lo.sf.end_pos {
+        return None;
+    }
+    let hi = (span.hi() - lo.sf.start_pos).to_usize();
+
+    Some((lo.sf, lo.pos.to_usize()..hi))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{source_map::FileLoader, FilePathMapping, DUMMY_SP};
+    use std::{
+        io,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering::SeqCst},
+            Arc,
+        },
+    };
+
+    fn span(f: &SourceFile, lo: usize, hi: usize) -> Span {
+        Span::new(
+            f.start_pos + BytePos(lo as u32),
+            f.start_pos + BytePos(hi as u32),
+            DUMMY_SP.ctxt,
+        )
+    }
+
+    #[test]
+    fn single_file() {
+        let cm = SourceMap::new(FilePathMapping::empty());
+        let src = "let 가나 = 1;\nfoo(가나, '€');";
+        let f = cm.new_source_file(PathBuf::from("a.js").into(), src.into());
+
+        let decl = src.find("가나").unwrap();
+        let usage = src.rfind("가나").unwrap();
+        let euro = src.find('€').unwrap();
+
+        let mut diag = Diagnostic::new_with_code(
+            Level::Error,
+            Some(DiagnosticId::Error("E1".into())),
+            "bad usage",
+        );
+        diag.set_span(span(&f, usage, usage + "가나".len()));
+        diag.span_label(span(&f, decl, decl + "가나".len()), "declared here");
+        diag.span_label(span(&f, euro, euro + '€'.len_utf8()), "and this");
+
+        let exports = export_diagnostic(&diag, &cm);
+        assert_eq!(exports.len(), 1);
+
+        let export = &exports[0];
+        assert_eq!(export.source_name, PathBuf::from("a.js").into());
+        assert_eq!(export.message, "bad usage");
+        assert_eq!(export.code.as_deref(), Some("E1"));
+        assert_eq!(export.severity, Level::Error);
+
+        let text = export.source_text.as_ref().unwrap();
+        let labels = export
+            .labels
+            .iter()
+            .map(|(range, label, kind)| (&text[range.clone()], &**label, *kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            vec![
+                ("가나", "", LabelKind::Primary),
+                ("가나", "declared here", LabelKind::Secondary),
+                ("€", "and this", LabelKind::Secondary),
+            ]
+        );
+        assert_eq!(export.labels[0].0, usage..usage + 6);
+    }
+
+    #[test]
+    fn multiple_files() {
+        let cm = SourceMap::new(FilePathMapping::empty());
+        let a = cm.new_source_file(
+            PathBuf::from("a.js").into(),
+            "import { x } from './b';".into(),
+        );
+        let b = cm.new_source_file(PathBuf::from("b.js").into(), "export const y = 1;".into());
+
+        let mut diag = Diagnostic::new(Level::Error, "x is not exported");
+        diag.set_span(span(&a, 9, 10));
+        diag.span_note(span(&b, 0, 19), "exports are here");
+
+        let exports = export_diagnostic(&diag, &cm);
+        assert_eq!(exports.len(), 2);
+
+        assert_eq!(exports[0].source_name, PathBuf::from("a.js").into());
+        assert_eq!(exports[0].message, "x is not exported (see also: b.js)");
+        assert_eq!(
+            exports[0].labels,
+            vec![(9..10, String::new(), LabelKind::Primary)]
+        );
+
+        assert_eq!(exports[1].source_name, PathBuf::from("b.js").into());
+        assert_eq!(exports[1].message, "x is not exported (see also: a.js)");
+        assert_eq!(
+            exports[1].labels,
+            vec![(0..19, "exports are here".into(), LabelKind::Secondary)]
+        );
+    }
+
+    #[test]
+    fn no_span() {
+        let cm = SourceMap::new(FilePathMapping::empty());
+        let mut diag = Diagnostic::new(Level::Warning, "something");
+        diag.set_span(DUMMY_SP);
+
+        let exports = export_diagnostic(&diag, &cm);
+        assert_eq!(exports.len(), 1);
+        assert_eq!(exports[0].source_text, None);
+        assert!(exports[0].labels.is_empty());
+        assert_eq!(exports[0].message, "something");
+    }
+
+    #[test]
+    fn metadata_only_file() {
+        struct Loader(Arc<AtomicUsize>);
+
+        impl FileLoader for Loader {
+            fn file_exists(&self, _: &Path) -> bool {
+                true
+            }
+
+            fn abs_path(&self, path: &Path) -> Option<PathBuf> {
+                Some(path.to_path_buf())
+            }
+
+            fn read_file(&self, path: &Path) -> io::Result<String> {
+                self.0.fetch_add(1, SeqCst);
+                match path.to_str() {
+                    Some("a.js") => Ok("let a = b;\n".into()),
+                    _ => Err(io::ErrorKind::NotFound.into()),
+                }
+            }
+        }
+
+        let reads = Arc::new(AtomicUsize::new(0));
+        let cm =
+            SourceMap::with_file_loader(Box::new(Loader(reads.clone())), FilePathMapping::empty());
+        let f = cm.load_file_metadata_only(Path::new("a.js")).unwrap();
+        assert_eq!(reads.load(SeqCst), 1);
+
+        let mut diag = Diagnostic::new(Level::Error, "b is not defined");
+        diag.set_span(span(&f, 8, 9));
+        let exports = export_diagnostic(&diag, &cm);
+        assert_eq!(reads.load(SeqCst), 2);
+
+        let text = exports[0].source_text.as_ref().unwrap();
+        assert_eq!(&text[exports[0].labels[0].0.clone()], "b");
+
+        // The text is not exported if it can't be loaded.
+        let g = cm.new_imported_source_file(
+            PathBuf::from("b.js").into(),
+            vec![BytePos(0)],
+            11,
+            None,
+            None,
+        );
+        diag.set_span(span(&g, 8, 9));
+        let exports = export_diagnostic(&diag, &cm);
+        assert_eq!(exports[0].source_text, None);
+        assert_eq!(exports[0].labels[0].0, 8..9);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::syntax_pos::{BytePos, SourceFile};
+use crate::syntax_pos::{BytePos, SourceFile, Span};
 use std::str;
 
 pub type SourceFileInput<'a> = StringInput<'a>;
@@ -37,6 +37,61 @@ impl<'a> StringInput<'a> {
     }
 }
 
+/// Helpers for building auxiliary scanners on top of [StringInput].
+///
+/// All positions are absolute positions, so spans created by these methods
+/// can be used with [SourceMap] directly.
+impl<'a> StringInput<'a> {
+    /// Returns the `n`-th character after the current character, where
+    /// `peek_n(0)` is same as [Input::cur].
+    #[inline]
+    pub fn peek_n(&self, n: usize) -> Option<char> {
+        self.iter.clone().nth(n).map(|i| i.1)
+    }
+
+    /// Returns next `len` bytes without advancing the cursor.
+    ///
+    /// Returns `None` if there are less than `len` bytes left or if it ends in
+    /// the middle of a character.
+    #[inline]
+    pub fn slice_from_cur(&self, len: usize) -> Option<&'a str> {
+        self.iter.as_str().get(..len)
+    }
+
+    /// Advances the cursor by `n` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it advances past the end of the input or into the middle of
+    /// a character.
+    pub fn advance_bytes(&mut self, n: usize) {
+        let s = self.iter.as_str();
+        assert!(
+            s.is_char_boundary(n),
+            "Cannot advance {} bytes from {:?}",
+            n,
+            self.last_pos
+        );
+
+        self.last_pos = self.last_pos + BytePos(n as _);
+        self.start_pos = self.last_pos;
+        self.iter = s[n..].char_indices();
+    }
+
+    /// Returns a span from `start` to the current position.
+    #[inline]
+    pub fn span_since(&self, start: BytePos) -> Span {
+        let cur_pos = self
+            .iter
+            .clone()
+            .next()
+            .map(|(p, _)| self.start_pos + BytePos(p as u32))
+            .unwrap_or(self.last_pos);
+
+        Span::new(start, cur_pos, Default::default())
+    }
+}
+
 /// Creates an [Input] from [SourceFile]. This is an alias for
 ///
 /// ```ignore
@@ -308,6 +363,72 @@ mod tests {
         });
     }
 
+    /// Finds the urls of `//# sourceMappingURL=` comments.
+    fn find_source_mapping_urls(mut input: StringInput<'_>) -> Vec<Span> {
+        const PREFIX: &str = "//# sourceMappingURL=";
+        let mut urls = vec![];
+
+        while let Some(c) = input.peek_n(0) {
+            if input.slice_from_cur(PREFIX.len()) == Some(PREFIX) {
+                input.advance_bytes(PREFIX.len());
+                let start = input.cur_pos();
+                input.uncons_while(|c| !c.is_whitespace());
+                urls.push(input.span_since(start));
+            } else {
+                input.advance_bytes(c.len_utf8());
+            }
+        }
+
+        urls
+    }
+
+    #[test]
+    fn src_input_scanner() {
+        let cm = Arc::new(SourceMap::new(FilePathMapping::empty()));
+        cm.new_source_file(FileName::Real("first".into()), "foo();".into());
+        let fm = cm.new_source_file(
+            FileName::Real("testing".into()),
+            "const a = '€';\n//# sourceMappingURL=a.js.map\n℘//# sourceMappingURL=b.js.map".into(),
+        );
+
+        let urls = find_source_mapping_urls((&*fm).into());
+        let urls = urls
+            .into_iter()
+            .map(|sp| cm.span_to_snippet(sp).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(urls, vec!["a.js.map", "b.js.map"]);
+    }
+
+    #[test]
+    fn src_input_peek_n() {
+        let _ = with_test_sess("f€o", |mut i| {
+            assert_eq!(i.peek_n(0), Some('f'));
+            assert_eq!(i.peek_n(1), Some('€'));
+            assert_eq!(i.peek_n(2), Some('o'));
+            assert_eq!(i.peek_n(3), None);
+
+            assert_eq!(i.slice_from_cur(4), Some("f€"));
+            assert_eq!(i.slice_from_cur(2), None);
+            assert_eq!(i.slice_from_cur(6), None);
+
+            i.advance_bytes(4);
+            assert_eq!(i.cur(), Some('o'));
+            assert_eq!(i.cur_pos(), BytePos(4));
+            assert_eq!(
+                i.span_since(BytePos(1)),
+                Span::new(BytePos(1), BytePos(4), Default::default())
+            );
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot advance")]
+    fn src_input_advance_bytes_char_boundary() {
+        let _ = with_test_sess("f€o", |mut i| {
+            i.advance_bytes(2);
+        });
+    }
+
     //    #[test]
     //    fn src_input_smoke_02() {
     //        let _ = crate::with_test_sess("℘℘/℘℘", | mut i| {
@@ -13,6 +13,11 @@
 //! Adds methods to generate web sourcemap.
 #![deny(unused)]
 
+// The dependency is renamed so that the `sourcemap` feature can enable
+// `serde_json` as well.
+#[cfg(feature = "sourcemap")]
+extern crate dep_sourcemap as sourcemap;
+
 pub use self::eq::EqIgnoreSpan;
 pub use self::eq::TypeEq;
 pub use self::{
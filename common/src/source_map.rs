@@ -18,23 +18,27 @@
 //! information, source code snippets, etc.
 pub use crate::syntax_pos::*;
 use crate::{
+    comments::CommentKind,
     errors::SourceMapper,
     rustc_data_structures::stable_hasher::StableHasher,
     sync::{Lock, LockGuard, Lrc, MappedLockGuard},
 };
+use fxhash::{FxHashMap, FxHashSet};
 use log::debug;
+use owning_ref::OwningRef;
 #[cfg(feature = "sourcemap")]
 use sourcemap::SourceMapBuilder;
 use std::{
-    cmp,
     cmp::{max, min},
     collections::HashMap,
     env, fs,
     hash::Hash,
-    io::{self, Read},
+    io::{self, BufRead, Read},
+    ops::Range,
     path::{Path, PathBuf},
     sync::atomic::{AtomicUsize, Ordering::SeqCst},
 };
+use unicode_width::UnicodeWidthChar;
 
 // _____________________________________________________________________________
 // SourceFile, MultiByteChar, FileName, FileLines
@@ -50,6 +54,17 @@ pub trait FileLoader {
 
     /// Read the contents of an UTF-8 file into memory.
     fn read_file(&self, path: &Path) -> io::Result<String>;
+
+    /// Opens a file to read its contents as UTF-8 in chunks, like
+    /// [FileLoader::read_file] reads them at once.
+    ///
+    /// This is used by [SourceMap::load_file_metadata_only]. The default
+    /// implementation reads the whole file with [FileLoader::read_file].
+    fn open_file(&self, path: &Path) -> io::Result<Box<dyn BufRead>> {
+        Ok(Box::new(io::Cursor::new(
+            self.read_file(path)?.into_bytes(),
+        )))
+    }
 }
 
 /// A FileLoader that uses std::fs to load real files.
@@ -73,6 +88,111 @@ impl FileLoader for RealFileLoader {
         fs::File::open(path)?.read_to_string(&mut src)?;
         Ok(src)
     }
+
+    fn open_file(&self, path: &Path) -> io::Result<Box<dyn BufRead>> {
+        Ok(Box::new(io::BufReader::new(fs::File::open(path)?)))
+    }
+}
+
+/// Same as [RealFileLoader], but files starting with a UTF-16 BOM are
+/// converted to UTF-8.
+///
+/// The BOM is kept as `U+FEFF`, so [SourceFile::had_bom] is set for such files
+/// and positions are relative to the content after the BOM, like UTF-8 files
+/// with a BOM. The original encoding is not recorded.
+pub struct TranscodingFileLoader;
+
+impl FileLoader for TranscodingFileLoader {
+    fn file_exists(&self, path: &Path) -> bool {
+        RealFileLoader.file_exists(path)
+    }
+
+    fn abs_path(&self, path: &Path) -> Option<PathBuf> {
+        RealFileLoader.abs_path(path)
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        decode_source(fs::read(path)?)
+    }
+
+    /// UTF-8 files are read in chunks, and UTF-16 files are converted at once.
+    fn open_file(&self, path: &Path) -> io::Result<Box<dyn BufRead>> {
+        let mut file = io::BufReader::new(fs::File::open(path)?);
+        match file.fill_buf()?.get(..2) {
+            Some([0xff, 0xfe]) | Some([0xfe, 0xff]) => {
+                let mut bytes = vec![];
+                file.read_to_end(&mut bytes)?;
+                Ok(Box::new(io::Cursor::new(
+                    decode_source(bytes)?.into_bytes(),
+                )))
+            }
+            _ => Ok(Box::new(file)),
+        }
+    }
+}
+
+/// Decodes `bytes` as UTF-16 if it starts with a UTF-16 BOM, and as UTF-8
+/// otherwise.
+fn decode_source(bytes: Vec<u8>) -> io::Result<String> {
+    fn utf16(bytes: &[u8], to_u16: fn([u8; 2]) -> u16) -> io::Result<String> {
+        if bytes.len() % 2 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-16",
+            ));
+        }
+
+        let units = bytes.chunks_exact(2).map(|c| to_u16([c[0], c[1]]));
+        std::char::decode_utf16(units)
+            .collect::<Result<String, _>>()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    match bytes.get(..2) {
+        Some([0xff, 0xfe]) => utf16(&bytes, u16::from_le_bytes),
+        Some([0xfe, 0xff]) => utf16(&bytes, u16::from_be_bytes),
+        _ => {
+            String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+    }
+}
+
+/// Converts names of files into shared [FileName]s, so that files with the
+/// same name can share one allocation.
+pub trait FileNameInterner: crate::sync::Send + crate::sync::Sync {
+    fn intern(&self, name: FileName) -> Lrc<FileName>;
+}
+
+/// A [FileNameInterner] which does not share names.
+///
+/// This is the default interner of [SourceMap].
+pub struct NoopFileNameInterner;
+
+impl FileNameInterner for NoopFileNameInterner {
+    fn intern(&self, name: FileName) -> Lrc<FileName> {
+        Lrc::new(name)
+    }
+}
+
+/// A [FileNameInterner] which returns the same [Lrc] for equal names.
+///
+/// Interned names are never freed.
+#[derive(Default)]
+pub struct DedupFileNameInterner {
+    names: Lock<FxHashSet<Lrc<FileName>>>,
+}
+
+impl FileNameInterner for DedupFileNameInterner {
+    fn intern(&self, name: FileName) -> Lrc<FileName> {
+        let mut names = self.names.borrow_mut();
+        if let Some(interned) = names.get(&name) {
+            return interned.clone();
+        }
+
+        let name = Lrc::new(name);
+        names.insert(name.clone());
+        name
+    }
 }
 
 // This is a SourceFile identifier that is used to correlate SourceFiles between
@@ -93,6 +213,197 @@ impl StableSourceFileId {
     }
 }
 
+/// A snippet of a [SourceFile], which shares the source text of the file.
+pub type SourceSnippet = OwningRef<Lrc<String>, str>;
+
+/// A part of a line which fits in a limited width, returned by
+/// [SourceMap::window_around].
+///
+/// All columns are display columns, so wide characters take two columns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnippetWindow {
+    /// The visible part of the line, with `…` in place of the truncated parts.
+    pub line: String,
+    /// The column of the start of the span in `line`.
+    pub start_col: usize,
+    /// The column of the end of the span in `line`, which is clamped to the
+    /// visible part.
+    pub end_col: usize,
+    pub truncated_left: bool,
+    pub truncated_right: bool,
+    /// Columns of the visible part in the original line.
+    visible: Range<usize>,
+}
+
+impl SnippetWindow {
+    /// Selects the part of `line` around the columns `start_col..end_col`
+    /// which fits in `max_width` columns, including the ellipses.
+    ///
+    /// Characters are never split, so the window may be narrower than
+    /// `max_width` if a wide character lies on its edge. `max_width` is at
+    /// least `3`, which leaves room for a character between the ellipses.
+    pub fn new(line: &str, start_col: usize, end_col: usize, max_width: usize) -> Self {
+        let total = line.chars().map(char_display_width).sum::<usize>();
+        let end_col = min(end_col, total).max(start_col);
+
+        if total <= max_width {
+            return SnippetWindow {
+                line: line.to_string(),
+                start_col,
+                end_col,
+                truncated_left: false,
+                truncated_right: false,
+                visible: 0..total,
+            };
+        }
+
+        // The window without the ellipses, which take one column each.
+        let max_width = max(max_width, 3);
+        let left = start_col.saturating_sub(max_width.saturating_sub(end_col - start_col) / 2);
+        let (left, right) = if left == 0 {
+            (0, max_width - 1)
+        } else if left + max_width - 1 >= total {
+            (total - (max_width - 1), total)
+        } else {
+            (left, left + max_width - 2)
+        };
+
+        let mut bytes = line.len()..line.len();
+        let mut visible = right..right;
+        let mut col = 0;
+        for (idx, c) in line.char_indices() {
+            let width = char_display_width(c);
+            if col >= left && col + width <= right {
+                if bytes.start == line.len() {
+                    bytes.start = idx;
+                    visible.start = col;
+                }
+                bytes.end = idx + c.len_utf8();
+                visible.end = col + width;
+            }
+            col += width;
+            if col > right {
+                break;
+            }
+        }
+        if bytes.start == line.len() {
+            bytes.end = line.len();
+        }
+
+        let truncated_left = left > 0;
+        let truncated_right = right < total;
+        let mut text = String::with_capacity(bytes.len() + 6);
+        if truncated_left {
+            text.push('…');
+        }
+        text.push_str(&line[bytes]);
+        if truncated_right {
+            text.push('…');
+        }
+
+        let mut window = SnippetWindow {
+            line: text,
+            start_col: 0,
+            end_col: 0,
+            truncated_left,
+            truncated_right,
+            visible,
+        };
+        window.start_col = window.map_col(start_col);
+        window.end_col = window.map_col(end_col);
+        window
+    }
+
+    /// Converts a column of the original line into a column of
+    /// [SnippetWindow::line]. Columns outside of the window are clamped to its
+    /// edges.
+    pub fn map_col(&self, col: usize) -> usize {
+        let col = col.max(self.visible.start).min(self.visible.end);
+        col - self.visible.start + self.truncated_left as usize
+    }
+}
+
+/// Lexical state passed to the callback of [SourceMap::def_span_until].
+///
+/// It tracks nesting of parentheses, brackets and braces, and whether the
+/// scanner is inside of a string or template literal, in JS syntax.
+#[derive(Debug, Clone, Default)]
+pub struct DefSpanState {
+    /// Closing delimiters of the open groups, innermost last. A backtick is
+    /// an open template literal.
+    stack: Vec<char>,
+    /// The quote of the string literal being scanned.
+    quote: Option<char>,
+    escaped: bool,
+    /// `$` was the previous character of a template literal.
+    dollar: bool,
+}
+
+impl DefSpanState {
+    /// The number of open groups, including template literals and their
+    /// `${}` substitutions.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Returns `true` if the scanner is inside of the text of a string or
+    /// template literal.
+    pub fn in_literal(&self) -> bool {
+        self.quote.is_some() || self.stack.last() == Some(&'`')
+    }
+
+    /// Returns `true` if the scanner is not inside of any group or literal.
+    pub fn is_top_level(&self) -> bool {
+        self.stack.is_empty() && self.quote.is_none()
+    }
+
+    fn advance(&mut self, c: char) {
+        if self.escaped {
+            self.escaped = false;
+            return;
+        }
+
+        if let Some(quote) = self.quote {
+            match c {
+                '\\' => self.escaped = true,
+                _ if c == quote => self.quote = None,
+                _ => {}
+            }
+            return;
+        }
+
+        if self.stack.last() == Some(&'`') {
+            let dollar = self.dollar;
+            self.dollar = false;
+            match c {
+                '\\' => self.escaped = true,
+                '`' => {
+                    self.stack.pop();
+                }
+                '$' => self.dollar = true,
+                '{' if dollar => self.stack.push('}'),
+                _ => {}
+            }
+            return;
+        }
+
+        match c {
+            '\'' | '"' => self.quote = Some(c),
+            '`' => self.stack.push('`'),
+            '(' => self.stack.push(')'),
+            '[' => self.stack.push(']'),
+            '{' => self.stack.push('}'),
+            ')' | ']' | '}' => {
+                // Unbalanced closing delimiters are ignored.
+                if self.stack.last() == Some(&c) {
+                    self.stack.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 // _____________________________________________________________________________
 // SourceMap
 //
@@ -103,6 +414,12 @@ pub(super) struct SourceMapFiles {
     stable_id_to_source_file: HashMap<StableSourceFileId, Lrc<SourceFile>>,
 }
 
+/// Identifies a callback registered with [SourceMap::on_file_added].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(usize);
+
+type FileAddedCallback = dyn Fn(&Lrc<SourceFile>) + Sync + Send;
+
 /// The interner for spans.
 ///
 /// As most spans are simply stored, we store them as interend form.
@@ -112,16 +429,48 @@ pub(super) struct SourceMapFiles {
 ///    using this struct.
 pub struct SourceMap {
     pub(super) files: Lock<SourceMapFiles>,
-    start_pos: AtomicUsize,
+    /// The start position of the next file. Shared with the source maps
+    /// created by [SourceMap::fork], so their files never overlap.
+    start_pos: Lrc<AtomicUsize>,
     file_loader: Box<dyn FileLoader + Sync + Send>,
     // This is used to apply the file path remapping as specified via
     // --remap-path-prefix to all SourceFiles allocated within this SourceMap.
     path_mapping: FilePathMapping,
     /// In case we are in a doctest, replace all file names with the PathBuf,
-    /// and add the given offsets to the line info
-    doctest_offset: Option<(FileName, isize)>,
+    /// and add the given offsets to the line info and to the columns of the
+    /// first line
+    doctest_offset: Option<(FileName, isize, usize)>,
+    /// Line offsets of doctests aggregated into one file, keyed by the range of
+    /// lines they cover.
+    doctest_offsets: Vec<(Range<usize>, isize)>,
+    /// Files loaded by [SourceMap::load_file_cached], keyed by their absolute
+    /// paths.
+    loaded_files: Lock<FxHashMap<PathBuf, Lrc<SourceFile>>>,
+    /// Number of files created by [SourceMap::new_anon_source_file]. Shared
+    /// like `start_pos`.
+    anon_files: Lrc<AtomicUsize>,
+    interner: Lrc<dyn FileNameInterner>,
+    file_added_callbacks: Lock<Vec<(SubscriptionId, Lrc<FileAddedCallback>)>>,
+    next_subscription: AtomicUsize,
+    /// Real paths under this directory are rendered relative to it.
+    base_dir: Option<PathBuf>,
+    /// Paths of files added by [SourceMap::load_file_metadata_only], keyed by
+    /// their start positions.
+    metadata_only_files: Lock<FxHashMap<BytePos, PathBuf>>,
+    /// Recently loaded sources of metadata-only files, most recently used
+    /// last.
+    loaded_sources: Lock<Vec<(BytePos, Lrc<String>)>>,
+    loaded_sources_capacity: usize,
 }
 
+/// The default number of sources of metadata-only files kept in memory. See
+/// [SourceMap::with_loaded_sources_capacity].
+const DEFAULT_LOADED_SOURCES_CAPACITY: usize = 8;
+
+/// The number of bytes before a span which [SourceMap::span_to_margin] looks
+/// at before falling back to the whole file.
+const MARGIN_LOOKBACK: usize = 256;
+
 impl Default for SourceMap {
     fn default() -> Self {
         Self::new(FilePathMapping::empty())
@@ -136,6 +485,16 @@ impl SourceMap {
             file_loader: Box::new(RealFileLoader),
             path_mapping,
             doctest_offset: None,
+            doctest_offsets: vec![],
+            loaded_files: Default::default(),
+            anon_files: Default::default(),
+            interner: Lrc::new(NoopFileNameInterner),
+            file_added_callbacks: Default::default(),
+            next_subscription: Default::default(),
+            base_dir: None,
+            metadata_only_files: Default::default(),
+            loaded_sources: Default::default(),
+            loaded_sources_capacity: DEFAULT_LOADED_SOURCES_CAPACITY,
         }
     }
 
@@ -149,210 +508,1019 @@ impl SourceMap {
             file_loader,
             path_mapping,
             doctest_offset: None,
+            doctest_offsets: vec![],
+            loaded_files: Default::default(),
+            anon_files: Default::default(),
+            interner: Lrc::new(NoopFileNameInterner),
+            file_added_callbacks: Default::default(),
+            next_subscription: Default::default(),
+            base_dir: None,
+            metadata_only_files: Default::default(),
+            loaded_sources: Default::default(),
+            loaded_sources_capacity: DEFAULT_LOADED_SOURCES_CAPACITY,
         }
     }
 
+    /// Uses `interner` for the names of files added to this source map.
+    pub fn with_interner(mut self, interner: impl FileNameInterner + 'static) -> Self {
+        self.interner = Lrc::new(interner);
+        self
+    }
+
+    /// Sets the number of sources of metadata-only files kept in memory after
+    /// loading them for snippets. See [SourceMap::load_file_metadata_only].
+    ///
+    /// The least recently used source is dropped first. With `0`, the source
+    /// is loaded for every snippet.
+    pub fn with_loaded_sources_capacity(mut self, capacity: usize) -> Self {
+        self.loaded_sources_capacity = capacity;
+        self
+    }
+
     pub fn path_mapping(&self) -> &FilePathMapping {
         &self.path_mapping
     }
 
+    /// Renders real paths under `base_dir` relative to it in messages, like
+    /// the ones of [SourceMap::span_to_string].
+    ///
+    /// This only affects rendering. Names of files and
+    /// [SourceFile::unmapped_path] are kept as is.
+    pub fn with_base_dir(mut self, base_dir: PathBuf) -> Self {
+        self.base_dir = Some(base_dir);
+        self
+    }
+
+    pub fn base_dir(&self) -> Option<&Path> {
+        self.base_dir.as_deref()
+    }
+
+    /// Returns `path` relative to the base directory, or `path` itself if it's
+    /// not under the base directory. See [SourceMap::with_base_dir].
+    ///
+    /// [SourceMapGenConfig] implementations can use this to name sources.
+    pub fn relativize(&self, path: &Path) -> PathBuf {
+        self.base_dir
+            .as_ref()
+            .and_then(|base| path.strip_prefix(base).ok())
+            .unwrap_or(path)
+            .to_path_buf()
+    }
+
+    /// Renders `name` for messages, with [SourceMap::relativize] applied to
+    /// real paths.
+    fn display_file_name(&self, name: &FileName) -> String {
+        match name {
+            FileName::Real(path) => self.relativize(path).display().to_string(),
+            _ => name.to_string(),
+        }
+    }
+
     pub fn file_exists(&self, path: &Path) -> bool {
         self.file_loader.file_exists(path)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, path), fields(path = %path.display()))
+    )]
     pub fn load_file(&self, path: &Path) -> io::Result<Lrc<SourceFile>> {
         let src = self.file_loader.read_file(path)?;
         let filename = path.to_owned().into();
         Ok(self.new_source_file(filename, src))
     }
 
-    pub fn files(&self) -> MappedLockGuard<'_, Vec<Lrc<SourceFile>>> {
-        LockGuard::map(self.files.borrow(), |files| &mut files.source_files)
-    }
+    /// Same as [SourceMap::load_file], but the source code is not kept in
+    /// memory.
+    ///
+    /// The file is streamed once with [FileLoader::open_file] to compute its
+    /// positions and hashes, so only one line is in memory at a time. Lookups
+    /// of positions don't read it again. Methods returning snippets, like
+    /// [SourceMap::span_to_snippet], and the emitter load the source on
+    /// demand. Recently loaded sources are kept, see
+    /// [SourceMap::with_loaded_sources_capacity].
+    ///
+    /// [SourceFile::src] of the returned file is empty, so code inspecting it
+    /// directly, like [SourceFile::get_line], treats the file as empty.
+    pub fn load_file_metadata_only(&self, path: &Path) -> io::Result<Lrc<SourceFile>> {
+        let filename: FileName = path.to_owned().into();
+        let unmapped_path = filename.clone();
+        let (filename, was_remapped) = self.intern_file_name(filename);
 
-    pub fn source_file_by_stable_id(
-        &self,
-        stable_id: StableSourceFileId,
-    ) -> Option<Lrc<SourceFile>> {
-        self.files
-            .borrow()
-            .stable_id_to_source_file
-            .get(&stable_id)
-            .cloned()
-    }
+        let mut src = self.file_loader.open_file(path)?;
+        let source_file = SourceFile::new_metadata_only(
+            filename,
+            was_remapped,
+            unmapped_path,
+            &mut *src,
+            |len| Pos::from_usize(self.next_start_pos(len)),
+        )?;
 
-    fn next_start_pos(&self, len: usize) -> usize {
-        // Add one so there is some space between files. This lets us distinguish
-        // positions in the source_map, even in the presence of zero-length files.
-        self.start_pos.fetch_add(len + 1, SeqCst)
+        self.metadata_only_files
+            .borrow_mut()
+            .insert(source_file.start_pos, path.to_owned());
+        let source_file = Lrc::new(source_file);
+        self.insert_source_file(source_file.clone());
+
+        Ok(source_file)
     }
 
-    /// Creates a new source_file.
-    /// This does not ensure that only one SourceFile exists per file name.
-    pub fn new_source_file(&self, filename: FileName, src: String) -> Lrc<SourceFile> {
-        // The path is used to determine the directory for loading submodules and
-        // include files, so it must be before remapping.
-        // Note that filename may not be a valid path, eg it may be `<anon>` etc,
-        // but this is okay because the directory determined by `path.pop()` will
-        // be empty, so the working directory will be used.
-        let unmapped_path = filename.clone();
+    /// Returns the source code of `f`, loading it if `f` was added by
+    /// [SourceMap::load_file_metadata_only].
+    pub(crate) fn source_of(&self, f: &SourceFile) -> Result<Lrc<String>, SpanSnippetError> {
+        if f.has_src() {
+            return Ok(f.src.clone());
+        }
 
-        let (filename, was_remapped) = match filename {
-            FileName::Real(filename) => {
-                let (filename, was_remapped) = self.path_mapping.map_prefix(filename);
-                (FileName::Real(filename), was_remapped)
+        let path = self.metadata_only_files.borrow().get(&f.start_pos).cloned();
+        let path = match path {
+            Some(path) => path,
+            None => return resident_source_of(f),
+        };
+
+        {
+            let mut loaded = self.loaded_sources.borrow_mut();
+            if let Some(idx) = loaded.iter().position(|(pos, _)| *pos == f.start_pos) {
+                let entry = loaded.remove(idx);
+                let src = entry.1.clone();
+                loaded.push(entry);
+                return Ok(src);
             }
-            other => (other, false),
+        }
+
+        let not_available = || SpanSnippetError::SourceNotAvailable {
+            filename: (*f.name).clone(),
         };
+        // The source is decoded like it was when the file was added.
+        let mut src = String::new();
+        self.file_loader
+            .open_file(&path)
+            .and_then(|mut file| file.read_to_string(&mut src))
+            .map_err(|_| not_available())?;
+        crate::syntax_pos::remove_bom(&mut src);
+        // The file has changed since it was added.
+        if src.len() != f.byte_length() as usize {
+            return Err(not_available());
+        }
 
-        // We hold lock at here to prevent panic
-        // If we don't do this, lookup_char_pos and its family **may** panic.
-        let mut files = self.files.borrow_mut();
+        let src = Lrc::new(src);
+        if self.loaded_sources_capacity > 0 {
+            let mut loaded = self.loaded_sources.borrow_mut();
+            if loaded.len() >= self.loaded_sources_capacity {
+                loaded.remove(0);
+            }
+            loaded.push((f.start_pos, src.clone()));
+        }
 
-        let start_pos = self.next_start_pos(src.len());
+        Ok(src)
+    }
 
-        let source_file = Lrc::new(SourceFile::new(
-            filename,
-            was_remapped,
-            unmapped_path,
-            src,
-            Pos::from_usize(start_pos),
-        ));
+    /// Same as [SourceMap::load_file], but returns the previously loaded file
+    /// if `path` resolves to the same absolute path as a previous call.
+    ///
+    /// The file is registered under `path` as given, so [FilePathMapping] is
+    /// applied as usual.
+    pub fn load_file_cached(&self, path: &Path) -> io::Result<Lrc<SourceFile>> {
+        let key = self.cache_key(path);
+        if let Some(fm) = self.loaded_files.borrow().get(&key) {
+            return Ok(fm.clone());
+        }
 
+        // Reading and analyzing the file is expensive, so it's done without
+        // holding the lock.
+        let src = self.file_loader.read_file(path)?;
+        let fm = Lrc::new(self.create_source_file(path.to_owned().into(), src));
         {
-            files.source_files.push(source_file.clone());
-            files
-                .stable_id_to_source_file
-                .insert(StableSourceFileId::new(&source_file), source_file.clone());
+            let mut loaded_files = self.loaded_files.borrow_mut();
+            // If another thread loaded the same file in the meantime, its file
+            // is returned so that all callers agree on the spans, and this one
+            // is never added to the source map.
+            if let Some(loaded) = loaded_files.get(&key) {
+                return Ok(loaded.clone());
+            }
+            // The file is added before the lock is released, so other threads
+            // can look it up as soon as they get it.
+            self.add_source_file(&fm);
+            loaded_files.insert(key, fm.clone());
         }
+        self.notify_file_added(&fm);
 
-        source_file
+        Ok(fm)
     }
 
-    pub fn mk_substr_filename(&self, sp: Span) -> String {
-        let pos = self.lookup_char_pos(sp.lo());
-        format!(
-            "<{}:{}:{}>",
-            pos.file.name,
-            pos.line,
-            pos.col.to_usize() + 1
-        )
+    /// Removes `path` from the cache of [SourceMap::load_file_cached], so the
+    /// next call loads it again.
+    ///
+    /// The removed file is returned. It's still a part of the source map, so
+    /// spans pointing to it stay valid.
+    pub fn invalidate_path(&self, path: &Path) -> Option<Lrc<SourceFile>> {
+        let key = self.cache_key(path);
+        self.loaded_files.borrow_mut().remove(&key)
     }
 
-    // If there is a doctest_offset, apply it to the line
-    pub fn doctest_offset_line(&self, mut orig: usize) -> usize {
-        if let Some((_, line)) = self.doctest_offset {
-            if line >= 0 {
-                orig += line as usize;
-            } else {
-                orig -= (-line) as usize;
-            }
+    fn cache_key(&self, path: &Path) -> PathBuf {
+        self.file_loader
+            .abs_path(path)
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+
+    /// Releases the memory over-allocated by the tables of files, e.g. after
+    /// parsing many files or invalidating cached ones.
+    pub fn shrink_to_fit(&self) {
+        {
+            let mut files = self.files.borrow_mut();
+            files.source_files.shrink_to_fit();
+            files.stable_id_to_source_file.shrink_to_fit();
         }
-        orig
+        self.loaded_files.borrow_mut().shrink_to_fit();
     }
 
-    /// Lookup source information about a BytePos
-    pub fn lookup_char_pos(&self, pos: BytePos) -> Loc {
-        let fm = self.lookup_source_file(pos);
-        self.lookup_char_pos_with(fm, pos)
+    /// Takes an immutable snapshot of the files added so far.
+    ///
+    /// The snapshot does not use locks, so lookups on it are cheaper than the
+    /// ones on a live source map. Use [SourceMap::thaw] to continue adding
+    /// files.
+    pub fn freeze(&self) -> FrozenSourceMap {
+        let files = self.files.borrow().source_files.clone();
+        let mut names = FxHashMap::default();
+        for (idx, f) in files.iter().enumerate() {
+            names.entry(f.name.clone()).or_insert(idx);
+        }
+
+        FrozenSourceMap {
+            inner: Lrc::new(FrozenSourceMapInner {
+                files,
+                names,
+                start_pos: self.start_pos.load(SeqCst),
+                path_mapping: self.path_mapping.clone(),
+                loaded_files: self.loaded_files.borrow().clone(),
+                anon_files: self.anon_files.load(SeqCst),
+                interner: self.interner.clone(),
+                base_dir: self.base_dir.clone(),
+            }),
+        }
     }
 
-    /// Lookup source information about a BytePos
+    /// Creates a source map which contains the files of `frozen`.
     ///
+    /// Files added to the new source map are placed after the files of
+    /// `frozen`, so spans created before [SourceMap::freeze] stay valid. The
+    /// new source map uses [RealFileLoader] and no doctest offsets.
+    pub fn thaw(frozen: FrozenSourceMap) -> SourceMap {
+        let inner = Lrc::try_unwrap(frozen.inner).unwrap_or_else(|inner| (*inner).clone());
+
+        let stable_id_to_source_file = inner
+            .files
+            .iter()
+            .map(|f| (StableSourceFileId::new(f), f.clone()))
+            .collect();
+
+        SourceMap {
+            files: Lock::new(SourceMapFiles {
+                source_files: inner.files,
+                stable_id_to_source_file,
+            }),
+            start_pos: Lrc::new(AtomicUsize::new(inner.start_pos)),
+            anon_files: Lrc::new(AtomicUsize::new(inner.anon_files)),
+            loaded_files: Lock::new(inner.loaded_files),
+            interner: inner.interner,
+            base_dir: inner.base_dir,
+            ..SourceMap::new(inner.path_mapping)
+        }
+    }
+
+    /// Creates a source map for speculative work, like trying to parse a file
+    /// in a different way.
     ///
-    /// This method exists only for optimization and it's not part of public
-    /// api.
-    #[doc(hidden)]
-    pub fn lookup_char_pos_with(&self, fm: Lrc<SourceFile>, pos: BytePos) -> Loc {
-        let line_info = self.lookup_line_with(fm, pos);
-        match line_info {
-            Ok(SourceFileAndLine { sf: f, line: a }) => {
-                let chpos = self.bytepos_to_file_charpos_with(&f, pos);
+    /// The fork contains the files of this source map, so existing spans can
+    /// be looked up in it. Files added to the fork are not visible to this
+    /// source map until it's passed to [SourceMap::adopt], and dropping the
+    /// fork leaves this source map untouched. Positions of new files are
+    /// taken from the same counter, so files of this source map and of its
+    /// forks never overlap. Like [SourceMap::thaw], the fork uses
+    /// [RealFileLoader].
+    pub fn fork(&self) -> SourceMap {
+        let files = self.files.borrow();
 
-                let line = a + 1; // Line numbers start at 1
-                let linebpos = f.lines[a];
-                assert!(
-                    pos >= linebpos,
-                    "{}: bpos = {:?}; linebpos = {:?};",
-                    f.name,
-                    pos,
-                    linebpos,
-                );
+        SourceMap {
+            files: Lock::new(SourceMapFiles {
+                source_files: files.source_files.clone(),
+                stable_id_to_source_file: files.stable_id_to_source_file.clone(),
+            }),
+            start_pos: self.start_pos.clone(),
+            doctest_offset: self.doctest_offset.clone(),
+            doctest_offsets: self.doctest_offsets.clone(),
+            loaded_files: Lock::new(self.loaded_files.borrow().clone()),
+            anon_files: self.anon_files.clone(),
+            interner: self.interner.clone(),
+            base_dir: self.base_dir.clone(),
+            metadata_only_files: Lock::new(self.metadata_only_files.borrow().clone()),
+            loaded_sources_capacity: self.loaded_sources_capacity,
+            ..SourceMap::new(self.path_mapping.clone())
+        }
+    }
 
-                let linechpos = self.bytepos_to_file_charpos_with(&f, linebpos);
+    /// Adds the files added to `child` after it was created by
+    /// [SourceMap::fork] to this source map.
+    ///
+    /// The files keep their positions, so spans created with `child` stay
+    /// valid. Callbacks registered with [SourceMap::on_file_added] are called
+    /// for each of them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `child` is not a fork of this source map.
+    pub fn adopt(&self, child: SourceMap) {
+        assert!(
+            Lrc::ptr_eq(&self.start_pos, &child.start_pos),
+            "the source map to adopt is not a fork of this source map"
+        );
 
-                let col = max(chpos, linechpos) - min(chpos, linechpos);
+        let new_files = {
+            let files = self.files.borrow();
+            child
+                .files_snapshot()
+                .into_iter()
+                .filter(|f| {
+                    files
+                        .source_files
+                        .binary_search_by_key(&f.start_pos, |f| f.start_pos)
+                        .is_err()
+                })
+                .collect::<Vec<_>>()
+        };
 
-                let col_display = {
-                    let start_width_idx = f
-                        .non_narrow_chars
-                        .binary_search_by_key(&linebpos, |x| x.pos())
-                        .unwrap_or_else(|x| x);
-                    let end_width_idx = f
-                        .non_narrow_chars
-                        .binary_search_by_key(&pos, |x| x.pos())
-                        .unwrap_or_else(|x| x);
-                    let special_chars = end_width_idx - start_width_idx;
-                    let non_narrow: usize = f.non_narrow_chars[start_width_idx..end_width_idx]
-                        .iter()
-                        .map(|x| x.width())
-                        .sum();
-                    col.0 - special_chars + non_narrow
-                };
-                debug!(
-                    "byte pos {:?} is on the line at byte pos {:?}",
-                    pos, linebpos
-                );
-                debug!(
-                    "char pos {:?} is on the line at char pos {:?}",
-                    chpos, linechpos
-                );
-                debug!("byte is on line: {}", line);
-                //                assert!(chpos >= linechpos);
-                Loc {
-                    file: f,
-                    line,
-                    col,
-                    col_display,
+        {
+            let child_metadata_only_files = child.metadata_only_files.borrow();
+            let mut metadata_only_files = self.metadata_only_files.borrow_mut();
+            for f in &new_files {
+                if let Some(path) = child_metadata_only_files.get(&f.start_pos) {
+                    metadata_only_files.insert(f.start_pos, path.clone());
                 }
             }
-            Err(f) => {
-                let chpos = self.bytepos_to_file_charpos(pos);
-
-                let col_display = {
-                    let end_width_idx = f
-                        .non_narrow_chars
-                        .binary_search_by_key(&pos, |x| x.pos())
-                        .unwrap_or_else(|x| x);
-                    let non_narrow: usize = f.non_narrow_chars[0..end_width_idx]
-                        .iter()
-                        .map(|x| x.width())
-                        .sum();
-                    chpos.0 - end_width_idx + non_narrow
-                };
-                Loc {
-                    file: f,
-                    line: 0,
-                    col: chpos,
-                    col_display,
+        }
+        {
+            let mut loaded_files = self.loaded_files.borrow_mut();
+            for (key, f) in child.loaded_files.borrow().iter() {
+                if new_files.iter().any(|new| Lrc::ptr_eq(new, f)) {
+                    loaded_files.entry(key.clone()).or_insert_with(|| f.clone());
                 }
             }
         }
+
+        for f in new_files {
+            self.insert_source_file(f);
+        }
     }
 
-    /// If the relevant source_file is empty, we don't return a line number.
-    pub fn lookup_line(&self, pos: BytePos) -> Result<SourceFileAndLine, Lrc<SourceFile>> {
-        let f = self.lookup_source_file(pos);
+    /// Creates a source map containing `files`, which are usually deserialized
+    /// from the files of a source map in a previous run. See the `serde-impl`
+    /// feature.
+    ///
+    /// The files keep their positions, so spans created for them stay valid.
+    /// Files added later are placed after all of `files`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the position ranges of `files` overlap.
+    pub fn from_serialized(path_mapping: FilePathMapping, files: Vec<SourceFile>) -> SourceMap {
+        let mut files = files.into_iter().map(Lrc::new).collect::<Vec<_>>();
+        files.sort_by_key(|f| f.start_pos);
+        for w in files.windows(2) {
+            assert!(
+                w[0].end_pos < w[1].start_pos,
+                "positions of {} and {} overlap",
+                w[0].name,
+                w[1].name
+            );
+        }
 
-        self.lookup_line_with(f, pos)
+        let start_pos = files.last().map_or(0, |f| f.end_pos.to_usize() + 1);
+        let stable_id_to_source_file = files
+            .iter()
+            .map(|f| (StableSourceFileId::new(f), f.clone()))
+            .collect();
+
+        SourceMap {
+            files: Lock::new(SourceMapFiles {
+                source_files: files,
+                stable_id_to_source_file,
+            }),
+            start_pos: Lrc::new(AtomicUsize::new(start_pos)),
+            ..SourceMap::new(path_mapping)
+        }
     }
 
-    /// If the relevant source_file is empty, we don't return a line number.
+    /// Returns the files sorted by their start positions.
     ///
-    /// This method exists only for optimization and it's not part of public
-    /// api.
-    #[doc(hidden)]
-    pub fn lookup_line_with(
+    /// The files are locked while the returned guard is alive, so calling a
+    /// method of this source map meanwhile, like [SourceMap::new_source_file]
+    /// or [SourceMap::lookup_char_pos], deadlocks with the `concurrent`
+    /// feature and panics without it. Note that a guard created in the head of
+    /// a `for` loop lives until the end of the loop. Use
+    /// [SourceMap::for_each_file] or [SourceMap::files_snapshot] if the source
+    /// map is used while iterating.
+    pub fn files(&self) -> MappedLockGuard<'_, Vec<Lrc<SourceFile>>> {
+        LockGuard::map(self.files.borrow(), |files| &mut files.source_files)
+    }
+
+    /// Returns the files sorted by their start positions, without holding
+    /// the lock of the files.
+    pub fn files_snapshot(&self) -> Vec<Lrc<SourceFile>> {
+        self.files.borrow().source_files.clone()
+    }
+
+    /// Calls `op` with each file, in the order of their start positions.
+    ///
+    /// The lock of the files is released before `op` is called, so `op` can
+    /// use this source map. Files added by `op` are not visited.
+    pub fn for_each_file(&self, op: impl FnMut(&Lrc<SourceFile>)) {
+        self.files_snapshot().iter().for_each(op)
+    }
+
+    pub fn source_file_by_stable_id(
+        &self,
+        stable_id: StableSourceFileId,
+    ) -> Option<Lrc<SourceFile>> {
+        self.files
+            .borrow()
+            .stable_id_to_source_file
+            .get(&stable_id)
+            .cloned()
+    }
+
+    /// Creates a file for a source which does not have a name, e.g. code in a
+    /// string.
+    ///
+    /// Unlike [FileName::Anon], each file gets a distinct
+    /// [FileName::NumberedAnon] name, displayed like `<anon:0>`, which is
+    /// unique among the files created by this method for this source map. So
+    /// [SourceMap::get_source_file] and the source map
+    /// built by [SourceMap::build_source_map] can tell them apart.
+    /// [FileName::is_anon] returns `true` for the name.
+    pub fn new_anon_source_file(&self, src: String) -> Lrc<SourceFile> {
+        let n = self.anon_files.fetch_add(1, SeqCst);
+        self.new_source_file(FileName::NumberedAnon(n), src)
+    }
+
+    fn next_start_pos(&self, len: usize) -> usize {
+        // Add one so there is some space between files. This lets us distinguish
+        // positions in the source_map, even in the presence of zero-length files.
+        self.start_pos.fetch_add(len + 1, SeqCst)
+    }
+
+    /// Returns how much of the [BytePos] space is used by the files, including
+    /// the position after each file.
+    ///
+    /// This is the start position of the next file. Saturates at `u32::MAX`.
+    pub fn used_pos_space(&self) -> u32 {
+        min(self.start_pos.load(SeqCst), u32::MAX as usize) as u32
+    }
+
+    /// Creates a new source_file.
+    /// This does not ensure that only one SourceFile exists per file name.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip(self, filename, src),
+            fields(name = %filename, len = src.len())
+        )
+    )]
+    pub fn new_source_file(&self, filename: FileName, src: String) -> Lrc<SourceFile> {
+        let source_file = Lrc::new(self.create_source_file(filename, src));
+        self.insert_source_file(source_file.clone());
+
+        source_file
+    }
+
+    /// Registers a file of which only the line structure is known, e.g. an
+    /// original file of an existing source map which is being chained.
+    ///
+    /// `line_starts` are the offsets of the beginnings of lines from the start
+    /// of the file, like [SourceFile::lines] but relative, and `len` is the
+    /// length of the file in bytes. Positions in the file can be looked up and
+    /// used by [SourceMap::build_source_map] as usual, but columns are byte
+    /// offsets as the characters of the file are unknown.
+    ///
+    /// If `src` is `None`, methods returning snippets fail with
+    /// [SpanSnippetError::SourceNotAvailable] and [SourceFile::get_line]
+    /// returns `None`. If `src` is given, the file is the same as a file added
+    /// by [SourceMap::new_source_file].
+    ///
+    /// `src_hash` is the [SourceFile::src_hash] of the original file, if it is
+    /// known. Without `src` and `src_hash`, the file has the hash of an empty
+    /// source.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `line_starts` is not sorted, does not start at zero or is out
+    /// of the file, or if `src` does not match `len`, `line_starts` and
+    /// `src_hash`.
+    pub fn new_imported_source_file(
+        &self,
+        filename: FileName,
+        line_starts: Vec<BytePos>,
+        len: u32,
+        src: Option<String>,
+        src_hash: Option<u128>,
+    ) -> Lrc<SourceFile> {
+        let source_file = match src {
+            Some(src) => {
+                let source_file = self.create_source_file(filename, src);
+                assert!(
+                    source_file.byte_length() == len
+                        && source_file
+                            .lines
+                            .iter()
+                            .map(|&pos| pos - source_file.start_pos)
+                            .eq(line_starts.iter().copied())
+                        && src_hash.map_or(true, |hash| hash == source_file.src_hash()),
+                    "the source of {} does not match the given line table or hash",
+                    source_file.name
+                );
+                source_file
+            }
+            None => {
+                assert!(
+                    line_starts
+                        .first()
+                        .map_or(len == 0, |&pos| pos == BytePos(0))
+                        && line_starts.windows(2).all(|w| w[0] < w[1])
+                        && line_starts.last().map_or(true, |&pos| pos.0 < len),
+                    "invalid line table for {}",
+                    filename
+                );
+
+                let unmapped_path = filename.clone();
+                let (filename, was_remapped) = self.intern_file_name(filename);
+                let start_pos = BytePos::from_usize(self.next_start_pos(len as usize));
+                SourceFile::new_imported(
+                    filename,
+                    was_remapped,
+                    unmapped_path,
+                    line_starts.into_iter().map(|pos| start_pos + pos).collect(),
+                    len,
+                    start_pos,
+                    src_hash,
+                )
+            }
+        };
+
+        let source_file = Lrc::new(source_file);
+        self.insert_source_file(source_file.clone());
+
+        source_file
+    }
+
+    /// Applies [FilePathMapping] to `filename` and interns it.
+    ///
+    /// Returns the name and whether it was remapped.
+    fn intern_file_name(&self, filename: FileName) -> (Lrc<FileName>, bool) {
+        let (filename, was_remapped) = match filename {
+            FileName::Real(filename) => {
+                let (filename, was_remapped) = self.path_mapping.map_prefix(filename);
+                (FileName::Real(filename), was_remapped)
+            }
+            other => (other, false),
+        };
+
+        (self.interner.intern(filename), was_remapped)
+    }
+
+    /// Creates a source file placed after the existing files, without adding it
+    /// to the source map.
+    fn create_source_file(&self, filename: FileName, src: String) -> SourceFile {
+        // The path is used to determine the directory for loading submodules and
+        // include files, so it must be before remapping.
+        // Note that filename may not be a valid path, eg it may be `<anon>` etc,
+        // but this is okay because the directory determined by `path.pop()` will
+        // be empty, so the working directory will be used.
+        let unmapped_path = filename.clone();
+
+        let (filename, was_remapped) = self.intern_file_name(filename);
+        let start_pos = self.next_start_pos(src.len());
+
+        // Analyzing and hashing the source is expensive, so it's done without
+        // holding the lock.
+        SourceFile::new(
+            filename,
+            was_remapped,
+            unmapped_path,
+            src,
+            Pos::from_usize(start_pos),
+        )
+    }
+
+    /// Registers `new_src`, which is the source of `old` with something
+    /// prepended, e.g. a banner, as a new file.
+    ///
+    /// Returns the new file and the delta which should be added to positions
+    /// in `old` to get the same positions in the new file. A BOM at the start
+    /// of `new_src` is stripped before comparing it with `old`.
+    ///
+    /// Returns `None` if the source of `old` is not available or `new_src`
+    /// does not end with it. The delta is only meaningful for the unchanged
+    /// part of the file, so the caller must not change the content after the
+    /// prepended text.
+    pub fn new_source_file_from_existing(
+        &self,
+        old: &Lrc<SourceFile>,
+        mut new_src: String,
+    ) -> Option<(Lrc<SourceFile>, i64)> {
+        let old_src = self.source_of(old).ok()?;
+        let had_bom = crate::syntax_pos::remove_bom(&mut new_src);
+        let prepended = new_src.len().checked_sub(old_src.len())?;
+        if !new_src.ends_with(&**old_src) {
+            return None;
+        }
+
+        let start_pos = self.next_start_pos(new_src.len());
+        let mut source_file = SourceFile::new(
+            old.name.clone(),
+            old.name_was_remapped,
+            old.unmapped_path
+                .clone()
+                .unwrap_or_else(|| (*old.name).clone()),
+            new_src,
+            Pos::from_usize(start_pos),
+        );
+        source_file.had_bom = had_bom;
+        let source_file = Lrc::new(source_file);
+        self.insert_source_file(source_file.clone());
+
+        let delta = (start_pos + prepended) as i64 - old.start_pos.to_usize() as i64;
+        Some((source_file, delta))
+    }
+
+    fn insert_source_file(&self, source_file: Lrc<SourceFile>) {
+        self.add_source_file(&source_file);
+        self.notify_file_added(&source_file);
+    }
+
+    /// Adds `source_file` to the files, without calling the callbacks
+    /// registered with [SourceMap::on_file_added].
+    fn add_source_file(&self, source_file: &Lrc<SourceFile>) {
+        let stable_id = StableSourceFileId::new(source_file);
+
+        let mut files = self.files.borrow_mut();
+        // Another thread may have added a file with a larger `start_pos` in
+        // the meantime, so the file is inserted at the sorted position to
+        // keep `lookup_source_file_in` working.
+        let idx = files
+            .source_files
+            .partition_point(|f| f.start_pos < source_file.start_pos);
+        files.source_files.insert(idx, source_file.clone());
+        files
+            .stable_id_to_source_file
+            .insert(stable_id, source_file.clone());
+    }
+
+    fn notify_file_added(&self, source_file: &Lrc<SourceFile>) {
+        // Callbacks are called without holding any lock, so they can use the
+        // source map.
+        let callbacks = self
+            .file_added_callbacks
+            .borrow()
+            .iter()
+            .map(|(_, callback)| callback.clone())
+            .collect::<Vec<_>>();
+        let mut first_panic = None;
+        for callback in callbacks {
+            let result =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback(source_file)));
+            if let Err(payload) = result {
+                first_panic.get_or_insert(payload);
+            }
+        }
+        if let Some(payload) = first_panic {
+            std::panic::resume_unwind(payload);
+        }
+    }
+
+    /// Calls `callback` with every file added to this source map from now on,
+    /// no matter which thread adds it.
+    ///
+    /// The callback is called after the file is inserted and without holding
+    /// any lock, so it can look up positions in the source map. If it panics,
+    /// the other callbacks are still called, and then the first panic is
+    /// propagated to the caller which added the file. The file stays in the
+    /// source map.
+    pub fn on_file_added(
+        &self,
+        callback: Box<dyn Fn(&Lrc<SourceFile>) + Sync + Send>,
+    ) -> SubscriptionId {
+        let id = SubscriptionId(self.next_subscription.fetch_add(1, SeqCst));
+        self.file_added_callbacks
+            .borrow_mut()
+            .push((id, Lrc::from(callback)));
+        id
+    }
+
+    /// Removes a callback registered with [SourceMap::on_file_added].
+    ///
+    /// Returns false if the callback was already removed.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut callbacks = self.file_added_callbacks.borrow_mut();
+        let len = callbacks.len();
+        callbacks.retain(|(i, _)| *i != id);
+        callbacks.len() != len
+    }
+
+    pub fn mk_substr_filename(&self, sp: Span) -> String {
+        let pos = self.lookup_char_pos(sp.lo());
+        format!(
+            "<{}:{}:{}>",
+            self.display_file_name(&pos.file.name),
+            pos.line,
+            pos.col.to_usize() + 1
+        )
+    }
+
+    /// Uses different line offsets for each range of lines, for a file which
+    /// contains multiple doctests.
+    ///
+    /// Lines out of the ranges are not adjusted by
+    /// [SourceMap::doctest_offset_line].
+    pub fn with_doctest_offsets(mut self, offsets: Vec<(Range<usize>, isize)>) -> Self {
+        self.doctest_offsets = offsets;
+        self
+    }
+
+    /// Reports locations as if the files were embedded in `file`, e.g. for a
+    /// script in a HTML file.
+    ///
+    /// `line_offset` is added to all lines, and `first_line_col_offset` to
+    /// the columns of the first line, where the embedded code starts.
+    /// [SourceMap::lookup_char_pos_adj] and [SourceMap::span_to_string]
+    /// apply these offsets.
+    pub fn with_doctest_offset(
+        mut self,
+        file: FileName,
+        line_offset: isize,
+        first_line_col_offset: usize,
+    ) -> Self {
+        self.doctest_offset = Some((file, line_offset, first_line_col_offset));
+        self
+    }
+
+    // If there is a doctest_offset, apply it to the line
+    pub fn doctest_offset_line(&self, orig: usize) -> usize {
+        let line = if self.doctest_offsets.is_empty() {
+            self.doctest_offset.as_ref().map(|(_, line, _)| *line)
+        } else {
+            self.doctest_offsets
+                .iter()
+                .find(|(range, _)| range.contains(&orig))
+                .map(|(_, line)| *line)
+        };
+
+        match line {
+            Some(line) if line >= 0 => orig + line as usize,
+            Some(line) => orig - (-line) as usize,
+            None => orig,
+        }
+    }
+
+    /// Applies the column offset of [SourceMap::with_doctest_offset] to `col`
+    /// if `line` is the first one. `line` is 1-based and not adjusted.
+    pub fn doctest_offset_col(&self, line: usize, col: usize) -> usize {
+        match self.doctest_offset {
+            Some((_, _, offset)) if line == 1 => col + offset,
+            _ => col,
+        }
+    }
+
+    /// Lookup source information about a BytePos
+    #[track_caller]
+    pub fn lookup_char_pos(&self, pos: BytePos) -> Loc {
+        let fm = self.lookup_source_file(pos);
+        // `fm` does not contain `pos` only if the lookup is degraded, which is
+        // already reported.
+        let pos = pos.clamp(fm.start_pos, fm.end_pos);
+        self.lookup_char_pos_with(fm, pos)
+    }
+
+    /// Lookup source information about a BytePos
+    ///
+    ///
+    /// This method exists only for optimization and it's not part of public
+    /// api.
+    #[doc(hidden)]
+    #[track_caller]
+    pub fn lookup_char_pos_with(&self, fm: Lrc<SourceFile>, pos: BytePos) -> Loc {
+        let pos = check_pos_in_file(&fm, pos, debug_spans);
+        Self::lookup_char_pos_in(fm, pos)
+    }
+
+    /// Computes the location of `pos` in `fm`, which should be the file
+    /// containing `pos`.
+    fn lookup_char_pos_in(fm: Lrc<SourceFile>, pos: BytePos) -> Loc {
+        match fm.lookup_line(pos) {
+            Some(a) => {
+                let chpos = Self::bytepos_to_file_charpos_with(&fm, pos);
+                let linechpos = Self::bytepos_to_file_charpos_with(&fm, fm.lines[a]);
+
+                Self::loc_in_line(fm, pos, a, chpos, linechpos)
+            }
+            // Only an empty file has no lines, so `pos` is at the start of the
+            // file.
+            None => Loc {
+                file: fm,
+                line: 1,
+                col: CharPos(0),
+                col_display: 0,
+            },
+        }
+    }
+
+    /// Creates the location of `pos`, which is on the line at index `a` of `f`.
+    /// `chpos` and `linechpos` are the char positions of `pos` and of the start
+    /// of the line.
+    fn loc_in_line(
+        f: Lrc<SourceFile>,
+        pos: BytePos,
+        a: usize,
+        chpos: CharPos,
+        linechpos: CharPos,
+    ) -> Loc {
+        let line = a + 1; // Line numbers start at 1
+        let linebpos = f.lines[a];
+        assert!(
+            pos >= linebpos,
+            "{}: bpos = {:?}; linebpos = {:?};",
+            f.name,
+            pos,
+            linebpos,
+        );
+
+        let col = max(chpos, linechpos) - min(chpos, linechpos);
+
+        let col_display = {
+            let start_width_idx = f
+                .non_narrow_chars
+                .binary_search_by_key(&linebpos, |x| x.pos())
+                .unwrap_or_else(|x| x);
+            let end_width_idx = f
+                .non_narrow_chars
+                .binary_search_by_key(&pos, |x| x.pos())
+                .unwrap_or_else(|x| x);
+            let special_chars = end_width_idx - start_width_idx;
+            let non_narrow: usize = f.non_narrow_chars[start_width_idx..end_width_idx]
+                .iter()
+                .map(|x| x.width())
+                .sum();
+            col.0 - special_chars + non_narrow
+        };
+        debug!(
+            "byte pos {:?} is on the line at byte pos {:?}",
+            pos, linebpos
+        );
+        debug!(
+            "char pos {:?} is on the line at char pos {:?}",
+            chpos, linechpos
+        );
+        debug!("byte is on line: {}", line);
+        //                assert!(chpos >= linechpos);
+        Loc {
+            file: f,
+            line,
+            col,
+            col_display,
+        }
+    }
+
+    /// Looks up the locations of many positions at once.
+    ///
+    /// The location of `positions[i]` is at index `i` of the result, and is
+    /// `None` if the position is not in any file. The result is the same as
+    /// calling [SourceMap::lookup_char_pos] for each position, but positions
+    /// are visited in sorted order, so each file is looked up once and its
+    /// multibyte characters are scanned once.
+    pub fn lookup_char_pos_many(&self, positions: &[BytePos]) -> Vec<Option<Loc>> {
+        Self::lookup_char_pos_many_in(&self.files.borrow().source_files, positions)
+    }
+
+    fn lookup_char_pos_many_in(
+        files: &[Lrc<SourceFile>],
+        positions: &[BytePos],
+    ) -> Vec<Option<Loc>> {
+        let mut order = (0..positions.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&idx| positions[idx]);
+
+        let mut locs = vec![None; positions.len()];
+        let mut cur: Option<(Lrc<SourceFile>, ExtraBytes, ExtraBytes)> = None;
+
+        for idx in order {
+            let pos = positions[idx];
+            let in_cur = match &cur {
+                Some((f, ..)) => f.start_pos <= pos && pos <= f.end_pos,
+                None => false,
+            };
+            if !in_cur {
+                match Self::lookup_source_file_in(files, pos) {
+                    Some(f) => cur = Some((f, Default::default(), Default::default())),
+                    None => continue,
+                }
+            }
+            let (f, ch, line_ch) = cur.as_mut().unwrap();
+
+            locs[idx] = Some(match f.lookup_line(pos) {
+                Some(a) => {
+                    let chpos = ch.char_pos(f, pos);
+                    let linechpos = line_ch.char_pos(f, f.lines[a]);
+                    Self::loc_in_line(f.clone(), pos, a, chpos, linechpos)
+                }
+                None => Self::lookup_char_pos_in(f.clone(), pos),
+            });
+        }
+
+        locs
+    }
+
+    /// Same as [SourceMap::lookup_char_pos], but `col_display` of the result
+    /// expands tabs to the next multiple of `tab_width` instead of treating
+    /// them as 4 columns wide.
+    ///
+    /// If [SourceMap::display_col_of] returns `None`, e.g. because the source
+    /// code is not available, `col_display` is the one of
+    /// [SourceMap::lookup_char_pos].
+    pub fn lookup_char_pos_with_tab_width(&self, pos: BytePos, tab_width: usize) -> Loc {
+        let mut loc = self.lookup_char_pos(pos);
+        if let Some(col_display) = self.display_col_of(pos, tab_width) {
+            loc.col_display = col_display;
+        }
+        loc
+    }
+
+    /// Returns the part of the line containing `sp.lo()` which fits in
+    /// `max_width` columns, along with the columns of `sp` in it.
+    ///
+    /// If `sp` spans multiple lines, the end column is the end of the line.
+    /// See [SnippetWindow::new] for details. Returns `None` if `sp` does not
+    /// resolve to a single file.
+    pub fn window_around(&self, sp: Span, max_width: usize) -> Option<SnippetWindow> {
+        let f = self.try_lookup_source_file(sp.lo())?;
+        if sp.hi() < sp.lo() || sp.hi() > f.end_pos {
+            return None;
+        }
+        let lo = Self::lookup_char_pos_in(f.clone(), sp.lo());
+        let hi = Self::lookup_char_pos_in(f, sp.hi());
+        let line = match lo.line {
+            0 => Default::default(),
+            line => lo.file.get_line(line - 1).unwrap_or_default(),
+        };
+        let end_col = if hi.line == lo.line {
+            hi.col_display
+        } else {
+            usize::MAX
+        };
+
+        Some(SnippetWindow::new(
+            &line,
+            lo.col_display,
+            end_col,
+            max_width,
+        ))
+    }
+
+    /// Returns the display column of `pos`, which accounts for wide
+    /// characters and expands tabs to the next multiple of `tab_width`.
+    ///
+    /// Pass `1` as `tab_width` to treat a tab as one column wide. Returns
+    /// `None` if `pos` is not at a character boundary of a file or the source
+    /// code of the file is not available.
+    pub fn display_col_of(&self, pos: BytePos, tab_width: usize) -> Option<usize> {
+        let f = self.try_lookup_source_file(pos)?;
+        // The source of files added by `load_file_metadata_only` is loaded on
+        // demand.
+        let src = self.source_of(&f).ok()?;
+        if !src.is_char_boundary((pos - f.start_pos).to_usize()) {
+            return None;
+        }
+
+        let line_begin = match f.lookup_line(pos) {
+            Some(line) => f.lines[line],
+            // Empty file
+            None => f.start_pos,
+        };
+
+        Some(display_width(&f, &src, line_begin, pos, tab_width))
+    }
+
+    /// If the relevant source_file is empty, we don't return a line number.
+    pub fn lookup_line(&self, pos: BytePos) -> Result<SourceFileAndLine, Lrc<SourceFile>> {
+        let f = self.lookup_source_file(pos);
+
+        self.lookup_line_with(f, pos)
+    }
+
+    /// If the relevant source_file is empty, we don't return a line number.
+    ///
+    /// This method exists only for optimization and it's not part of public
+    /// api.
+    #[doc(hidden)]
+    pub fn lookup_line_with(
         &self,
         f: Lrc<SourceFile>,
         pos: BytePos,
@@ -365,11 +1533,19 @@ impl SourceMap {
 
     pub fn lookup_char_pos_adj(&self, pos: BytePos) -> LocWithOpt {
         let loc = self.lookup_char_pos(pos);
-        LocWithOpt {
-            filename: loc.file.name.clone(),
-            line: loc.line,
-            col: loc.col,
-            file: Some(loc.file),
+        match self.doctest_offset {
+            Some((ref filename, _, _)) => LocWithOpt {
+                filename: filename.clone(),
+                line: self.doctest_offset_line(loc.line),
+                col: CharPos(self.doctest_offset_col(loc.line, loc.col.0)),
+                file: Some(loc.file),
+            },
+            None => LocWithOpt {
+                filename: (*loc.file.name).clone(),
+                line: loc.line,
+                col: loc.col,
+                file: Some(loc.file),
+            },
         }
     }
 
@@ -409,6 +1585,61 @@ impl SourceMap {
         }
     }
 
+    /// Returns true if `outer` fully encloses `inner` and both spans are in the
+    /// same source file.
+    pub fn span_contains(&self, outer: Span, inner: Span) -> bool {
+        match (
+            self.span_within_one_file(outer),
+            self.span_within_one_file(inner),
+        ) {
+            (Some(a), Some(b)) if a.start_pos == b.start_pos => outer.contains(inner),
+            _ => false,
+        }
+    }
+
+    /// Returns true if `a` and `b` share at least one byte and both spans are
+    /// in the same source file.
+    pub fn spans_overlap(&self, a: Span, b: Span) -> bool {
+        match (self.span_within_one_file(a), self.span_within_one_file(b)) {
+            (Some(fa), Some(fb)) if fa.start_pos == fb.start_pos => {
+                a.lo() < b.hi() && b.lo() < a.hi()
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the source file containing both ends of `sp`.
+    ///
+    /// Returns `None` for dummy spans, spans whose ends are in different
+    /// files and spans which are not in any file. This is a cheap check before
+    /// calling e.g. [SourceMap::span_to_snippet], as it does not build errors.
+    pub fn span_within_one_file(&self, sp: Span) -> Option<Lrc<SourceFile>> {
+        if sp.is_dummy() || sp.lo() > sp.hi() {
+            return None;
+        }
+
+        let f = Self::lookup_source_file_in(&self.files.borrow().source_files, sp.lo())?;
+        if sp.hi() <= f.end_pos {
+            Some(f)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if `a` and `b` are in the same source file.
+    ///
+    /// Returns false if either of them is not in any file.
+    pub fn is_same_file(&self, a: BytePos, b: BytePos) -> bool {
+        let files = self.files.borrow();
+        match (
+            Self::lookup_source_file_in(&files.source_files, a),
+            Self::lookup_source_file_in(&files.source_files, b),
+        ) {
+            (Some(fa), Some(fb)) => fa.start_pos == fb.start_pos,
+            _ => false,
+        }
+    }
+
     pub fn span_to_string(&self, sp: Span) -> String {
         if self.files.borrow().source_files.is_empty() && sp.is_dummy() {
             return "no-location".to_string();
@@ -418,7 +1649,7 @@ impl SourceMap {
         let hi = self.lookup_char_pos_adj(sp.hi());
         format!(
             "{}:{}:{}: {}:{}",
-            lo.filename,
+            self.display_file_name(&lo.filename),
             lo.line,
             lo.col.to_usize() + 1,
             hi.line,
@@ -427,45 +1658,80 @@ impl SourceMap {
     }
 
     pub fn span_to_filename(&self, sp: Span) -> FileName {
-        self.lookup_char_pos(sp.lo()).file.name.clone()
+        (*self.lookup_char_pos(sp.lo()).file.name).clone()
+    }
+
+    /// Returns the name of the file containing `sp`, with `mapping` applied to
+    /// its [SourceFile::unmapped_path].
+    ///
+    /// Unlike the [FilePathMapping] of the source map, which is applied when
+    /// files are added, `mapping` can be chosen after parsing. Files without
+    /// an unmapped real path keep their names.
+    pub fn span_to_mapped_filename(&self, sp: Span, mapping: &FilePathMapping) -> Lrc<FileName> {
+        let f = self.lookup_source_file(sp.lo());
+        match f.unmapped_path {
+            Some(FileName::Real(ref path)) => {
+                Lrc::new(FileName::Real(mapping.map_prefix(path.clone()).0))
+            }
+            _ => f.name.clone(),
+        }
     }
 
     pub fn span_to_unmapped_path(&self, sp: Span) -> FileName {
-        self.lookup_char_pos(sp.lo())
-            .file
-            .unmapped_path
-            .clone()
+        self.try_span_to_unmapped_path(sp)
             .expect("SourceMap::span_to_unmapped_path called for imported SourceFile?")
     }
 
+    /// Returns the unmapped path of the file containing `sp`.
+    ///
+    /// Returns `None` if `sp` is not in any file or the file was imported
+    /// without an unmapped path.
+    pub fn try_span_to_unmapped_path(&self, sp: Span) -> Option<FileName> {
+        self.try_lookup_byte_offset(sp.lo())?
+            .sf
+            .unmapped_path
+            .clone()
+    }
+
     pub fn is_multiline(&self, sp: Span) -> bool {
         let lo = self.lookup_char_pos(sp.lo());
         let hi = self.lookup_char_pos(sp.hi());
         lo.line != hi.line
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip(self, sp),
+            fields(len = sp.hi().0.saturating_sub(sp.lo().0))
+        )
+    )]
     pub fn span_to_lines(&self, sp: Span) -> FileLinesResult {
+        Self::span_to_lines_with(sp, |pos| self.lookup_char_pos(pos))
+    }
+
+    fn span_to_lines_with(sp: Span, lookup_char_pos: impl Fn(BytePos) -> Loc) -> FileLinesResult {
         debug!("span_to_lines(sp={:?})", sp);
 
         if sp.lo() > sp.hi() {
             return Err(SpanLinesError::IllFormedSpan(sp));
         }
 
-        let lo = self.lookup_char_pos(sp.lo());
+        let lo = lookup_char_pos(sp.lo());
         debug!("span_to_lines: lo={:?}", lo);
-        let hi = self.lookup_char_pos(sp.hi());
+        let hi = lookup_char_pos(sp.hi());
         debug!("span_to_lines: hi={:?}", hi);
 
         if lo.file.start_pos != hi.file.start_pos {
-            return Err(SpanLinesError::DistinctSources(DistinctSources {
-                begin: (lo.file.name.clone(), lo.file.start_pos),
-                end: (hi.file.name.clone(), hi.file.start_pos),
-            }));
+            return Err(SpanLinesError::DistinctSources(distinct_sources(
+                &lo.file, &hi.file, sp,
+            )));
         }
         assert!(hi.line >= lo.line);
 
         // Empty file contains no lines
-        if lo.file.src.is_empty() {
+        if lo.file.byte_length() == 0 {
             return Ok(FileLines {
                 file: lo.file,
                 lines: vec![],
@@ -482,16 +1748,18 @@ impl SourceMap {
         // and to the end of the line. Be careful because the line
         // numbers in Loc are 1-based, so we subtract 1 to get 0-based
         // lines.
+        //
+        // The length of a line is taken from the tables of the file instead of
+        // its source code, which may not be loaded.
         for line_index in lo.line - 1..hi.line - 1 {
-            let line_len = lo
-                .file
-                .get_line(line_index)
-                .map(|s| s.chars().count())
-                .unwrap_or(0);
+            let line_begin = lo.file.lines[line_index];
+            let line_end = lo.file.lines[line_index + 1] - BytePos(1);
+            let line_len = Self::bytepos_to_file_charpos_with(&lo.file, line_end)
+                - Self::bytepos_to_file_charpos_with(&lo.file, line_begin);
             lines.push(LineInfo {
                 line_index,
                 start_col,
-                end_col: CharPos::from_usize(line_len),
+                end_col: line_len,
             });
             start_col = CharPos::from_usize(0);
         }
@@ -509,12 +1777,51 @@ impl SourceMap {
         })
     }
 
+    /// Same as [SourceMap::span_to_lines], but fixes up malformed spans
+    /// instead of returning an error.
+    ///
+    /// An inverted span is swapped, and a span ending in another file is cut
+    /// at the end of the file containing `sp.lo()`. Returns `None` for dummy
+    /// spans and for spans starting outside of any file.
+    pub fn span_to_lines_lossy(&self, sp: Span) -> Option<FileLines> {
+        if sp.is_dummy() {
+            return None;
+        }
+
+        let (lo, hi) = if sp.lo() <= sp.hi() {
+            (sp.lo(), sp.hi())
+        } else {
+            (sp.hi(), sp.lo())
+        };
+        let f = self.try_lookup_source_file(lo)?;
+        let hi = min(hi, f.end_pos);
+
+        self.span_to_lines(Span::new(lo, hi, sp.ctxt())).ok()
+    }
+
     /// Extract the source surrounding the given `Span` using the
     /// `extract_source` function. The extract function takes three
     /// arguments: a string slice containing the source, an index in
     /// the slice for the beginning of the span and an index in the slice for
     /// the end of the span.
     fn span_to_source<F, Ret>(&self, sp: Span, extract_source: F) -> Result<Ret, SpanSnippetError>
+    where
+        F: FnOnce(&str, usize, usize) -> Ret,
+    {
+        Self::span_to_source_with(
+            sp,
+            |pos| self.try_lookup_byte_offset(pos),
+            |f| self.source_of(f),
+            extract_source,
+        )
+    }
+
+    fn span_to_source_with<F, Ret>(
+        sp: Span,
+        try_lookup_byte_offset: impl Fn(BytePos) -> Option<SourceFileAndBytePos>,
+        source_of: impl FnOnce(&SourceFile) -> Result<Lrc<String>, SpanSnippetError>,
+        extract_source: F,
+    ) -> Result<Ret, SpanSnippetError>
     where
         F: FnOnce(&str, usize, usize) -> Ret,
     {
@@ -522,14 +1829,20 @@ impl SourceMap {
             return Err(SpanSnippetError::IllFormedSpan(sp));
         }
 
-        let local_begin = self.lookup_byte_offset(sp.lo());
-        let local_end = self.lookup_byte_offset(sp.hi());
+        let (local_begin, local_end) = match (
+            try_lookup_byte_offset(sp.lo()),
+            try_lookup_byte_offset(sp.hi()),
+        ) {
+            (Some(begin), Some(end)) => (begin, end),
+            _ => return Err(SpanSnippetError::IllFormedSpan(sp)),
+        };
 
         if local_begin.sf.start_pos != local_end.sf.start_pos {
-            Err(SpanSnippetError::DistinctSources(DistinctSources {
-                begin: (local_begin.sf.name.clone(), local_begin.sf.start_pos),
-                end: (local_end.sf.name.clone(), local_end.sf.start_pos),
-            }))
+            Err(SpanSnippetError::DistinctSources(distinct_sources(
+                &local_begin.sf,
+                &local_end.sf,
+                sp,
+            )))
         } else {
             let start_index = local_begin.pos.to_usize();
             let end_index = local_end.pos.to_usize();
@@ -538,7 +1851,7 @@ impl SourceMap {
             if start_index > end_index || end_index > source_len {
                 return Err(SpanSnippetError::MalformedForSourcemap(
                     MalformedSourceMapPositions {
-                        name: local_begin.sf.name.clone(),
+                        name: (*local_begin.sf.name).clone(),
                         source_len,
                         begin_pos: local_begin.pos,
                         end_pos: local_end.pos,
@@ -546,8 +1859,9 @@ impl SourceMap {
                 ));
             }
 
-            let src = &local_begin.sf.src;
-            Ok(extract_source(src, start_index, end_index))
+            let src = source_of(&local_begin.sf)?;
+            check_char_boundaries(&local_begin.sf, &src, start_index, end_index)?;
+            Ok(extract_source(&src, start_index, end_index))
         }
     }
 
@@ -558,14 +1872,181 @@ impl SourceMap {
         })
     }
 
+    /// Returns the source snippets of `spans`, in the same order as `spans`.
+    ///
+    /// This is equivalent to calling [SourceMap::span_to_snippet] for each
+    /// span, but each source file is looked up only once and the returned
+    /// snippets share the source text of the file instead of copying it.
+    ///
+    /// An error for a span does not affect the results of other spans.
+    pub fn snippets_of_spans(
+        &self,
+        spans: &[Span],
+    ) -> Vec<Result<SourceSnippet, SpanSnippetError>> {
+        let resolved = {
+            let files = self.files.borrow();
+            let mut resolved: Vec<Lrc<SourceFile>> = vec![];
+
+            let mut lookup = |pos: BytePos| -> Option<Lrc<SourceFile>> {
+                // `end_pos` belongs to the file, as in `lookup_source_file_in`.
+                if let Some(f) = resolved
+                    .iter()
+                    .find(|f| f.start_pos <= pos && pos <= f.end_pos)
+                {
+                    return Some(f.clone());
+                }
+
+                let f = Self::lookup_source_file_in(&files.source_files, pos)?;
+                resolved.push(f.clone());
+                Some(f)
+            };
+
+            spans
+                .iter()
+                .map(|&sp| {
+                    if sp.lo() > sp.hi() {
+                        return Err(SpanSnippetError::IllFormedSpan(sp));
+                    }
+
+                    match (lookup(sp.lo()), lookup(sp.hi())) {
+                        (Some(begin), Some(end)) => {
+                            let range = snippet_range(&begin, &end, sp)?;
+                            Ok((begin, range))
+                        }
+                        _ => Err(SpanSnippetError::IllFormedSpan(sp)),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Sources of metadata-only files are read without holding the lock.
+        resolved
+            .into_iter()
+            .map(|resolved| {
+                let (begin, (start_index, end_index)) = resolved?;
+
+                let src = self.source_of(&begin)?;
+                check_char_boundaries(&begin, &src, start_index, end_index)?;
+                Ok(OwningRef::new(src).map(|src| &src[start_index..end_index]))
+            })
+            .collect()
+    }
+
+    /// Calls `op` with the index and the source snippet of each span in
+    /// `spans`, while taking the lock of the files only once.
+    ///
+    /// Spans are visited in the order of their start positions, so a file is
+    /// looked up only once for a run of spans in the file. Unlike
+    /// [SourceMap::span_to_snippet], the dummy span is reported as
+    /// [SpanSnippetError::IllFormedSpan]. An error for a span does not affect
+    /// other spans.
+    ///
+    /// The lock is released before `op` is called, so `op` can use this
+    /// source map.
+    pub fn with_snippets_of_spans<F>(&self, spans: &[Span], mut op: F)
+    where
+        F: FnMut(usize, Result<&str, SpanSnippetError>),
+    {
+        let mut order = (0..spans.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&idx| spans[idx].lo());
+
+        let resolved = {
+            let files = self.files.borrow();
+            let mut cur: Option<Lrc<SourceFile>> = None;
+
+            order
+                .into_iter()
+                .map(|idx| {
+                    let sp = spans[idx];
+                    if sp.is_dummy() || sp.lo() > sp.hi() {
+                        return (idx, Err(SpanSnippetError::IllFormedSpan(sp)));
+                    }
+
+                    let is_in_cur = match cur {
+                        Some(ref f) => f.start_pos <= sp.lo() && sp.lo() <= f.end_pos,
+                        None => false,
+                    };
+                    if !is_in_cur {
+                        cur = Self::lookup_source_file_in(&files.source_files, sp.lo());
+                    }
+                    let begin = match cur {
+                        Some(ref f) => f.clone(),
+                        None => return (idx, Err(SpanSnippetError::IllFormedSpan(sp))),
+                    };
+
+                    let end = if sp.hi() <= begin.end_pos {
+                        begin.clone()
+                    } else {
+                        match Self::lookup_source_file_in(&files.source_files, sp.hi()) {
+                            Some(f) => f,
+                            None => return (idx, Err(SpanSnippetError::IllFormedSpan(sp))),
+                        }
+                    };
+
+                    let range = snippet_range(&begin, &end, sp);
+                    (idx, range.map(|range| (begin, range)))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (idx, resolved) in resolved {
+            let snippet = resolved.and_then(|(f, (start_index, end_index))| {
+                let src = self.source_of(&f)?;
+                check_char_boundaries(&f, &src, start_index, end_index)?;
+                Ok((src, start_index, end_index))
+            });
+            match snippet {
+                Ok((src, start_index, end_index)) => op(idx, Ok(&src[start_index..end_index])),
+                Err(err) => op(idx, Err(err)),
+            }
+        }
+    }
+
+    /// Applies `op` to the source snippet of each span in `spans` and returns
+    /// the results in the same order as `spans`.
+    ///
+    /// Files are looked up as in [SourceMap::with_snippets_of_spans]. If any
+    /// span fails, the error of the first such span in `spans` is returned.
+    /// `op` is called without holding the lock of the files, so it can use
+    /// this source map.
+    pub fn with_snippet_of_spans<F, R>(
+        &self,
+        spans: &[Span],
+        mut op: F,
+    ) -> Result<Vec<R>, SpanSnippetError>
+    where
+        F: FnMut(&str) -> R,
+    {
+        let mut results: Vec<Option<Result<R, SpanSnippetError>>> =
+            (0..spans.len()).map(|_| None).collect();
+
+        self.with_snippets_of_spans(spans, |idx, snippet| {
+            results[idx] = Some(snippet.map(&mut op));
+        });
+
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
     pub fn span_to_margin(&self, sp: Span) -> Option<usize> {
-        match self.span_to_prev_source(sp) {
-            Err(_) => None,
-            Ok(source) => source
-                .split('\n')
-                .last()
-                .map(|last_line| last_line.len() - last_line.trim_start().len()),
+        fn margin(last_line: &str) -> usize {
+            last_line.len() - last_line.trim_start().len()
+        }
+
+        // The start of the line is usually close, so the whole file before `sp`
+        // is only scanned if the line is longer than the window.
+        let found = self
+            .with_prev_source_up_to(sp, MARGIN_LOOKBACK, |source| {
+                source.rfind('\n').map(|idx| margin(&source[idx + 1..]))
+            })
+            .ok()?;
+        if found.is_some() {
+            return found;
         }
+
+        self.with_span_to_prev_source(sp, |source| {
+            margin(source.rsplit('\n').next().unwrap_or(""))
+        })
+        .ok()
     }
 
     /// Calls the given closure with the source snippet before the given `Span`
@@ -576,6 +2057,29 @@ impl SourceMap {
         self.span_to_source(sp, |src, start_index, _| op(&src[..start_index]))
     }
 
+    /// Same as [SourceMap::with_span_to_prev_source], but `op` is called with
+    /// at most `max_bytes` before the given `Span`.
+    ///
+    /// The snippet starts at a char boundary, so it may be a few bytes shorter
+    /// than `max_bytes` even if there's more source before it.
+    pub fn with_prev_source_up_to<F, Ret>(
+        &self,
+        sp: Span,
+        max_bytes: usize,
+        op: F,
+    ) -> Result<Ret, SpanSnippetError>
+    where
+        F: FnOnce(&str) -> Ret,
+    {
+        self.span_to_source(sp, |src, start_index, _| {
+            let mut begin = start_index.saturating_sub(max_bytes);
+            while !src.is_char_boundary(begin) {
+                begin += 1;
+            }
+            op(&src[begin..start_index])
+        })
+    }
+
     /// Return the source snippet as `String` before the given `Span`
     pub fn span_to_prev_source(&self, sp: Span) -> Result<String, SpanSnippetError> {
         self.with_span_to_prev_source(sp, |s| s.to_string())
@@ -598,14 +2102,18 @@ impl SourceMap {
     /// Return the same span if no character could be found or if an error
     /// occurred while retrieving the code snippet.
     pub fn span_extend_to_prev_char(&self, sp: Span, c: char) -> Span {
-        if let Ok(prev_source) = self.span_to_prev_source(sp) {
+        self.with_span_to_prev_source(sp, |prev_source| {
             let prev_source = prev_source.rsplit(c).nth(0).unwrap_or("").trim_start();
             if !prev_source.is_empty() && !prev_source.contains('\n') {
-                return sp.with_lo(BytePos(sp.lo().0 - prev_source.len() as u32));
+                match sp.lo().0.checked_sub(prev_source.len() as u32) {
+                    Some(lo) => self.checked_adjusted_span(sp, sp.with_lo(BytePos(lo))),
+                    None => sp,
+                }
+            } else {
+                sp
             }
-        }
-
-        sp
+        })
+        .unwrap_or(sp)
     }
 
     /// Extend the given `Span` to just after the previous occurrence of `pat`
@@ -613,21 +2121,42 @@ impl SourceMap {
     /// could be found or if an error occurred while retrieving the code
     /// snippet.
     pub fn span_extend_to_prev_str(&self, sp: Span, pat: &str, accept_newlines: bool) -> Span {
-        // assure that the pattern is delimited, to avoid the following
-        //     fn my_fn()
-        //           ^^^^ returned span without the check
-        //     ---------- correct span
-        for ws in &[" ", "\t", "\n"] {
-            let pat = pat.to_owned() + ws;
-            if let Ok(prev_source) = self.span_to_prev_source(sp) {
-                let prev_source = prev_source.rsplit(&pat).nth(0).unwrap_or("").trim_start();
-                if !prev_source.is_empty() && (!prev_source.contains('\n') || accept_newlines) {
-                    return sp.with_lo(BytePos(sp.lo().0 - prev_source.len() as u32));
+        if pat.is_empty() {
+            return sp;
+        }
+
+        self.with_span_to_prev_source(sp, |prev_source| {
+            // assure that the pattern is delimited, to avoid the following
+            //     fn my_fn()
+            //           ^^^^ returned span without the check
+            //     ---------- correct span
+            // Any whitespace, including `\r\n` and non-breaking spaces, is a
+            // delimiter.
+            let mut end = prev_source.len();
+            let after_pat = loop {
+                let idx = match prev_source[..end].rfind(pat) {
+                    Some(idx) => idx,
+                    None => return sp,
+                };
+                let after_pat = &prev_source[idx + pat.len()..];
+                if after_pat.starts_with(char::is_whitespace) {
+                    break after_pat;
+                }
+                end = idx;
+            };
+
+            // `after_pat` ends at `sp.lo()`, so the whitespace after the pattern
+            // is excluded by measuring the rest.
+            let extension = after_pat.trim_start();
+            if !extension.is_empty() && (!extension.contains('\n') || accept_newlines) {
+                if let Some(lo) = sp.lo().0.checked_sub(extension.len() as u32) {
+                    return self.checked_adjusted_span(sp, sp.with_lo(BytePos(lo)));
                 }
             }
-        }
 
-        sp
+            sp
+        })
+        .unwrap_or(sp)
     }
 
     /// Given a `Span`, try to get a shorter span ending before the first
@@ -646,7 +2175,7 @@ impl SourceMap {
             Ok(snippet) => {
                 let snippet = snippet.split(c).nth(0).unwrap_or("").trim_end();
                 if !snippet.is_empty() && !snippet.contains('\n') {
-                    sp.with_hi(BytePos(sp.lo().0 + snippet.len() as u32))
+                    self.checked_adjusted_span(sp, sp.leading_of(snippet.len()))
                 } else {
                     sp
                 }
@@ -668,7 +2197,7 @@ impl SourceMap {
 
         if let Ok(snippet) = self.span_to_snippet(sp) {
             if let Some(offset) = snippet.find(c) {
-                return sp.with_hi(BytePos(sp.lo().0 + (offset + c.len_utf8()) as u32));
+                return self.checked_adjusted_span(sp, sp.leading_of(offset + c.len_utf8()));
             }
         }
         sp
@@ -700,6 +2229,81 @@ impl SourceMap {
         self.span_take_while(sp, |c| !c.is_whitespace())
     }
 
+    /// Returns the span without the leading whitespaces, or the original span
+    /// in case of error.
+    ///
+    /// # Notes
+    ///
+    /// This method returns a dummy span for a dummy span.
+    pub fn span_trim_start(&self, sp: Span) -> Span {
+        if sp.is_dummy() {
+            return sp;
+        }
+
+        self.span_to_source(sp, |src, start_index, end_index| {
+            let snippet = &src[start_index..end_index];
+            let len = snippet.len() - snippet.trim_start().len();
+
+            sp.trailing_of(snippet.len() - len)
+        })
+        .map(|trimmed| self.checked_adjusted_span(sp, trimmed))
+        .unwrap_or(sp)
+    }
+
+    /// Returns the position of the first character in `sp` which is not a
+    /// whitespace according to [char::is_whitespace].
+    ///
+    /// Returns `None` if `sp` is dummy, contains only whitespaces or can't be
+    /// resolved.
+    pub fn first_non_whitespace_pos(&self, sp: Span) -> Option<BytePos> {
+        if sp.is_dummy() {
+            return None;
+        }
+
+        self.span_to_source(sp, |src, start_index, end_index| {
+            let mut offset = 0;
+            for c in src[start_index..end_index].chars() {
+                if !c.is_whitespace() {
+                    return Some(sp.lo() + BytePos(offset));
+                }
+                offset += c.len_utf8() as u32;
+            }
+            None
+        })
+        .ok()
+        .flatten()
+    }
+
+    /// Returns the span without the trailing whitespaces, or the original span
+    /// in case of error.
+    ///
+    /// # Notes
+    ///
+    /// This method returns a dummy span for a dummy span.
+    pub fn span_trim_end(&self, sp: Span) -> Span {
+        if sp.is_dummy() {
+            return sp;
+        }
+
+        self.span_to_source(sp, |src, start_index, end_index| {
+            let snippet = &src[start_index..end_index];
+
+            sp.leading_of(snippet.trim_end().len())
+        })
+        .map(|trimmed| self.checked_adjusted_span(sp, trimmed))
+        .unwrap_or(sp)
+    }
+
+    /// Returns the span without the leading and trailing whitespaces, or the
+    /// original span in case of error.
+    ///
+    /// # Notes
+    ///
+    /// This method returns a dummy span for a dummy span.
+    pub fn span_trim(&self, sp: Span) -> Span {
+        self.span_trim_end(self.span_trim_start(sp))
+    }
+
     /// Given a `Span`, get a shorter one until `predicate` yields false.
     pub fn span_take_while<P>(&self, sp: Span, mut predicate: P) -> Span
     where
@@ -714,8 +2318,9 @@ impl SourceMap {
                 .map(|c| c.len_utf8())
                 .sum::<usize>();
 
-            sp.with_hi(BytePos(sp.lo().0 + (offset as u32)))
+            sp.leading_of(offset)
         })
+        .map(|taken| self.checked_adjusted_span(sp, taken))
         .unwrap_or(sp)
     }
 
@@ -723,172 +2328,346 @@ impl SourceMap {
         self.span_until_char(sp, '{')
     }
 
+    /// Like [SourceMap::def_span], but cuts `sp` before the first character
+    /// for which `stop` returns `true`. `stop` is called for each character
+    /// with the state of the scanner before the character.
+    ///
+    /// Returns `sp` if the result would be empty or span multiple lines.
+    pub fn def_span_until<F>(&self, sp: Span, stop: F) -> Span
+    where
+        F: Fn(char, &DefSpanState) -> bool,
+    {
+        if sp.is_dummy() {
+            return sp;
+        }
+
+        let snippet = match self.span_to_snippet(sp) {
+            Ok(snippet) => snippet,
+            Err(_) => return sp,
+        };
+
+        let mut state = DefSpanState::default();
+        let mut end = snippet.len();
+        for (idx, c) in snippet.char_indices() {
+            if stop(c, &state) {
+                end = idx;
+                break;
+            }
+            state.advance(c);
+        }
+
+        let snippet = snippet[..end].trim_end();
+        if !snippet.is_empty() && !snippet.contains('\n') {
+            self.checked_adjusted_span(sp, sp.leading_of(snippet.len()))
+        } else {
+            sp
+        }
+    }
+
+    /// Returns the span of the head of a JS definition, which ends before the
+    /// first `{` which is not nested in parentheses, brackets, braces or
+    /// literals.
+    ///
+    /// Unlike [SourceMap::def_span], this handles braces in parameters and
+    /// type annotations, like `function g(x: {y: number}) {}`.
+    pub fn def_span_js(&self, sp: Span) -> Span {
+        self.def_span_until(sp, |c, state| c == '{' && state.is_top_level())
+    }
+
+    /// Returns `adjusted`, which is `sp` extended or shrunk by a helper like
+    /// [SourceMap::span_until_char], if it's in the file of `sp` and both of
+    /// its ends are at char boundaries. Returns `sp` otherwise.
+    fn checked_adjusted_span(&self, sp: Span, adjusted: Span) -> Span {
+        if adjusted == sp {
+            return sp;
+        }
+
+        let f = match self.try_lookup_source_file(sp.lo()) {
+            Some(f) => f,
+            None => return sp,
+        };
+        if adjusted.lo() < f.start_pos || adjusted.hi() > f.end_pos {
+            return sp;
+        }
+        let src = match self.source_of(&f) {
+            Ok(src) => src,
+            Err(_) => return sp,
+        };
+
+        let is_boundary = |pos: BytePos| src.is_char_boundary((pos - f.start_pos).to_usize());
+        if is_boundary(adjusted.lo()) && is_boundary(adjusted.hi()) {
+            adjusted
+        } else {
+            sp
+        }
+    }
+
     /// Returns a new span representing just the start-point of this span
+    ///
+    /// Zero-width spans, including the dummy span, are returned as is.
     pub fn start_point(&self, sp: Span) -> Span {
-        let pos = sp.lo().0;
-        let width = self.find_width_of_character_at_span(sp, false);
-        let corrected_start_position = pos.checked_add(width).unwrap_or(pos);
-        let end_point = BytePos(cmp::max(corrected_start_position, sp.lo().0));
-        sp.with_hi(end_point)
+        if sp.lo() >= sp.hi() {
+            return sp.shrink_to_lo();
+        }
+
+        let width = self.find_width_of_character_at_span(sp.shrink_to_lo(), true);
+        self.checked_adjusted_span(sp, sp.leading_of(width as usize))
     }
 
     /// Returns a new span representing just the end-point of this span
+    ///
+    /// Zero-width spans, including the dummy span, are returned as is.
     pub fn end_point(&self, sp: Span) -> Span {
-        let pos = sp.hi().0;
+        if sp.lo() >= sp.hi() {
+            return sp.shrink_to_hi();
+        }
 
         let width = self.find_width_of_character_at_span(sp, false);
-        let corrected_end_position = pos.checked_sub(width).unwrap_or(pos);
-
-        let end_point = BytePos(cmp::max(corrected_end_position, sp.lo().0));
-        sp.with_lo(end_point)
+        self.checked_adjusted_span(sp, sp.trailing_of(width as usize))
     }
 
     /// Returns a new span representing the next character after the end-point
-    /// of this span
+    /// of this span.
+    ///
+    /// Returns an empty span at the end of `sp` if there's no such character.
+    /// See [SourceMap::try_next_point].
     pub fn next_point(&self, sp: Span) -> Span {
-        let start_of_next_point = sp.hi().0;
+        self.try_next_point(sp).unwrap_or_else(|| sp.shrink_to_hi())
+    }
+
+    /// Returns a new span covering the whole character right after the end of
+    /// `sp`.
+    ///
+    /// Returns `None` if `sp` is malformed or if it ends at the end of its
+    /// file, so the returned span never crosses the `end_pos` of the file.
+    pub fn try_next_point(&self, sp: Span) -> Option<Span> {
+        if sp.lo() > sp.hi() {
+            return None;
+        }
 
-        let width = self.find_width_of_character_at_span(sp, true);
-        // If the width is 1, then the next span should point to the same `lo` and `hi`.
-        // However, in the case of a multibyte character, where the width != 1,
-        // the next span should span multiple bytes to include the whole
-        // character.
-        let end_of_next_point = start_of_next_point
-            .checked_add(width - 1)
-            .unwrap_or(start_of_next_point);
+        let f = self.try_lookup_source_file(sp.hi())?;
+        let offset = (sp.hi() - f.start_pos).to_usize();
+        let c = f.src.get(offset..)?.chars().next()?;
 
-        let end_of_next_point = BytePos(cmp::max(sp.lo().0 + 1, end_of_next_point));
-        Span::new(BytePos(start_of_next_point), end_of_next_point, sp.ctxt())
+        Some(Span::new(
+            sp.hi(),
+            sp.hi() + BytePos(c.len_utf8() as u32),
+            sp.ctxt(),
+        ))
     }
 
     /// Finds the width of a character, either before or after the provided
     /// span.
+    ///
+    /// Returns 1 if there's no such character or if the span is malformed.
     fn find_width_of_character_at_span(&self, sp: Span, forwards: bool) -> u32 {
-        // Disregard malformed spans and assume a one-byte wide character.
-        if sp.lo() >= sp.hi() {
+        if sp.lo() > sp.hi() {
             debug!("find_width_of_character_at_span: early return malformed span");
             return 1;
         }
 
-        let local_begin = self.lookup_byte_offset(sp.lo());
-        let local_end = self.lookup_byte_offset(sp.hi());
+        let (local_begin, local_end) = match (
+            self.try_lookup_byte_offset(sp.lo()),
+            self.try_lookup_byte_offset(sp.hi()),
+        ) {
+            (Some(begin), Some(end)) if begin.sf.start_pos == end.sf.start_pos => (begin, end),
+            _ => {
+                debug!("find_width_of_character_at_span: span does not resolve to a file");
+                return 1;
+            }
+        };
         debug!(
             "find_width_of_character_at_span: local_begin=`{:?}`, local_end=`{:?}`",
             local_begin, local_end
         );
 
-        let start_index = local_begin.pos.to_usize();
-        let end_index = local_end.pos.to_usize();
-        debug!(
-            "find_width_of_character_at_span: start_index=`{:?}`, end_index=`{:?}`",
-            start_index, end_index
-        );
-
-        // Disregard indexes that are at the start or end of their spans, they can't fit
-        // bigger characters.
-        if (!forwards && end_index == usize::min_value())
-            || (forwards && start_index == usize::max_value())
-        {
-            debug!("find_width_of_character_at_span: start or end of span, cannot be multibyte");
+        if local_end.sf.is_ascii() {
             return 1;
         }
 
-        let source_len = (local_begin.sf.end_pos - local_begin.sf.start_pos).to_usize();
-        debug!(
-            "find_width_of_character_at_span: source_len=`{:?}`",
-            source_len
-        );
-        // Ensure indexes are also not malformed.
-        if start_index > end_index || end_index > source_len {
-            debug!("find_width_of_character_at_span: source indexes are malformed");
+        let src = &local_end.sf.src;
+        let end_index = local_end.pos.to_usize();
+        if !src.is_char_boundary(end_index) {
+            debug!("find_width_of_character_at_span: end of span is not a char boundary");
             return 1;
         }
 
-        // We need to extend the snippet to the end of the src rather than to end_index
-        // so when searching forwards for boundaries we've got somewhere to
-        // search.
-        let src = &local_begin.sf.src;
-        let snippet = {
-            let len = src.len();
-            &src[start_index..len]
-        };
-        debug!("find_width_of_character_at_span: snippet=`{:?}`", snippet);
-
-        let mut target = if forwards {
-            end_index + 1
+        let c = if forwards {
+            src[end_index..].chars().next()
         } else {
-            end_index - 1
+            src[..end_index].chars().next_back()
         };
-        debug!(
-            "find_width_of_character_at_span: initial target=`{:?}`",
-            target
-        );
-
-        while !snippet.is_char_boundary(target - start_index) && target < source_len {
-            target = if forwards {
-                target + 1
-            } else {
-                match target.checked_sub(1) {
-                    Some(target) => target,
-                    None => {
-                        break;
-                    }
-                }
-            };
-            debug!("find_width_of_character_at_span: target=`{:?}`", target);
-        }
-        debug!(
-            "find_width_of_character_at_span: final target=`{:?}`",
-            target
-        );
+        debug!("find_width_of_character_at_span: char=`{:?}`", c);
 
-        if forwards {
-            (target - end_index) as u32
-        } else {
-            (end_index - target) as u32
-        }
+        c.map_or(1, |c| c.len_utf8() as u32)
     }
 
     pub fn get_source_file(&self, filename: &FileName) -> Option<Lrc<SourceFile>> {
         for sf in self.files.borrow().source_files.iter() {
-            if *filename == sf.name {
+            if *filename == *sf.name {
                 return Some(sf.clone());
             }
         }
         None
     }
 
+    /// Returns [SourceFile::byte_range] of the file named `filename`.
+    pub fn file_range(&self, filename: &FileName) -> Option<Range<BytePos>> {
+        self.get_source_file(filename).map(|f| f.byte_range())
+    }
+
+    /// Finds a file named `filename` with the [SourceFile::src_hash] of `hash`.
+    ///
+    /// Hashes are computed only for the files named `filename`.
+    pub fn source_file_by_name_and_hash(
+        &self,
+        filename: &FileName,
+        hash: u128,
+    ) -> Option<Lrc<SourceFile>> {
+        self.files
+            .borrow()
+            .source_files
+            .iter()
+            .find(|sf| *filename == *sf.name && sf.src_hash() == hash)
+            .cloned()
+    }
+
     /// For a global BytePos compute the local offset within the containing
     /// SourceFile
+    #[track_caller]
     pub fn lookup_byte_offset(&self, bpos: BytePos) -> SourceFileAndBytePos {
         let sf = self.lookup_source_file(bpos);
-        let offset = bpos - sf.start_pos;
+        let offset = bpos.clamp(sf.start_pos, sf.end_pos) - sf.start_pos;
         SourceFileAndBytePos { sf, pos: offset }
     }
 
-    /// Converts an absolute BytePos to a CharPos relative to the source_file.
-    fn bytepos_to_file_charpos(&self, bpos: BytePos) -> CharPos {
-        let map = self.lookup_source_file(bpos);
-
-        self.bytepos_to_file_charpos_with(&map, bpos)
+    /// Same as [SourceMap::lookup_byte_offset], but returns `None` instead of
+    /// panicking if `bpos` does not resolve to a source file.
+    pub fn try_lookup_byte_offset(&self, bpos: BytePos) -> Option<SourceFileAndBytePos> {
+        let sf = self.try_lookup_source_file(bpos)?;
+        let offset = bpos - sf.start_pos;
+        Some(SourceFileAndBytePos { sf, pos: offset })
     }
 
-    fn bytepos_to_file_charpos_with(&self, map: &SourceFile, bpos: BytePos) -> CharPos {
-        let total_extra_bytes = self.calc_extra_bytes(map, &mut 0, bpos);
-        assert!(
-            map.start_pos.to_u32() + total_extra_bytes <= bpos.to_u32(),
-            "map.start_pos = {:?}; total_extra_bytes = {}; bpos = {:?}",
-            map.start_pos,
-            total_extra_bytes,
-            bpos,
-        );
-        CharPos(bpos.to_usize() - map.start_pos.to_usize() - total_extra_bytes as usize)
-    }
+    /// Returns the span of the identifier-like word at `pos`, which consists of
+    /// `[a-zA-Z0-9_$]`.
+    ///
+    /// Returns `None` if the character at `pos` is not a part of a word, e.g.
+    /// a whitespace, a punctuation or the end of the file.
+    pub fn span_of_word_at(&self, pos: BytePos) -> Option<Span> {
+        fn is_word_byte(b: u8) -> bool {
+            b.is_ascii_alphanumeric() || b == b'_' || b == b'$'
+        }
 
-    /// Converts an absolute BytePos to a CharPos relative to the source_file.
-    fn calc_extra_bytes(&self, map: &SourceFile, start: &mut usize, bpos: BytePos) -> u32 {
-        // The number of extra bytes due to multibyte chars in the SourceFile
-        let mut total_extra_bytes = 0;
+        let SourceFileAndBytePos { sf, pos: offset } = self.try_lookup_byte_offset(pos)?;
+        // As all word characters are ascii, scanning bytes never stops in the
+        // middle of a multibyte character.
+        let src = sf.src.as_bytes();
+        let offset = offset.to_usize();
+
+        if !src.get(offset).copied().map_or(false, is_word_byte) {
+            return None;
+        }
+
+        let lo = src[..offset]
+            .iter()
+            .rposition(|&b| !is_word_byte(b))
+            .map_or(0, |i| i + 1);
+        let hi = src[offset..]
+            .iter()
+            .position(|&b| !is_word_byte(b))
+            .map_or(src.len(), |i| offset + i);
+
+        Some(Span::new(
+            sf.start_pos + BytePos(lo as u32),
+            sf.start_pos + BytePos(hi as u32),
+            NO_EXPANSION,
+        ))
+    }
+
+    /// Converts `pos` into an offset in UTF-16 code units from the start of
+    /// the file containing it, which is how LSP counts characters.
+    ///
+    /// A position in the middle of a multibyte character is snapped to the
+    /// start of the character. Returns `None` if `pos` is not in any file.
+    pub fn bytepos_to_utf16(&self, pos: BytePos) -> Option<u32> {
+        let SourceFileAndBytePos { sf, pos: offset } = self.try_lookup_byte_offset(pos)?;
+        let mut units = offset.0;
+
+        for mbc in sf.multibyte_chars.iter() {
+            if mbc.pos >= pos {
+                break;
+            }
+            if pos < mbc.pos + BytePos(mbc.bytes as u32) {
+                units -= (pos - mbc.pos).0;
+                break;
+            }
+            units -= mbc.bytes as u32 - utf16_len_of_utf8(mbc.bytes);
+        }
+
+        Some(units)
+    }
+
+    /// Inverse of [SourceMap::bytepos_to_utf16] for `file`.
+    ///
+    /// An offset pointing at the second half of a surrogate pair is snapped to
+    /// the start of the character. Returns `None` if `utf16_off` is past the
+    /// end of `file`.
+    pub fn utf16_to_bytepos(&self, file: &SourceFile, utf16_off: u32) -> Option<BytePos> {
+        let mut pos = file.start_pos;
+        let mut units = 0;
+
+        for mbc in file.multibyte_chars.iter() {
+            // Characters between `pos` and `mbc` are single byte.
+            let narrow = (mbc.pos - pos).0;
+            if utf16_off <= units + narrow {
+                break;
+            }
+            units += narrow;
+
+            let len = utf16_len_of_utf8(mbc.bytes);
+            if utf16_off < units + len {
+                return Some(mbc.pos);
+            }
+            units += len;
+            pos = mbc.pos + BytePos(mbc.bytes as u32);
+        }
+
+        let pos = pos + BytePos(utf16_off - units);
+        if pos > file.end_pos {
+            return None;
+        }
+        Some(pos)
+    }
+
+    /// Converts an absolute BytePos to a CharPos relative to the source_file.
+    #[cfg(test)]
+    fn bytepos_to_file_charpos(&self, bpos: BytePos) -> CharPos {
+        let map = self.lookup_source_file(bpos);
+
+        Self::bytepos_to_file_charpos_with(&map, bpos)
+    }
+
+    fn bytepos_to_file_charpos_with(map: &SourceFile, bpos: BytePos) -> CharPos {
+        if map.is_ascii() {
+            return CharPos((bpos - map.start_pos).to_usize());
+        }
+
+        let total_extra_bytes = Self::calc_extra_bytes(map, &mut 0, bpos);
+        assert!(
+            map.start_pos.to_u32() + total_extra_bytes <= bpos.to_u32(),
+            "map.start_pos = {:?}; total_extra_bytes = {}; bpos = {:?}",
+            map.start_pos,
+            total_extra_bytes,
+            bpos,
+        );
+        CharPos(bpos.to_usize() - map.start_pos.to_usize() - total_extra_bytes as usize)
+    }
+
+    /// Converts an absolute BytePos to a CharPos relative to the source_file.
+    fn calc_extra_bytes(map: &SourceFile, start: &mut usize, bpos: BytePos) -> u32 {
+        // The number of extra bytes due to multibyte chars in the SourceFile
+        let mut total_extra_bytes = 0;
 
         for (i, &mbc) in map.multibyte_chars[*start..].iter().enumerate() {
             debug!("{}-byte char at {:?}", mbc.bytes, mbc.pos);
@@ -910,6 +2689,18 @@ impl SourceMap {
 
     /// Return the index of the source_file (in self.files) which contains pos.
     ///
+    /// Each file owns the range `start_pos..=end_pos`. `end_pos` is the byte
+    /// reserved after the file by [SourceMap::new_source_file], so it resolves
+    /// to the file with an offset equal to the length of the file, which is
+    /// only valid as an exclusive end. Positions before the first file or past
+    /// the `end_pos` of the last file resolve to `None`.
+    ///
+    /// As the end (`hi`) of a span, a position may be equal to `end_pos`. As
+    /// the start (`lo`), it must be less than `end_pos` unless the span is
+    /// empty. Files never touch, so `end_pos` of a file is never the
+    /// `start_pos` of another file and both ends of a span are resolved the
+    /// same way.
+    ///
     /// This method exists only for optimization and it's not part of public
     /// api.
     #[doc(hidden)]
@@ -917,66 +2708,108 @@ impl SourceMap {
         files: &[Lrc<SourceFile>],
         pos: BytePos,
     ) -> Option<Lrc<SourceFile>> {
-        let count = files.len();
-
-        // Binary search for the source_file.
-        let mut a = 0;
-        let mut b = count;
-        while b - a > 1 {
-            let m = (a + b) / 2;
-            if files[m].start_pos > pos {
-                b = m;
-            } else {
-                a = m;
-            }
+        let idx = files.partition_point(|f| f.start_pos <= pos);
+        if idx == 0 {
+            return None;
         }
 
-        if a >= count {
+        let f = &files[idx - 1];
+        if pos > f.end_pos {
             return None;
         }
 
-        Some(files[a].clone())
+        Some(f.clone())
+    }
+
+    /// Same as [SourceMap::lookup_source_file], but returns `None` instead of
+    /// panicking if `pos` does not resolve to a source file.
+    ///
+    /// See [SourceMap::lookup_source_file_in] for the range owned by a file.
+    /// Positions between files return `None` instead of the preceding file.
+    /// Use [SourceMap::classify_pos] to find out why a position does not
+    /// resolve.
+    pub fn try_lookup_source_file(&self, pos: BytePos) -> Option<Lrc<SourceFile>> {
+        Self::lookup_source_file_in(&self.files.borrow().source_files, pos)
+    }
+
+    /// Describes where `pos` is relative to the files.
+    ///
+    /// Files added to a source map are adjacent, so gaps only exist between
+    /// files passed to [SourceMap::from_serialized]. `BytePos(0)` is
+    /// [PosKind::Dummy] unless a file contains it.
+    pub fn classify_pos(&self, pos: BytePos) -> PosKind {
+        Self::classify_pos_in(&self.files.borrow().source_files, pos)
+    }
+
+    fn classify_pos_in(files: &[Lrc<SourceFile>], pos: BytePos) -> PosKind {
+        if pos.is_reserved_for_comments() {
+            return PosKind::Reserved;
+        }
+
+        let idx = files.partition_point(|f| f.start_pos <= pos);
+        let before = idx.checked_sub(1).map(|idx| &files[idx]);
+
+        match (before, files.get(idx)) {
+            (Some(f), _) if pos <= f.end_pos => PosKind::InFile(f.clone()),
+            _ if pos == BytePos(0) => PosKind::Dummy,
+            (Some(before), Some(after)) => PosKind::FileGap {
+                before: before.clone(),
+                after: after.clone(),
+            },
+            (None, Some(_)) => PosKind::BeforeFirst,
+            (_, None) => PosKind::PastEnd,
+        }
     }
 
     /// Return the index of the source_file (in self.files) which contains pos.
     ///
     /// This is not a public api.
     #[doc(hidden)]
+    #[track_caller]
     pub fn lookup_source_file(&self, pos: BytePos) -> Lrc<SourceFile> {
         let files = self.files.borrow();
         let files = &files.source_files;
-        let fm = Self::lookup_source_file_in(&files, pos);
-        match fm {
+        match Self::lookup_source_file_in(&files, pos) {
             Some(fm) => fm,
-            None => {
-                panic!(
-                    "position {} does not resolve to a source location",
-                    pos.to_usize()
-                );
-            }
+            None => unresolved_pos(files, pos, debug_spans),
         }
     }
 
+    /// Returns the total number of lines of all files.
+    ///
+    /// Line tables are computed when a file is added, so this is a sum of
+    /// precomputed values and does not analyze the sources again.
     pub fn count_lines(&self) -> usize {
         self.files().iter().fold(0, |a, f| a + f.count_lines())
     }
 
-    pub fn generate_fn_name_span(&self, span: Span) -> Option<Span> {
-        let prev_span = self.span_extend_to_prev_str(span, "fn", true);
-        self.span_to_snippet(prev_span)
-            .map(|snippet| {
-                let len = snippet
-                    .find(|c: char| !c.is_alphanumeric() && c != '_')
-                    .expect("no label after fn");
-                prev_span.with_hi(BytePos(prev_span.lo().0 + len as u32))
-            })
-            .ok()
+    /// Returns the number of lines of each file along with its name, in the
+    /// order of [SourceMap::files].
+    pub fn line_counts(&self) -> Vec<(FileName, usize)> {
+        self.files()
+            .iter()
+            .map(|f| ((*f.name).clone(), f.count_lines()))
+            .collect()
+    }
+
+    /// Take a span inside the signature of a function and try to generate a
+    /// span for the function name, by extending it to the previous `keyword`
+    /// (like `fn` or `function`).
+    ///
+    /// Returns `None` if there's no identifier after the keyword.
+    pub fn generate_fn_name_span(&self, span: Span, keyword: &str) -> Option<Span> {
+        let prev_span = self.span_extend_to_prev_str(span, keyword, true);
+        let snippet = self.span_to_snippet(prev_span).ok()?;
+        let len = ident_len(&snippet)?;
+
+        Some(prev_span.leading_of(len))
     }
 
     /// Take the span of a type parameter in a function signature and try to
     /// generate a span for the function name (with generics) and a new
     /// snippet for this span with the pointed type parameter as a new local
-    /// type parameter.
+    /// type parameter. `keyword` is the keyword starting the function, like
+    /// `fn` or `function`.
     ///
     /// For instance:
     /// ```rust,ignore (pseudo-Rust)
@@ -989,61 +2822,209 @@ impl SourceMap {
     /// // ^^^^^^^^^^^ Generated span with snippet `my_function<T>`
     /// ```
     ///
+    /// Returns `None` if the signature can't be recognized.
+    ///
     /// Attention: The method used is very fragile since it essentially
     /// duplicates the work of the parser. If you need to use this function
     /// or something similar, please consider updating the source_map
     /// functions and this function to something more robust.
-    pub fn generate_local_type_param_snippet(&self, span: Span) -> Option<(Span, String)> {
-        // Try to extend the span to the previous "fn" keyword to retrieve the function
+    pub fn generate_local_type_param_snippet(
+        &self,
+        span: Span,
+        keyword: &str,
+    ) -> Option<(Span, String)> {
+        // Try to extend the span to the previous keyword to retrieve the function
         // signature
-        let sugg_span = self.span_extend_to_prev_str(span, "fn", false);
-        if sugg_span != span {
-            if let Ok(snippet) = self.span_to_snippet(sugg_span) {
-                // Consume the function name
-                let mut offset = snippet
-                    .find(|c: char| !c.is_alphanumeric() && c != '_')
-                    .expect("no label after fn");
-
-                // Consume the generics part of the function signature
-                let mut bracket_counter = 0;
-                let mut last_char = None;
-                for c in snippet[offset..].chars() {
-                    match c {
-                        '<' => bracket_counter += 1,
-                        '>' => bracket_counter -= 1,
-                        '(' => {
-                            if bracket_counter == 0 {
-                                break;
-                            }
-                        }
-                        _ => {}
+        let sugg_span = self.span_extend_to_prev_str(span, keyword, false);
+        if sugg_span == span {
+            return None;
+        }
+        let snippet = self.span_to_snippet(sugg_span).ok()?;
+
+        // Consume the function name
+        let mut offset = ident_len(&snippet)?;
+
+        // Consume the generics part of the function signature
+        let mut bracket_counter = 0u32;
+        let mut last_char = None;
+        let mut found_paren = false;
+        for c in snippet[offset..].chars() {
+            match c {
+                '<' => bracket_counter += 1,
+                // `->` and `=>` in the generics don't close them.
+                '>' if last_char != Some('-') && last_char != Some('=') => {
+                    bracket_counter = bracket_counter.checked_sub(1)?
+                }
+                '(' if bracket_counter == 0 => {
+                    found_paren = true;
+                    break;
+                }
+                _ => {}
+            }
+            offset += c.len_utf8();
+            last_char = Some(c);
+        }
+        if !found_paren {
+            return None;
+        }
+        // Skip whitespace between the generics and the parameters.
+        let name_with_generics = snippet[..offset].trim_end();
+        let offset = name_with_generics.len();
+
+        // Adjust the suggestion span to encompass the function name with its generics
+        let sugg_span = sugg_span.leading_of(offset);
+
+        // Prepare the new suggested snippet to append the type parameter that triggered
+        // the error in the generics of the function signature
+        let mut new_snippet = match name_with_generics.strip_suffix('>') {
+            Some(generics) => format!("{}, ", generics),
+            None => format!("{}<", name_with_generics),
+        };
+        new_snippet.push_str(
+            &self
+                .span_to_snippet(span)
+                .unwrap_or_else(|_| "T".to_string()),
+        );
+        new_snippet.push('>');
+
+        Some((sugg_span, new_snippet))
+    }
+
+    /// Returns the positions of `mappings` which don't resolve to a file,
+    /// along with their kinds. [SourceMap::build_source_map] skips these
+    /// mappings.
+    ///
+    /// Reserved positions are not included, as they are skipped on purpose.
+    pub fn unresolved_mapping_positions(
+        &self,
+        mappings: &[(BytePos, LineCol)],
+    ) -> Vec<(BytePos, PosKind)> {
+        mappings
+            .iter()
+            .filter_map(|&(pos, _)| match self.classify_pos(pos) {
+                PosKind::InFile(..) | PosKind::Reserved => None,
+                kind => Some((pos, kind)),
+            })
+            .collect()
+    }
+
+    /// Returns the sources referenced by `mappings` which look like generated
+    /// code, along with the heuristic which matched.
+    ///
+    /// The names are the ones used in `sources` of the source map built with
+    /// the same `config` and `options`, whose `ignoreList` contains these
+    /// sources. This returns an empty list if
+    /// [SourceMapOptions::auto_ignore_generated] is `None`.
+    pub fn generated_sources(
+        &self,
+        mappings: &[(BytePos, LineCol)],
+        config: impl SourceMapGenConfig,
+        options: &SourceMapOptions,
+    ) -> Vec<(String, GeneratedHeuristic)> {
+        let heuristics = match &options.auto_ignore_generated {
+            Some(v) => v,
+            None => return vec![],
+        };
+
+        let mut checked: Vec<BytePos> = vec![];
+        let mut generated = vec![];
+        let mut cur_file: Option<Lrc<SourceFile>> = None;
+
+        for &(pos, _) in mappings {
+            if pos.is_reserved_for_comments() {
+                continue;
+            }
+
+            match cur_file {
+                Some(ref f) if f.start_pos <= pos && pos < f.end_pos => continue,
+                _ => {}
+            }
+
+            let f = match self.try_lookup_source_file(pos) {
+                Some(f) => f,
+                None => continue,
+            };
+            if !checked.contains(&f.start_pos) {
+                checked.push(f.start_pos);
+
+                if let Some(reason) = heuristics.check(&f) {
+                    let name = source_name(&config, options, &f.name, f.unmapped_path.as_ref());
+                    if generated.iter().all(|(n, _)| *n != name) {
+                        generated.push((name, reason));
                     }
-                    offset += c.len_utf8();
-                    last_char = Some(c);
                 }
+            }
+            cur_file = Some(f);
+        }
 
-                // Adjust the suggestion span to encompass the function name with its generics
-                let sugg_span = sugg_span.with_hi(BytePos(sugg_span.lo().0 + offset as u32));
+        generated
+    }
 
-                // Prepare the new suggested snippet to append the type parameter that triggered
-                // the error in the generics of the function signature
-                let mut new_snippet = if last_char == Some('>') {
-                    format!("{}, ", &snippet[..(offset - '>'.len_utf8())])
-                } else {
-                    format!("{}<", &snippet[..offset])
-                };
-                new_snippet.push_str(
-                    &self
-                        .span_to_snippet(span)
-                        .unwrap_or_else(|_| "T".to_string()),
-                );
-                new_snippet.push('>');
+    /// Finds string literals whose length in `generated` differs from their
+    /// length in the original source, like a literal whose quotes were
+    /// switched by a minifier.
+    ///
+    /// `mappings` and `generated` are the mappings and the code produced by
+    /// the code generator. Columns of `mappings` are byte offsets into the
+    /// lines of `generated`, as emitted by the code generator. Columns read
+    /// back from a `.map` file count UTF-16 code units instead, and must be
+    /// converted first on lines with non-ASCII text. Lengths are reported in
+    /// bytes.
+    ///
+    /// Only literals followed by another mapping on the same generated line
+    /// are reported, because columns of those mappings are off by the
+    /// difference if they were derived from original spans.
+    pub fn mapping_drifts(
+        &self,
+        mappings: &[(BytePos, LineCol)],
+        generated: &str,
+    ) -> Vec<MappingDrift> {
+        let lines = generated.split('\n').collect::<Vec<_>>();
+        let mut drifts = vec![];
+
+        // The last mapped column of each generated line.
+        let mut last_cols = vec![None; lines.len()];
+        for &(_, lc) in mappings {
+            if let Some(last) = last_cols.get_mut(lc.line as usize) {
+                *last = max(*last, Some(lc.col));
+            }
+        }
+
+        for &(pos, lc) in mappings {
+            let has_next_on_line = match last_cols.get(lc.line as usize) {
+                Some(&Some(last)) => last > lc.col,
+                _ => false,
+            };
+            if !has_next_on_line {
+                continue;
+            }
 
-                return Some((sugg_span, new_snippet));
+            let original_len = match self.try_lookup_byte_offset(pos) {
+                Some(loc) => loc
+                    .sf
+                    .src
+                    .get(loc.pos.to_usize()..)
+                    .and_then(string_literal_len),
+                None => continue,
+            };
+            let generated_len = lines
+                .get(lc.line as usize)
+                .and_then(|line| line.get(lc.col as usize..))
+                .and_then(string_literal_len);
+
+            if let (Some(original_len), Some(generated_len)) = (original_len, generated_len) {
+                if original_len != generated_len {
+                    drifts.push(MappingDrift {
+                        pos,
+                        generated: lc,
+                        original_len: original_len as u32,
+                        generated_len: generated_len as u32,
+                    });
+                }
             }
         }
 
-        None
+        drifts
     }
 
     ///
@@ -1063,92 +3044,456 @@ impl SourceMap {
     }
 
     #[cfg(feature = "sourcemap")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip(self, mappings, orig, config),
+            fields(mappings = mappings.len())
+        )
+    )]
     pub fn build_source_map_with_config(
         &self,
         mappings: &mut Vec<(BytePos, LineCol)>,
         orig: Option<&sourcemap::SourceMap>,
         config: impl SourceMapGenConfig,
     ) -> sourcemap::SourceMap {
-        let mut builder = SourceMapBuilder::new(None);
+        self.build_source_map_from_iter(mappings.iter().copied(), orig, config)
+    }
 
-        let mut src_id = 0u32;
+    /// Same as [SourceMap::build_source_map_with_config], but takes the
+    /// mappings from an iterator, so a code generator can stream them
+    /// instead of buffering all of them.
+    ///
+    /// The mappings are consumed in order. Like the other methods, consecutive
+    /// mappings in the same file are the fastest.
+    #[cfg(feature = "sourcemap")]
+    pub fn build_source_map_from_iter(
+        &self,
+        mappings: impl IntoIterator<Item = (BytePos, LineCol)>,
+        orig: Option<&sourcemap::SourceMap>,
+        config: impl SourceMapGenConfig,
+    ) -> sourcemap::SourceMap {
+        self.build_source_map_with_options(mappings, orig, config, &Default::default())
+    }
 
-        if let Some(orig) = orig {
-            for (idx, src) in orig.sources().enumerate() {
-                builder.set_source(idx as _, src);
-                src_id = idx as u32 + 1;
-            }
-            for (idx, contents) in orig.source_contents().enumerate() {
-                builder.set_source_contents(idx as _, contents);
+    /// Same as [SourceMap::build_source_map_from_iter], but takes the options
+    /// which don't depend on a file instead of using the default ones.
+    ///
+    /// `config` is only used for the names of sources and
+    /// [SourceMapGenConfig::skip_pos].
+    #[cfg(feature = "sourcemap")]
+    pub fn build_source_map_with_options(
+        &self,
+        mappings: impl IntoIterator<Item = (BytePos, LineCol)>,
+        orig: Option<&sourcemap::SourceMap>,
+        config: impl SourceMapGenConfig,
+        options: &SourceMapOptions,
+    ) -> sourcemap::SourceMap {
+        self.build_extended_source_map(mappings, orig, config, options)
+            .map
+    }
+
+    /// Same as [SourceMap::build_source_map_with_options], but also returns
+    /// the fields which `sourcemap::SourceMap` can't store, like `debugId`.
+    #[cfg(feature = "sourcemap")]
+    pub fn build_extended_source_map(
+        &self,
+        mappings: impl IntoIterator<Item = (BytePos, LineCol)>,
+        orig: Option<&sourcemap::SourceMap>,
+        config: impl SourceMapGenConfig,
+        options: &SourceMapOptions,
+    ) -> ExtendedSourceMap {
+        let mut builder = IncrementalSourceMapBuilder::new(self, orig, config, options.clone());
+        for (pos, lc) in mappings {
+            builder.add_mapping(pos, lc);
+        }
+        builder.finish_extended()
+    }
+}
+
+/// If this environment variable is set, the panicking lookups of
+/// [SourceMap] and [FrozenSourceMap] log an error and use the nearest file
+/// instead of panicking when a position does not resolve to a file.
+pub const DEBUG_SPANS_ENV: &str = "SWC_DEBUG_SPANS";
+
+/// Reads [DEBUG_SPANS_ENV]. This locks and copies the environment, so it's
+/// only called after a lookup has failed.
+fn debug_spans() -> bool {
+    env::var_os(DEBUG_SPANS_ENV).is_some()
+}
+
+/// Handles `pos` which does not resolve to any of `files` in a panicking
+/// lookup.
+///
+/// Panics with the caller of the lookup and the files around `pos`. If
+/// `degrade` returns true, the error is logged and the nearest file is returned
+/// instead, unless there is no file.
+#[track_caller]
+fn unresolved_pos(
+    files: &[Lrc<SourceFile>],
+    pos: BytePos,
+    degrade: impl FnOnce() -> bool,
+) -> Lrc<SourceFile> {
+    let (nearest, place) = match SourceMap::classify_pos_in(files, pos) {
+        PosKind::InFile(_) => unreachable!("{:?} is in a file", pos),
+        PosKind::FileGap { before, after } => {
+            let place = format!(
+                "between {} ({}) and {} ({})",
+                before.name,
+                file_range_str(&before),
+                after.name,
+                file_range_str(&after)
+            );
+            (Some(before), place)
+        }
+        PosKind::BeforeFirst => {
+            let first = files[0].clone();
+            let place = format!("before {} ({})", first.name, file_range_str(&first));
+            (Some(first), place)
+        }
+        PosKind::PastEnd => match files.last() {
+            Some(last) => {
+                let place = format!("after {} ({})", last.name, file_range_str(last));
+                (Some(last.clone()), place)
             }
+            None => (None, "there are no files".into()),
+        },
+        PosKind::Dummy => (files.first().cloned(), "it is the dummy position".into()),
+        PosKind::Reserved => (files.last().cloned(), "it is reserved".into()),
+    };
+    let msg = format!(
+        "position {} does not resolve to a source location ({}), looked up at {}",
+        pos.to_usize(),
+        place,
+        std::panic::Location::caller()
+    );
+
+    match nearest {
+        Some(f) if degrade() => {
+            report_degraded_lookup(&msg);
+            f
         }
+        _ => panic!("{}", msg),
+    }
+}
 
-        // // This method is optimized based on the fact that mapping is sorted.
-        // mappings.sort_by_key(|v| v.0);
+/// Checks that `pos` passed to [SourceMap::lookup_char_pos_with] is in `f`.
+///
+/// Like [unresolved_pos], `pos` is clamped to `f` instead of panicking if
+/// `degrade` returns true.
+#[track_caller]
+fn check_pos_in_file(f: &SourceFile, pos: BytePos, degrade: impl FnOnce() -> bool) -> BytePos {
+    if f.start_pos <= pos && pos <= f.end_pos {
+        return pos;
+    }
 
-        let mut cur_file: Option<Lrc<SourceFile>> = None;
+    let msg = format!(
+        "position {} is not in {} ({}), looked up at {}",
+        pos.to_usize(),
+        f.name,
+        file_range_str(f),
+        std::panic::Location::caller()
+    );
+    if !degrade() {
+        panic!("{}", msg)
+    }
+    report_degraded_lookup(&msg);
+    pos.clamp(f.start_pos, f.end_pos)
+}
+
+fn file_range_str(f: &SourceFile) -> String {
+    format!("{}..={}", f.start_pos.to_usize(), f.end_pos.to_usize())
+}
 
-        let mut ch_start = 0;
-        let mut line_ch_start = 0;
+fn report_degraded_lookup(msg: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::error!("{}", msg);
+    #[cfg(not(feature = "tracing"))]
+    log::error!("{}", msg);
+}
 
-        for (pos, lc) in mappings.iter() {
-            let pos = *pos;
-            let lc = *lc;
+/// Converts increasing positions in a file to char positions, scanning the
+/// multibyte characters of the file only once.
+#[derive(Default)]
+struct ExtraBytes {
+    /// The index of the first multibyte character which is not counted yet.
+    next: usize,
+    total: usize,
+}
 
-            // TODO: Use correct algorithm
-            if pos >= BytePos(4294967295) {
-                continue;
+impl ExtraBytes {
+    fn char_pos(&mut self, f: &SourceFile, pos: BytePos) -> CharPos {
+        while let Some(mbc) = f.multibyte_chars.get(self.next) {
+            if mbc.pos >= pos {
+                break;
             }
+            self.total += mbc.bytes as usize - 1;
+            self.next += 1;
+        }
+        CharPos((pos - f.start_pos).to_usize() - self.total)
+    }
+}
 
-            let f;
-            let f = match cur_file {
-                Some(ref f) if f.start_pos <= pos && pos < f.end_pos => f,
-                _ => {
-                    f = self.lookup_source_file(pos);
-                    src_id = builder.add_source(&config.file_name_to_source(&f.name));
-                    builder.set_source_contents(src_id, Some(&f.src));
-                    cur_file = Some(f.clone());
-                    ch_start = 0;
-                    line_ch_start = 0;
-                    &f
-                }
-            };
+/// Creates the error for `sp`, which starts in `begin` and ends in `end`.
+fn distinct_sources(begin: &SourceFile, end: &SourceFile, sp: Span) -> DistinctSources {
+    DistinctSources {
+        begin: ((*begin.name).clone(), begin.start_pos),
+        end: ((*end.name).clone(), end.start_pos),
+        begin_loc: Some(line_and_col(begin, sp.lo())),
+        end_loc: Some(line_and_col(end, sp.hi())),
+    }
+}
 
-            let a = match f.lookup_line(pos) {
-                Some(line) => line as u32,
-                None => continue,
-            };
+/// Returns the 1-based line and the column of `pos`, which is in `f`.
+fn line_and_col(f: &SourceFile, pos: BytePos) -> (usize, CharPos) {
+    match f.lookup_line(pos) {
+        Some(line) => {
+            let chpos = SourceMap::bytepos_to_file_charpos_with(f, pos);
+            let linechpos = SourceMap::bytepos_to_file_charpos_with(f, f.lines[line]);
+            (line + 1, chpos - linechpos)
+        }
+        // Only an empty file has no lines.
+        None => (1, CharPos(0)),
+    }
+}
 
-            let mut line = a + 1; // Line numbers start at 1
-            let linebpos = f.lines[a as usize];
-            debug_assert!(
-                pos >= linebpos,
-                "{}: bpos = {:?}; linebpos = {:?};",
-                f.name,
-                pos,
-                linebpos,
-            );
-            let chpos = pos.to_u32() - self.calc_extra_bytes(&f, &mut ch_start, pos);
-            let linechpos =
-                linebpos.to_u32() - self.calc_extra_bytes(&f, &mut line_ch_start, linebpos);
-
-            let mut col = max(chpos, linechpos) - min(chpos, linechpos);
-
-            if let Some(orig) = &orig {
-                if let Some(token) = orig.lookup_token(line, col) {
-                    line = token.get_src_line() + 1;
-                    col = token.get_src_col();
-                    if let Some(src) = token.get_source() {
-                        src_id = builder.add_source(src);
-                    }
-                }
+/// Returns the range of `sp` in the source of `begin`, where `begin` and `end`
+/// are the files containing the start and the end of `sp`.
+fn snippet_range(
+    begin: &SourceFile,
+    end: &SourceFile,
+    sp: Span,
+) -> Result<(usize, usize), SpanSnippetError> {
+    if begin.start_pos != end.start_pos {
+        return Err(SpanSnippetError::DistinctSources(distinct_sources(
+            begin, end, sp,
+        )));
+    }
+
+    let start_index = (sp.lo() - begin.start_pos).to_usize();
+    let end_index = (sp.hi() - begin.start_pos).to_usize();
+    let source_len = (begin.end_pos - begin.start_pos).to_usize();
+
+    if start_index > end_index || end_index > source_len {
+        return Err(SpanSnippetError::MalformedForSourcemap(
+            MalformedSourceMapPositions {
+                name: (*begin.name).clone(),
+                source_len,
+                begin_pos: sp.lo() - begin.start_pos,
+                end_pos: sp.hi() - begin.start_pos,
+            },
+        ));
+    }
+
+    Ok((start_index, end_index))
+}
+
+/// Returns an error if `start_index` or `end_index` is in the middle of a
+/// character of `src`, which is the source of `f`.
+fn check_char_boundaries(
+    f: &SourceFile,
+    src: &str,
+    start_index: usize,
+    end_index: usize,
+) -> Result<(), SpanSnippetError> {
+    if src.is_char_boundary(start_index) && src.is_char_boundary(end_index) {
+        return Ok(());
+    }
+
+    Err(SpanSnippetError::MalformedForSourcemap(
+        MalformedSourceMapPositions {
+            name: (*f.name).clone(),
+            source_len: src.len(),
+            begin_pos: BytePos::from_usize(start_index),
+            end_pos: BytePos::from_usize(end_index),
+        },
+    ))
+}
+
+/// Converts byte positions in a file into character positions, reusing the
+/// work done for the previous position.
+///
+/// Converting positions in increasing order takes time proportional to the
+/// number of multibyte characters in the file in total, instead of for each
+/// position. Moving backward is supported, but it walks back over the multibyte
+/// characters in between.
+///
+/// Positions must be in the file and at character boundaries.
+#[derive(Debug, Clone)]
+pub struct SourceFileCharCursor<'a> {
+    file: &'a SourceFile,
+    /// The number of multibyte characters before the current position.
+    idx: usize,
+    /// The number of bytes that characters before `idx` take in addition to
+    /// one byte each.
+    extra_bytes: u32,
+    /// The number of bytes that characters before `idx` take in addition to
+    /// their UTF-16 code units.
+    extra_utf16: u32,
+    /// The index and UTF-16 offset of the last line used by
+    /// [SourceFileCharCursor::utf16_col].
+    line: Option<(usize, u32)>,
+}
+
+impl<'a> SourceFileCharCursor<'a> {
+    pub fn new(file: &'a SourceFile) -> Self {
+        SourceFileCharCursor {
+            file,
+            idx: 0,
+            extra_bytes: 0,
+            extra_utf16: 0,
+            line: None,
+        }
+    }
+
+    /// Returns the character offset of `pos` from the start of the file.
+    pub fn char_pos(&mut self, pos: BytePos) -> CharPos {
+        self.seek(pos);
+        CharPos((pos - self.file.start_pos).to_usize() - self.extra_bytes as usize)
+    }
+
+    /// Returns the column of `pos` in UTF-16 code units, which is how source
+    /// maps and LSP count columns.
+    pub fn utf16_col(&mut self, pos: BytePos) -> u32 {
+        let line = match self.file.lookup_line(pos) {
+            Some(line) => line,
+            // Empty file
+            None => {
+                self.seek(pos);
+                return 0;
+            }
+        };
+        let line_start = match self.line {
+            Some((idx, offset)) if idx == line => offset,
+            _ => {
+                let offset = self.utf16_offset(self.file.lines[line]);
+                self.line = Some((line, offset));
+                offset
             }
+        };
+
+        self.utf16_offset(pos) - line_start
+    }
 
-            builder.add_raw(lc.line, lc.col, line - 1, col, Some(src_id), None);
+    fn utf16_offset(&mut self, pos: BytePos) -> u32 {
+        self.seek(pos);
+        (pos - self.file.start_pos).0 - self.extra_utf16
+    }
+
+    fn seek(&mut self, pos: BytePos) {
+        assert!(
+            self.file.start_pos <= pos && pos <= self.file.end_pos,
+            "{:?} is not in {}",
+            pos,
+            self.file.name
+        );
+
+        let chars = &self.file.multibyte_chars;
+        while let Some(mbc) = chars.get(self.idx).filter(|mbc| mbc.pos < pos) {
+            debug_assert!(pos >= mbc.pos + BytePos(mbc.bytes as u32));
+            self.extra_bytes += mbc.bytes as u32 - 1;
+            self.extra_utf16 += mbc.bytes as u32 - utf16_len_of_utf8(mbc.bytes);
+            self.idx += 1;
+        }
+        while self.idx > 0 && chars[self.idx - 1].pos >= pos {
+            self.idx -= 1;
+            let mbc = chars[self.idx];
+            self.extra_bytes -= mbc.bytes as u32 - 1;
+            self.extra_utf16 -= mbc.bytes as u32 - utf16_len_of_utf8(mbc.bytes);
         }
+    }
+}
+
+/// Returns the ranges of lines of `new` which differ from `old`, comparing
+/// [SourceFile::line_hashes].
+///
+/// Lines which are the same at the start and at the end of both files are
+/// unchanged, and everything in between is reported as one changed range. If
+/// lines are only removed, the range is empty and starts at the line after
+/// the removed ones. The result is empty if the files have the same lines.
+pub fn changed_line_ranges(old: &SourceFile, new: &SourceFile) -> Vec<Range<usize>> {
+    let (old, new) = (old.line_hashes(), new.line_hashes());
+
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    if prefix == old.len() && prefix == new.len() {
+        return vec![];
+    }
+
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    vec![prefix..new.len() - suffix]
+}
+
+/// Returns the number of UTF-16 code units of a character encoded in `bytes`
+/// UTF-8 bytes.
+fn utf16_len_of_utf8(bytes: u8) -> u32 {
+    if bytes == 4 {
+        2
+    } else {
+        1
+    }
+}
+
+/// Returns the number of columns `c` takes, in the same way as
+/// [Loc::col_display].
+fn char_display_width(c: char) -> usize {
+    match c {
+        '\t' => 4,
+        c if (c as u32) < 0x7f => 1,
+        c => UnicodeWidthChar::width(c).unwrap_or(0),
+    }
+}
+
+/// Returns [SourceFile::src] if it contains the whole source code.
+fn resident_source_of(f: &SourceFile) -> Result<Lrc<String>, SpanSnippetError> {
+    if f.has_src() {
+        Ok(f.src.clone())
+    } else {
+        Err(SpanSnippetError::SourceNotAvailable {
+            filename: (*f.name).clone(),
+        })
+    }
+}
 
-        builder.into_sourcemap()
+/// Computes the display width of the source between `lo` and `hi`, which
+/// should be in the same line.
+///
+/// Tabs are expanded to the next multiple of `tab_width`, relative to `lo`.
+/// `src` is the source code of `f`.
+fn display_width(f: &SourceFile, src: &str, lo: BytePos, hi: BytePos, tab_width: usize) -> usize {
+    let tab_width = max(tab_width, 1);
+    let start_idx = f
+        .non_narrow_chars
+        .binary_search_by_key(&lo, |x| x.pos())
+        .unwrap_or_else(|x| x);
+    let mut non_narrow_chars = f.non_narrow_chars[start_idx..].iter().peekable();
+
+    let text = &src[(lo - f.start_pos).to_usize()..(hi - f.start_pos).to_usize()];
+    let mut width = 0;
+    for (idx, _) in text.char_indices() {
+        let pos = lo + BytePos(idx as u32);
+
+        match non_narrow_chars.peek() {
+            Some(&&c) if c.pos() == pos => {
+                non_narrow_chars.next();
+                width = match c {
+                    NonNarrowChar::Tab(..) => (width / tab_width + 1) * tab_width,
+                    _ => width + c.width(),
+                };
+            }
+            _ => width += 1,
+        }
     }
+
+    width
 }
 
 impl SourceMapper for SourceMap {
@@ -1173,9 +3518,117 @@ impl SourceMapper for SourceMap {
     fn doctest_offset_line(&self, line: usize) -> usize {
         self.doctest_offset_line(line)
     }
+    fn doctest_offset_col(&self, line: usize, col: usize) -> usize {
+        self.doctest_offset_col(line, col)
+    }
+    fn source_line(&self, file: &SourceFile, line_index: usize) -> Option<String> {
+        let src = self.source_of(file).ok()?;
+        let begin = (*file.lines.get(line_index)? - file.start_pos).to_usize();
+        let line = &src[begin..];
+        Some(line[..line.find('\n').unwrap_or(line.len())].to_string())
+    }
 }
 
-#[derive(Clone)]
+/// An immutable snapshot of a [SourceMap], created by [SourceMap::freeze].
+///
+/// Cloning is cheap as the files are shared.
+///
+/// The snapshot is `Send` and `Sync` only with the `concurrent` feature.
+/// Without it, the files are shared with [std::rc::Rc] like in [SourceMap],
+/// so a snapshot can't be sent to other threads.
+#[derive(Clone)]
+pub struct FrozenSourceMap {
+    inner: Lrc<FrozenSourceMapInner>,
+}
+
+#[derive(Clone)]
+struct FrozenSourceMapInner {
+    files: Vec<Lrc<SourceFile>>,
+    /// Index of the first file with the name.
+    names: FxHashMap<Lrc<FileName>, usize>,
+    start_pos: usize,
+    path_mapping: FilePathMapping,
+    loaded_files: FxHashMap<PathBuf, Lrc<SourceFile>>,
+    anon_files: usize,
+    interner: Lrc<dyn FileNameInterner>,
+    base_dir: Option<PathBuf>,
+}
+
+impl FrozenSourceMap {
+    /// Returns the files sorted by their start positions.
+    pub fn files(&self) -> &[Lrc<SourceFile>] {
+        &self.inner.files
+    }
+
+    pub fn path_mapping(&self) -> &FilePathMapping {
+        &self.inner.path_mapping
+    }
+
+    /// See [SourceMap::get_source_file].
+    pub fn get_source_file(&self, filename: &FileName) -> Option<Lrc<SourceFile>> {
+        let idx = *self.inner.names.get(filename)?;
+        Some(self.inner.files[idx].clone())
+    }
+
+    /// See [SourceMap::try_lookup_source_file].
+    pub fn try_lookup_source_file(&self, pos: BytePos) -> Option<Lrc<SourceFile>> {
+        SourceMap::lookup_source_file_in(&self.inner.files, pos)
+    }
+
+    /// See [SourceMap::lookup_source_file].
+    #[track_caller]
+    pub fn lookup_source_file(&self, pos: BytePos) -> Lrc<SourceFile> {
+        match self.try_lookup_source_file(pos) {
+            Some(fm) => fm,
+            None => unresolved_pos(&self.inner.files, pos, debug_spans),
+        }
+    }
+
+    /// See [SourceMap::try_lookup_byte_offset].
+    pub fn try_lookup_byte_offset(&self, bpos: BytePos) -> Option<SourceFileAndBytePos> {
+        let sf = self.try_lookup_source_file(bpos)?;
+        let offset = bpos - sf.start_pos;
+        Some(SourceFileAndBytePos { sf, pos: offset })
+    }
+
+    /// See [SourceMap::lookup_byte_offset].
+    #[track_caller]
+    pub fn lookup_byte_offset(&self, bpos: BytePos) -> SourceFileAndBytePos {
+        let sf = self.lookup_source_file(bpos);
+        let offset = bpos.clamp(sf.start_pos, sf.end_pos) - sf.start_pos;
+        SourceFileAndBytePos { sf, pos: offset }
+    }
+
+    /// See [SourceMap::lookup_char_pos].
+    #[track_caller]
+    pub fn lookup_char_pos(&self, pos: BytePos) -> Loc {
+        let fm = self.lookup_source_file(pos);
+        let pos = pos.clamp(fm.start_pos, fm.end_pos);
+        SourceMap::lookup_char_pos_in(fm, pos)
+    }
+
+    /// See [SourceMap::lookup_char_pos_many].
+    pub fn lookup_char_pos_many(&self, positions: &[BytePos]) -> Vec<Option<Loc>> {
+        SourceMap::lookup_char_pos_many_in(&self.inner.files, positions)
+    }
+
+    /// See [SourceMap::span_to_lines].
+    pub fn span_to_lines(&self, sp: Span) -> FileLinesResult {
+        SourceMap::span_to_lines_with(sp, |pos| self.lookup_char_pos(pos))
+    }
+
+    /// See [SourceMap::span_to_snippet].
+    pub fn span_to_snippet(&self, sp: Span) -> Result<String, SpanSnippetError> {
+        SourceMap::span_to_source_with(
+            sp,
+            |pos| self.try_lookup_byte_offset(pos),
+            resident_source_of,
+            |src, start_index, end_index| src[start_index..end_index].to_string(),
+        )
+    }
+}
+
+#[derive(Clone)]
 pub struct FilePathMapping {
     mapping: Vec<(PathBuf, PathBuf)>,
 }
@@ -1189,6 +3642,29 @@ impl FilePathMapping {
         FilePathMapping { mapping }
     }
 
+    /// Returns the mapping entries, in the order they were given.
+    ///
+    /// Note that later entries take precedence over earlier ones.
+    pub fn mappings(&self) -> &[(PathBuf, PathBuf)] {
+        &self.mapping
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mapping.is_empty()
+    }
+
+    /// Same as [FilePathMapping::map_prefix], but for a path stored as a
+    /// string.
+    pub fn map_prefix_str(&self, path: &str) -> (String, bool) {
+        for &(ref from, ref to) in self.mapping.iter().rev() {
+            if let Ok(rest) = Path::new(path).strip_prefix(from) {
+                return (to.join(rest).to_string_lossy().into_owned(), true);
+            }
+        }
+
+        (path.to_string(), false)
+    }
+
     /// Applies any path prefix substitution as defined by the mapping.
     /// The return value is the remapped path and a boolean indicating whether
     /// the path was affected by the mapping.
@@ -1206,6 +3682,9 @@ impl FilePathMapping {
     }
 }
 
+/// Callbacks which name the sources of a source map and filter its mappings.
+///
+/// Options which don't depend on a file are fields of [SourceMapOptions].
 pub trait SourceMapGenConfig {
     /// # Returns
     ///
@@ -1213,6 +3692,463 @@ pub trait SourceMapGenConfig {
     ///
     /// This should **not** return content of the file.
     fn file_name_to_source(&self, f: &FileName) -> String;
+
+    /// Same as [SourceMapGenConfig::file_name_to_source], but also receives
+    /// [SourceFile::unmapped_path], the name before [FilePathMapping] of the
+    /// source map was applied.
+    ///
+    /// This allows remapping paths when the source map is built, e.g. with
+    /// [FilePathMapping::map_prefix].
+    fn file_name_to_source_with_unmapped(
+        &self,
+        f: &FileName,
+        _unmapped_path: Option<&FileName>,
+    ) -> String {
+        self.file_name_to_source(f)
+    }
+
+    /// If this returns true, mappings at `pos` are not added to the source
+    /// map.
+    ///
+    /// This can be used to exclude synthetic code, e.g. a prelude spliced into
+    /// a bundle, without giving it a special [FileName]. Sources without any
+    /// remaining mapping are not added either.
+    fn skip_pos(&self, _pos: BytePos) -> bool {
+        false
+    }
+}
+
+/// Options of [SourceMap::build_source_map_with_options] which don't depend on
+/// a file.
+///
+/// The other methods building a source map use the default options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMapOptions {
+    /// If `Some`, it's used as `sourceRoot` of the source map and sources
+    /// under the directory are emitted as relative paths.
+    ///
+    /// Sources outside of the directory are emitted using
+    /// [SourceMapGenConfig::file_name_to_source].
+    pub source_root: Option<PathBuf>,
+    /// If `Some`, it's prepended to the names of real and custom files in
+    /// `sources`, including the sources of the original source map.
+    ///
+    /// This is useful for building the same files for multiple targets, e.g.
+    /// `modern://` results in `modern:///src/app.ts`. Names which are already
+    /// urls, including `data:` urls, are not prefixed.
+    pub source_name_prefix: Option<String>,
+    /// If true, the source code of files and `sourcesContent` of the original
+    /// source map are added as `sourcesContent`.
+    ///
+    /// Defaults to `true`.
+    pub inline_sources_content: bool,
+    /// If `Some`, it's emitted as `debugId` of the source map, which pairs
+    /// the map with the generated code containing [debug_id_comment] of the
+    /// same id.
+    ///
+    /// `sourcemap::SourceMap` can't store it, so it's only emitted by
+    /// [ExtendedSourceMap::to_writer].
+    pub debug_id: Option<String>,
+    /// If `Some`, sources which look like generated code are added to
+    /// `ignoreList`.
+    ///
+    /// Like `debugId`, it's only emitted by [ExtendedSourceMap::to_writer].
+    pub auto_ignore_generated: Option<GeneratedHeuristics>,
+}
+
+impl Default for SourceMapOptions {
+    fn default() -> Self {
+        SourceMapOptions {
+            source_root: None,
+            source_name_prefix: None,
+            inline_sources_content: true,
+            debug_id: None,
+            auto_ignore_generated: None,
+        }
+    }
+}
+
+/// Returns the name of `f` used in `sources` of the source map.
+fn source_name(
+    config: &impl SourceMapGenConfig,
+    options: &SourceMapOptions,
+    f: &FileName,
+    unmapped_path: Option<&FileName>,
+) -> String {
+    let name = match (&options.source_root, f) {
+        (Some(root), FileName::Real(path)) if path.starts_with(root) => path
+            .strip_prefix(root)
+            .unwrap()
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+        _ => config.file_name_to_source_with_unmapped(f, unmapped_path),
+    };
+
+    match f {
+        FileName::Real(..) | FileName::Custom(..) => prefix_source_name(options, name),
+        _ => name,
+    }
+}
+
+/// Applies [SourceMapOptions::source_name_prefix] to `name`.
+fn prefix_source_name(options: &SourceMapOptions, name: String) -> String {
+    match &options.source_name_prefix {
+        Some(prefix) if !name.starts_with("data:") && !name.contains("://") => {
+            format!("{}{}", prefix, name)
+        }
+        _ => name,
+    }
+}
+
+/// Builds a source map from mappings added one at a time.
+///
+/// This allows a code generator to add mappings while writing the code, and
+/// to build the source maps of multiple outputs at once. The result is the
+/// same as [SourceMap::build_source_map_with_options] with the same mappings.
+#[cfg(feature = "sourcemap")]
+pub struct IncrementalSourceMapBuilder<'a, C: SourceMapGenConfig> {
+    cm: &'a SourceMap,
+    orig: Option<&'a sourcemap::SourceMap>,
+    config: C,
+    options: SourceMapOptions,
+    builder: SourceMapBuilder,
+    src_id: u32,
+    cur_file: Option<Lrc<SourceFile>>,
+    /// A file registered multiple times is added only once.
+    src_ids: FxHashMap<String, u32>,
+    ignore_list: Vec<u32>,
+    ch_start: usize,
+    line_ch_start: usize,
+}
+
+#[cfg(feature = "sourcemap")]
+impl<'a, C: SourceMapGenConfig> IncrementalSourceMapBuilder<'a, C> {
+    /// `orig` is the source map of the input, if any. Mapped positions are
+    /// remapped through it as they are added.
+    pub fn new(
+        cm: &'a SourceMap,
+        orig: Option<&'a sourcemap::SourceMap>,
+        config: C,
+        options: SourceMapOptions,
+    ) -> Self {
+        let mut builder = SourceMapBuilder::new(None);
+
+        if let Some(root) = &options.source_root {
+            builder.set_source_root(Some(root.to_string_lossy().into_owned()));
+        }
+
+        let mut src_id = 0u32;
+
+        if let Some(orig) = orig {
+            // The builder is empty, so `set_source` can't be used here.
+            for (src, contents) in orig.sources().zip(orig.source_contents()) {
+                // A repeated name gets the id of its first occurrence, so the
+                // id may differ from the index in `orig`.
+                let id = builder.add_source(&prefix_source_name(&options, src.to_string()));
+                src_id = id + 1;
+                if options.inline_sources_content && contents.is_some() {
+                    builder.set_source_contents(id, contents);
+                }
+            }
+        }
+
+        IncrementalSourceMapBuilder {
+            cm,
+            orig,
+            config,
+            options,
+            builder,
+            src_id,
+            cur_file: None,
+            src_ids: Default::default(),
+            ignore_list: vec![],
+            ch_start: 0,
+            line_ch_start: 0,
+        }
+    }
+
+    /// Maps `generated` to `pos`.
+    ///
+    /// Consecutive mappings in the same file are the fastest. Positions
+    /// skipped by [SourceMapGenConfig::skip_pos] or outside of files are
+    /// ignored.
+    pub fn add_mapping(&mut self, pos: BytePos, generated: LineCol) {
+        if pos.is_reserved_for_comments() {
+            return;
+        }
+        if self.config.skip_pos(pos) {
+            return;
+        }
+
+        let f = match self.cur_file {
+            Some(ref f) if f.start_pos <= pos && pos < f.end_pos => f.clone(),
+            _ => {
+                // Positions outside of files are reported by
+                // `unresolved_mapping_positions`.
+                let f = match self.cm.try_lookup_source_file(pos) {
+                    Some(f) => f,
+                    None => return,
+                };
+                let name = source_name(
+                    &self.config,
+                    &self.options,
+                    &f.name,
+                    f.unmapped_path.as_ref(),
+                );
+                self.src_id = match self.src_ids.get(&name) {
+                    Some(&id) => id,
+                    None => {
+                        let id = self.builder.add_source(&name);
+                        if self.options.inline_sources_content && f.has_src() {
+                            self.builder.set_source_contents(id, Some(&f.src));
+                        }
+                        if let Some(heuristics) = &self.options.auto_ignore_generated {
+                            if heuristics.check(&f).is_some() {
+                                self.ignore_list.push(id);
+                            }
+                        }
+                        self.src_ids.insert(name, id);
+                        id
+                    }
+                };
+                self.cur_file = Some(f.clone());
+                self.ch_start = 0;
+                self.line_ch_start = 0;
+                f
+            }
+        };
+
+        let a = match f.lookup_line(pos) {
+            Some(line) => line as u32,
+            None => return,
+        };
+
+        let mut line = a + 1; // Line numbers start at 1
+        let linebpos = f.lines[a as usize];
+        debug_assert!(
+            pos >= linebpos,
+            "{}: bpos = {:?}; linebpos = {:?};",
+            f.name,
+            pos,
+            linebpos,
+        );
+        let chpos = pos.to_u32() - SourceMap::calc_extra_bytes(&f, &mut self.ch_start, pos);
+        let linechpos =
+            linebpos.to_u32() - SourceMap::calc_extra_bytes(&f, &mut self.line_ch_start, linebpos);
+
+        let mut col = max(chpos, linechpos) - min(chpos, linechpos);
+
+        if let Some(orig) = self.orig {
+            if let Some(token) = orig.lookup_token(line, col) {
+                line = token.get_src_line() + 1;
+                col = token.get_src_col();
+                if let Some(src) = token.get_source() {
+                    let name = prefix_source_name(&self.options, src.to_string());
+                    self.src_id = self.builder.add_source(&name);
+                }
+            }
+        }
+
+        let src_id = self.src_id;
+        self.builder.add_raw(
+            generated.line,
+            generated.col,
+            line - 1,
+            col,
+            Some(src_id),
+            None,
+        );
+    }
+
+    /// Builds the source map from the mappings added so far.
+    pub fn finish(self) -> sourcemap::SourceMap {
+        self.finish_extended().map
+    }
+
+    /// Same as [IncrementalSourceMapBuilder::finish], but also returns the
+    /// fields which `sourcemap::SourceMap` can't store.
+    pub fn finish_extended(self) -> ExtendedSourceMap {
+        ExtendedSourceMap {
+            map: self.builder.into_sourcemap(),
+            debug_id: self.options.debug_id,
+            ignore_list: self.ignore_list,
+        }
+    }
+}
+
+/// A source map along with the fields which `sourcemap::SourceMap` can't
+/// store.
+#[cfg(feature = "sourcemap")]
+pub struct ExtendedSourceMap {
+    pub map: sourcemap::SourceMap,
+    /// See [SourceMapOptions::debug_id].
+    pub debug_id: Option<String>,
+    /// Indices into `sources` of the sources which look like generated code.
+    /// See [SourceMapOptions::auto_ignore_generated].
+    pub ignore_list: Vec<u32>,
+}
+
+#[cfg(feature = "sourcemap")]
+impl ExtendedSourceMap {
+    /// Writes the source map as json, including the extra fields.
+    pub fn to_writer<W: io::Write>(&self, w: W) -> Result<(), sourcemap::Error> {
+        if self.debug_id.is_none() && self.ignore_list.is_empty() {
+            return self.map.to_writer(w);
+        }
+
+        let mut buf = vec![];
+        self.map.to_writer(&mut buf)?;
+        let mut json = match serde_json::from_slice(&buf)? {
+            serde_json::Value::Object(json) => json,
+            _ => unreachable!("source maps are written as json objects"),
+        };
+        if let Some(id) = &self.debug_id {
+            json.insert("debugId".into(), id.clone().into());
+        }
+        if !self.ignore_list.is_empty() {
+            json.insert("ignoreList".into(), self.ignore_list.clone().into());
+        }
+
+        serde_json::to_writer(w, &json)?;
+        Ok(())
+    }
+}
+
+/// Returns the comment which should be appended to the generated code to pair
+/// it with the source map with `debugId` of `id`.
+///
+/// Use [CommentKind::Line] for javascript and [CommentKind::Block] for css.
+pub fn debug_id_comment(id: &str, kind: CommentKind) -> String {
+    match kind {
+        CommentKind::Line => format!("//# debugId={}", id),
+        CommentKind::Block => format!("/*# debugId={} */", id),
+    }
+}
+
+/// Thresholds used to detect sources which are probably generated code.
+///
+/// The default values only match minified bundles and files with an explicit
+/// marker, so hand-written files are not flagged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedHeuristics {
+    /// Files smaller than this are never flagged by
+    /// [GeneratedHeuristic::LongLines].
+    pub min_len: usize,
+    /// Files with an average line length larger than this are considered as
+    /// generated.
+    pub max_avg_line_len: usize,
+    /// Files with one of these in the first line are considered as
+    /// generated.
+    pub markers: Vec<String>,
+}
+
+impl Default for GeneratedHeuristics {
+    fn default() -> Self {
+        GeneratedHeuristics {
+            min_len: 64 * 1024,
+            max_avg_line_len: 1024,
+            markers: vec!["@generated".into(), "/* generated */".into()],
+        }
+    }
+}
+
+/// Returns the length of the identifier at the start of `s`, if it's followed
+/// by something else.
+fn ident_len(s: &str) -> Option<usize> {
+    match s.find(|c: char| !c.is_alphanumeric() && c != '_' && c != '$') {
+        Some(0) | None => None,
+        len => len,
+    }
+}
+
+/// The kind of a position, returned by [SourceMap::classify_pos].
+#[derive(Debug, Clone)]
+pub enum PosKind {
+    /// The position is in the file, including its `end_pos`.
+    InFile(Lrc<SourceFile>),
+    /// The position is after the `end_pos` of `before` and before the
+    /// `start_pos` of `after`.
+    FileGap {
+        before: Lrc<SourceFile>,
+        after: Lrc<SourceFile>,
+    },
+    /// The position is before the first file.
+    BeforeFirst,
+    /// The position is after the last file, or there is no file.
+    PastEnd,
+    /// The position of [crate::DUMMY_SP].
+    Dummy,
+    /// The position is reserved, and mappings at it are skipped by
+    /// [SourceMap::build_source_map].
+    Reserved,
+}
+
+/// A string literal whose generated length differs from its original length.
+///
+/// See [SourceMap::mapping_drifts].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappingDrift {
+    /// The start of the literal in the original source.
+    pub pos: BytePos,
+    /// The start of the literal in the generated code.
+    pub generated: LineCol,
+    /// The length of the literal in the original source, in bytes.
+    pub original_len: u32,
+    /// The length of the literal in the generated code, in bytes.
+    pub generated_len: u32,
+}
+
+/// Returns the length in bytes of the string or template literal at the
+/// start of `s`, including quotes.
+fn string_literal_len(s: &str) -> Option<usize> {
+    let quote = match s.chars().next()? {
+        c @ '\'' | c @ '"' | c @ '`' => c,
+        _ => return None,
+    };
+
+    let mut chars = s.char_indices().skip(1);
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\n' if quote != '`' => return None,
+            _ if c == quote => return Some(idx + 1),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// The reason why a source is considered as generated code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeneratedHeuristic {
+    /// The file is large and has very long lines, like a minified file.
+    LongLines,
+    /// The first line of the file contains the marker.
+    Marker(String),
+}
+
+impl GeneratedHeuristics {
+    pub fn check(&self, f: &SourceFile) -> Option<GeneratedHeuristic> {
+        if let Some(first_line) = f.get_line(0) {
+            for marker in &self.markers {
+                if first_line.contains(&**marker) {
+                    return Some(GeneratedHeuristic::Marker(marker.clone()));
+                }
+            }
+        }
+
+        let len = f.byte_length() as usize;
+        if len >= self.min_len && len / max(f.count_lines(), 1) > self.max_avg_line_len {
+            return Some(GeneratedHeuristic::LongLines);
+        }
+
+        None
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1227,6 +4163,18 @@ macro_rules! impl_ref {
             fn file_name_to_source(&self, f: &FileName) -> String {
                 (**self).file_name_to_source(f)
             }
+
+            fn file_name_to_source_with_unmapped(
+                &self,
+                f: &FileName,
+                unmapped_path: Option<&FileName>,
+            ) -> String {
+                (**self).file_name_to_source_with_unmapped(f, unmapped_path)
+            }
+
+            fn skip_pos(&self, pos: BytePos) -> bool {
+                (**self).skip_pos(pos)
+            }
         }
     };
 }
@@ -1242,6 +4190,45 @@ impl SourceMapGenConfig for DefaultSourceMapGenConfig {
     }
 }
 
+/// A [SourceMapGenConfig] which names sources with a closure, and behaves like
+/// [DefaultSourceMapGenConfig] otherwise.
+///
+/// ```
+/// use std::path::PathBuf;
+/// use swc_common::source_map::{FileName, MappedSourceMapGenConfig, SourceMapGenConfig};
+///
+/// let config = MappedSourceMapGenConfig::new(|f: &FileName| f.to_string().to_lowercase());
+///
+/// let name = FileName::Real(PathBuf::from("src/App.tsx"));
+/// assert_eq!(config.file_name_to_source(&name), "src/app.tsx");
+/// ```
+#[derive(Clone, Copy)]
+pub struct MappedSourceMapGenConfig<F> {
+    map: F,
+}
+
+impl<F> MappedSourceMapGenConfig<F>
+where
+    F: Fn(&FileName) -> String,
+{
+    pub fn new(map: F) -> Self {
+        MappedSourceMapGenConfig { map }
+    }
+}
+
+impl<F> SourceMapGenConfig for MappedSourceMapGenConfig<F>
+where
+    F: Fn(&FileName) -> String,
+{
+    fn file_name_to_source(&self, f: &FileName) -> String {
+        (self.map)(f)
+    }
+
+    fn skip_pos(&self, pos: BytePos) -> bool {
+        DefaultSourceMapGenConfig.skip_pos(pos)
+    }
+}
+
 // _____________________________________________________________________________
 // Tests
 //
@@ -1271,15 +4258,15 @@ mod tests {
         let sm = init_source_map();
 
         let srcfbp1 = sm.lookup_byte_offset(BytePos(23));
-        assert_eq!(srcfbp1.sf.name, PathBuf::from("blork.rs").into());
+        assert_eq!(*srcfbp1.sf.name, PathBuf::from("blork.rs").into());
         assert_eq!(srcfbp1.pos, BytePos(23));
 
         let srcfbp1 = sm.lookup_byte_offset(BytePos(24));
-        assert_eq!(srcfbp1.sf.name, PathBuf::from("empty.rs").into());
+        assert_eq!(*srcfbp1.sf.name, PathBuf::from("empty.rs").into());
         assert_eq!(srcfbp1.pos, BytePos(0));
 
         let srcfbp2 = sm.lookup_byte_offset(BytePos(25));
-        assert_eq!(srcfbp2.sf.name, PathBuf::from("blork2.rs").into());
+        assert_eq!(*srcfbp2.sf.name, PathBuf::from("blork2.rs").into());
         assert_eq!(srcfbp2.pos, BytePos(0));
     }
 
@@ -1301,14 +4288,20 @@ mod tests {
         let sm = init_source_map();
 
         let loc1 = sm.lookup_char_pos(BytePos(22));
-        assert_eq!(loc1.file.name, PathBuf::from("blork.rs").into());
+        assert_eq!(*loc1.file.name, PathBuf::from("blork.rs").into());
         assert_eq!(loc1.line, 2);
         assert_eq!(loc1.col, CharPos(10));
 
         let loc2 = sm.lookup_char_pos(BytePos(25));
-        assert_eq!(loc2.file.name, PathBuf::from("blork2.rs").into());
+        assert_eq!(*loc2.file.name, PathBuf::from("blork2.rs").into());
         assert_eq!(loc2.line, 1);
         assert_eq!(loc2.col, CharPos(0));
+
+        let loc3 = sm.lookup_char_pos(BytePos(24));
+        assert_eq!(*loc3.file.name, PathBuf::from("empty.rs").into());
+        assert_eq!(loc3.line, 1);
+        assert_eq!(loc3.col, CharPos(0));
+        assert_eq!(loc3.col_display, 0);
     }
 
     fn init_source_map_mbc() -> SourceMap {
@@ -1350,7 +4343,7 @@ mod tests {
         let span = Span::new(BytePos(12), BytePos(23), NO_EXPANSION);
         let file_lines = sm.span_to_lines(span).unwrap();
 
-        assert_eq!(file_lines.file.name, PathBuf::from("blork.rs").into());
+        assert_eq!(*file_lines.file.name, PathBuf::from("blork.rs").into());
         assert_eq!(file_lines.lines.len(), 1);
         assert_eq!(file_lines.lines[0].line_index, 1);
     }
@@ -1433,8 +4426,3074 @@ mod tests {
         let span = Span::new(BytePos(0), BytePos(0), NO_EXPANSION);
         let file_lines = sm.span_to_lines(span).unwrap();
 
-        assert_eq!(file_lines.file.name, PathBuf::from("blork.rs").into());
+        assert_eq!(*file_lines.file.name, PathBuf::from("blork.rs").into());
         assert_eq!(file_lines.lines.len(), 0);
+
+        assert_eq!(sm.span_to_string(span), "blork.rs:1:1: 1:1");
+        assert_eq!(sm.mk_substr_filename(span), "<blork.rs:1:1>");
+    }
+
+    #[test]
+    fn snippets_of_spans() {
+        let sm = init_source_map();
+        let spans = [
+            Span::new(BytePos(12), BytePos(23), NO_EXPANSION),
+            Span::new(BytePos(25), BytePos(30), NO_EXPANSION),
+            Span::new(BytePos(12), BytePos(30), NO_EXPANSION),
+            Span::new(BytePos(0), BytePos(5), NO_EXPANSION),
+            Span::new(BytePos(24), BytePos(24), NO_EXPANSION),
+        ];
+
+        let snippets = sm.snippets_of_spans(&spans);
+        assert_eq!(snippets.len(), spans.len());
+        assert_eq!(&**snippets[0].as_ref().unwrap(), "second line");
+        assert_eq!(&**snippets[1].as_ref().unwrap(), "first");
+        assert!(matches!(
+            snippets[2],
+            Err(SpanSnippetError::DistinctSources(..))
+        ));
+        assert_eq!(&**snippets[3].as_ref().unwrap(), "first");
+        assert_eq!(&**snippets[4].as_ref().unwrap(), "");
+
+        for (sp, snippet) in spans.iter().zip(snippets) {
+            assert_eq!(sm.span_to_snippet(*sp), snippet.map(|s| s.to_string()));
+        }
+    }
+
+    #[test]
+    fn with_snippets_of_spans() {
+        let sm = init_source_map();
+        let spans = [
+            Span::new(BytePos(25), BytePos(30), NO_EXPANSION),
+            Span::new(BytePos(12), BytePos(23), NO_EXPANSION),
+            DUMMY_SP,
+            Span::new(BytePos(12), BytePos(30), NO_EXPANSION),
+            Span::new(BytePos(0), BytePos(5), NO_EXPANSION),
+            Span::new(BytePos(24), BytePos(24), NO_EXPANSION),
+            Span::new(BytePos(40), BytePos(60), NO_EXPANSION),
+        ];
+
+        let mut snippets = vec![None; spans.len()];
+        sm.with_snippets_of_spans(&spans, |idx, snippet| {
+            assert!(snippets[idx].is_none());
+            snippets[idx] = Some(snippet.map(|s| s.to_string()));
+        });
+        let snippets = snippets.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+
+        assert_eq!(snippets[0], Ok("first".into()));
+        assert_eq!(snippets[1], Ok("second line".into()));
+        assert_eq!(snippets[2], Err(SpanSnippetError::IllFormedSpan(DUMMY_SP)));
+        assert!(matches!(
+            snippets[3],
+            Err(SpanSnippetError::DistinctSources(..))
+        ));
+        assert_eq!(snippets[4], Ok("first".into()));
+        assert_eq!(snippets[5], Ok("".into()));
+        assert_eq!(snippets[6], Err(SpanSnippetError::IllFormedSpan(spans[6])));
+
+        // `op` can use the source map.
+        sm.with_snippets_of_spans(&spans[..2], |idx, snippet| {
+            assert_eq!(sm.span_to_snippet(spans[idx]).unwrap(), snippet.unwrap());
+            sm.new_source_file(FileName::Anon, "added in op".into());
+        });
+        assert_eq!(sm.files().len(), 5);
+    }
+
+    #[test]
+    fn with_snippet_of_spans() {
+        let sm = init_source_map();
+        let spans = [
+            Span::new(BytePos(12), BytePos(23), NO_EXPANSION),
+            Span::new(BytePos(25), BytePos(30), NO_EXPANSION),
+            Span::new(BytePos(0), BytePos(5), NO_EXPANSION),
+        ];
+
+        assert_eq!(
+            sm.with_snippet_of_spans(&spans, |s| s.to_uppercase()),
+            Ok(vec!["SECOND LINE".into(), "FIRST".into(), "FIRST".into()])
+        );
+
+        let spans = [
+            Span::new(BytePos(0), BytePos(5), NO_EXPANSION),
+            Span::new(BytePos(12), BytePos(30), NO_EXPANSION),
+            DUMMY_SP,
+        ];
+        assert!(matches!(
+            sm.with_snippet_of_spans(&spans, |s| s.len()),
+            Err(SpanSnippetError::DistinctSources(..))
+        ));
+
+        let lines = sm.with_snippet_of_spans(&spans[..1], |s| {
+            let f = sm.new_source_file(FileName::Anon, s.into());
+            sm.lookup_char_pos(f.end_pos).line
+        });
+        assert_eq!(lines, Ok(vec![1]));
+    }
+
+    #[test]
+    fn path_mapping_entries() {
+        let mapping = FilePathMapping::new(vec![
+            (PathBuf::from("/src"), PathBuf::from("/first")),
+            (PathBuf::from("/src/lib"), PathBuf::from("/second")),
+        ]);
+
+        assert!(!mapping.is_empty());
+        assert!(FilePathMapping::empty().is_empty());
+        assert_eq!(
+            mapping.mappings(),
+            &[
+                (PathBuf::from("/src"), PathBuf::from("/first")),
+                (PathBuf::from("/src/lib"), PathBuf::from("/second")),
+            ]
+        );
+
+        assert_eq!(
+            mapping.map_prefix(PathBuf::from("/src/lib/a.js")),
+            (PathBuf::from("/second/a.js"), true)
+        );
+        assert_eq!(
+            mapping.map_prefix(PathBuf::from("/src/b.js")),
+            (PathBuf::from("/first/b.js"), true)
+        );
+        assert_eq!(
+            mapping.map_prefix_str("/src/lib/a.js"),
+            ("/second/a.js".to_string(), true)
+        );
+        assert_eq!(
+            mapping.map_prefix_str("/other/c.js"),
+            ("/other/c.js".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn generated_sources() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let user = sm.new_source_file(
+            PathBuf::from("user.js").into(),
+            "console.log('a user file with many lines');\n".repeat(10000),
+        );
+        let vendor =
+            sm.new_source_file(PathBuf::from("vendor.min.js").into(), "a();".repeat(125000));
+        let marked = sm.new_source_file(
+            PathBuf::from("marked.js").into(),
+            "// @generated\nfoo();\n".into(),
+        );
+
+        let mappings = vec![
+            (user.start_pos, LineCol { line: 0, col: 0 }),
+            (vendor.start_pos, LineCol { line: 1, col: 0 }),
+            (vendor.start_pos + BytePos(4), LineCol { line: 1, col: 4 }),
+            (user.start_pos + BytePos(8), LineCol { line: 2, col: 0 }),
+            (marked.start_pos, LineCol { line: 3, col: 0 }),
+        ];
+
+        let options = SourceMapOptions {
+            auto_ignore_generated: Some(Default::default()),
+            ..Default::default()
+        };
+        assert_eq!(
+            sm.generated_sources(&mappings, DefaultSourceMapGenConfig, &options),
+            vec![
+                ("vendor.min.js".to_string(), GeneratedHeuristic::LongLines),
+                (
+                    "marked.js".to_string(),
+                    GeneratedHeuristic::Marker("@generated".into())
+                ),
+            ]
+        );
+        assert_eq!(
+            sm.generated_sources(&mappings, DefaultSourceMapGenConfig, &Default::default()),
+            vec![]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn ignore_list_of_generated_sources() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let user = sm.new_source_file(PathBuf::from("user.js").into(), "foo();\n".into());
+        let marked = sm.new_source_file(
+            PathBuf::from("marked.js").into(),
+            "// @generated\nfoo();\n".into(),
+        );
+        let mappings = vec![
+            (user.start_pos, LineCol { line: 0, col: 0 }),
+            (marked.start_pos, LineCol { line: 1, col: 0 }),
+            (marked.start_pos + BytePos(14), LineCol { line: 1, col: 6 }),
+        ];
+        let build = |options: &SourceMapOptions| {
+            let map = sm.build_extended_source_map(
+                mappings.iter().copied(),
+                None,
+                DefaultSourceMapGenConfig,
+                options,
+            );
+            let mut buf = vec![];
+            map.to_writer(&mut buf).unwrap();
+            (
+                map,
+                serde_json::from_slice::<serde_json::Value>(&buf).unwrap(),
+            )
+        };
+
+        let (map, json) = build(&SourceMapOptions {
+            auto_ignore_generated: Some(Default::default()),
+            ..Default::default()
+        });
+        assert_eq!(map.ignore_list, vec![1]);
+        assert_eq!(map.map.get_source(1), Some("marked.js"));
+        assert_eq!(json["ignoreList"], serde_json::json!([1]));
+
+        let (map, json) = build(&Default::default());
+        assert_eq!(map.ignore_list, Vec::<u32>::new());
+        assert_eq!(json.get("ignoreList"), None);
+    }
+
+    #[test]
+    fn span_trim() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let inputs = [
+            "",
+            "   ",
+            "  foo  ",
+            "\tfoo bar\n",
+            "\u{3000}foo\u{3000}",
+            "\u{a0}\u{3000} €\u{3000}\u{a0}",
+            "foo;\r\n  bar",
+        ];
+
+        for input in inputs.iter() {
+            let fm = sm.new_source_file(PathBuf::from("trim.js").into(), input.to_string());
+
+            let boundaries = input
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain(Some(input.len()))
+                .collect::<Vec<_>>();
+
+            for (i, &lo) in boundaries.iter().enumerate() {
+                for &hi in &boundaries[i..] {
+                    let sp = Span::new(
+                        fm.start_pos + BytePos(lo as u32),
+                        fm.start_pos + BytePos(hi as u32),
+                        NO_EXPANSION,
+                    );
+                    let snippet = &input[lo..hi];
+
+                    let trimmed = sm.span_trim_start(sp);
+                    assert!(trimmed.lo() <= trimmed.hi());
+                    assert_eq!(sm.span_to_snippet(trimmed).unwrap(), snippet.trim_start());
+
+                    let trimmed = sm.span_trim_end(sp);
+                    assert!(trimmed.lo() <= trimmed.hi());
+                    assert_eq!(sm.span_to_snippet(trimmed).unwrap(), snippet.trim_end());
+
+                    let trimmed = sm.span_trim(sp);
+                    assert!(trimmed.lo() <= trimmed.hi());
+                    assert_eq!(sm.span_to_snippet(trimmed).unwrap(), snippet.trim());
+                }
+            }
+        }
+
+        assert_eq!(sm.span_trim(DUMMY_SP), DUMMY_SP);
+    }
+
+    #[test]
+    fn span_of_word_at() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        sm.new_source_file(PathBuf::from("blork.rs").into(), "x".to_string());
+        let fm = sm.new_source_file(
+            PathBuf::from("word.js").into(),
+            "let €foo_1 = $bar;\nbaz".to_string(),
+        );
+        let word_at = |offset: u32| {
+            sm.span_of_word_at(fm.start_pos + BytePos(offset))
+                .map(|sp| sm.span_to_snippet(sp).unwrap())
+        };
+
+        assert_eq!(word_at(0), Some("let".into()));
+        assert_eq!(word_at(2), Some("let".into()));
+        assert_eq!(word_at(3), None);
+        // Inside `€`
+        assert_eq!(word_at(4), None);
+        assert_eq!(word_at(5), None);
+        assert_eq!(word_at(7), Some("foo_1".into()));
+        assert_eq!(word_at(11), Some("foo_1".into()));
+        assert_eq!(word_at(13), None);
+        assert_eq!(word_at(15), Some("$bar".into()));
+        assert_eq!(word_at(19), None);
+        assert_eq!(word_at(20), None);
+        assert_eq!(word_at(23), Some("baz".into()));
+        assert_eq!(word_at(24), None);
+    }
+
+    #[test]
+    fn lookup_char_pos_with_tab_width() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let fm = sm.new_source_file(
+            PathBuf::from("tabs.js").into(),
+            "a\tb\n\t中\tx\n中中\t\ty".to_string(),
+        );
+        let col_display = |offset: u32, tab_width: usize| {
+            sm.lookup_char_pos_with_tab_width(fm.start_pos + BytePos(offset), tab_width)
+                .col_display
+        };
+
+        assert_eq!(col_display(2, 4), 4);
+        assert_eq!(col_display(2, 8), 8);
+        assert_eq!(col_display(2, 1), 2);
+
+        // `\t中\tx`
+        assert_eq!(col_display(5, 4), 4);
+        assert_eq!(col_display(8, 4), 6);
+        assert_eq!(col_display(9, 4), 8);
+        assert_eq!(col_display(9, 8), 16);
+
+        // `中中\t\ty`
+        assert_eq!(col_display(17, 4), 4);
+        assert_eq!(col_display(18, 4), 8);
+        assert_eq!(col_display(19, 4), 12);
+        assert_eq!(col_display(19, 8), 16);
+        // The default expands tabs to 4 columns regardless of the position.
+        assert_eq!(
+            sm.lookup_char_pos(fm.start_pos + BytePos(19)).col_display,
+            12
+        );
+        assert_eq!(
+            sm.lookup_char_pos(fm.start_pos + BytePos(9)).col_display,
+            10
+        );
+
+        let loc = sm.lookup_char_pos_with_tab_width(fm.start_pos + BytePos(19), 8);
+        assert_eq!(loc.line, 3);
+        assert_eq!(loc.col, CharPos(4));
+
+        // Without the source code, the default width of tabs is used.
+        let imported = sm.new_imported_source_file(
+            PathBuf::from("imported.js").into(),
+            vec![BytePos(0)],
+            8,
+            None,
+            None,
+        );
+        let loc = sm.lookup_char_pos_with_tab_width(imported.start_pos + BytePos(5), 8);
+        assert_eq!(loc.col_display, 5);
+
+        // The source of a metadata-only file is loaded: `// \t漢.js\n`
+        let sm = SourceMap::with_file_loader(
+            Box::new(MemoryFileLoader::default()),
+            FilePathMapping::empty(),
+        );
+        let fm = sm.load_file_metadata_only(Path::new("\t漢.js")).unwrap();
+        let loc = sm.lookup_char_pos_with_tab_width(fm.start_pos + BytePos(7), 8);
+        assert_eq!(loc.col_display, 10);
+    }
+
+    #[cfg(feature = "sourcemap")]
+    fn sources_of(map: &sourcemap::SourceMap) -> serde_json::Value {
+        let mut buf = vec![];
+        map.to_writer(&mut buf).unwrap();
+        serde_json::from_slice(&buf).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn build_source_map_with_repeated_orig_sources() {
+        let orig = sourcemap::SourceMap::from_slice(
+            br#"{
+                "version": 3,
+                "sources": ["a.ts", "a.ts", "b.ts"],
+                "sourcesContent": ["a", null, "b"],
+                "names": [],
+                "mappings": "AAAA;AEAA"
+            }"#,
+        )
+        .unwrap();
+
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let f = sm.new_source_file(PathBuf::from("out.js").into(), "x;\ny;".into());
+        let mut mappings = vec![
+            (f.start_pos, LineCol { line: 0, col: 0 }),
+            (f.start_pos + BytePos(3), LineCol { line: 1, col: 0 }),
+        ];
+        let map =
+            sm.build_source_map_with_config(&mut mappings, Some(&orig), DefaultSourceMapGenConfig);
+        let json = sources_of(&map);
+
+        assert_eq!(
+            json["sources"],
+            serde_json::json!(["a.ts", "b.ts", "out.js"])
+        );
+        assert_eq!(
+            json["sourcesContent"],
+            serde_json::json!(["a", "b", "x;\ny;"])
+        );
+        let token = map.lookup_token(1, 0).unwrap();
+        assert_eq!(token.get_source(), Some("b.ts"));
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn build_source_map_with_source_root() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let inner = sm.new_source_file(PathBuf::from("/project/src/a.js").into(), "a();".into());
+        let outer = sm.new_source_file(PathBuf::from("/other/b.js").into(), "b();".into());
+
+        let mut mappings = vec![
+            (inner.start_pos, LineCol { line: 0, col: 0 }),
+            (outer.start_pos, LineCol { line: 1, col: 0 }),
+        ];
+        let options = SourceMapOptions {
+            source_root: Some("/project".into()),
+            ..Default::default()
+        };
+        let map = sm.build_source_map_with_options(
+            mappings.iter().copied(),
+            None,
+            DefaultSourceMapGenConfig,
+            &options,
+        );
+        let json = sources_of(&map);
+
+        assert_eq!(json["sourceRoot"], "/project");
+        assert_eq!(
+            json["sources"],
+            serde_json::json!(["src/a.js", "/other/b.js"])
+        );
+
+        let json = sources_of(&sm.build_source_map(&mut mappings));
+        assert!(json.get("sourceRoot").map_or(true, |v| v.is_null()));
+        assert_eq!(
+            json["sources"],
+            serde_json::json!(["/project/src/a.js", "/other/b.js"])
+        );
+    }
+
+    #[test]
+    fn span_contains_and_overlap() {
+        let sm = init_source_map();
+        let sp = |lo: u32, hi: u32| Span::new(BytePos(lo), BytePos(hi), NO_EXPANSION);
+
+        assert!(sm.span_contains(sp(0, 10), sp(2, 5)));
+        assert!(sm.span_contains(sp(0, 10), sp(5, 10)));
+        assert!(sm.span_contains(sp(0, 10), sp(0, 10)));
+        assert!(!sm.span_contains(sp(0, 10), sp(5, 11)));
+        assert!(!sm.span_contains(sp(2, 5), sp(0, 10)));
+        // `blork.rs` and `blork2.rs`
+        assert!(!sm.span_contains(sp(0, 30), sp(25, 30)));
+        assert!(!sm.span_contains(sp(12, 30), sp(12, 20)));
+
+        assert!(sm.spans_overlap(sp(0, 10), sp(5, 15)));
+        assert!(sm.spans_overlap(sp(5, 15), sp(0, 10)));
+        assert!(sm.spans_overlap(sp(0, 10), sp(2, 5)));
+        assert!(!sm.spans_overlap(sp(0, 5), sp(5, 10)));
+        assert!(!sm.spans_overlap(sp(0, 5), sp(6, 10)));
+        assert!(!sm.spans_overlap(sp(20, 23), sp(25, 30)));
+        assert!(!sm.spans_overlap(sp(12, 30), sp(25, 30)));
+    }
+
+    #[test]
+    fn lookup_file_boundaries() {
+        let sm = init_source_map();
+        let sp = |lo: u32, hi: u32| Span::new(BytePos(lo), BytePos(hi), NO_EXPANSION);
+
+        // `blork.rs` is 0..23, `empty.rs` is 24..24 and `blork2.rs` is 25..48.
+        let expected = |pos: u32| match pos {
+            0..=23 => Some(("blork.rs", 0)),
+            24 => Some(("empty.rs", 24)),
+            25..=48 => Some(("blork2.rs", 25)),
+            _ => None,
+        };
+
+        for pos in 0..60 {
+            let found = sm.try_lookup_source_file(BytePos(pos));
+            let found = found.as_ref().map(|f| (f.name.to_string(), f.start_pos.0));
+            let expected = expected(pos).map(|(name, start)| (name.to_string(), start));
+            assert_eq!(found, expected, "pos = {}", pos);
+
+            match expected {
+                Some((_, start)) => {
+                    let off = sm.lookup_byte_offset(BytePos(pos));
+                    assert_eq!(off.pos, BytePos(pos - start));
+
+                    let snippet = sm.span_to_snippet(sp(start, pos)).unwrap();
+                    assert_eq!(snippet.len(), (pos - start) as usize);
+                    assert_eq!(sm.span_to_snippet(sp(pos, pos)).unwrap(), "");
+                }
+                None => {
+                    assert!(sm.try_lookup_byte_offset(BytePos(pos)).is_none());
+                    assert!(sm.span_to_snippet(sp(pos, pos)).is_err());
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "does not resolve to a source location")]
+    fn lookup_past_last_file() {
+        let sm = init_source_map();
+        sm.lookup_source_file(BytePos(49));
+    }
+
+    #[test]
+    fn line_counts() {
+        let sm = init_source_map();
+
+        assert_eq!(
+            sm.line_counts(),
+            vec![
+                (PathBuf::from("blork.rs").into(), 2),
+                (PathBuf::from("empty.rs").into(), 0),
+                (PathBuf::from("blork2.rs").into(), 2),
+            ]
+        );
+        assert_eq!(sm.count_lines(), 4);
+    }
+
+    #[test]
+    fn next_point() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        sm.new_source_file(PathBuf::from("a.js").into(), "ab".into());
+        sm.new_source_file(PathBuf::from("b.js").into(), "x€".into());
+        let sp = |lo: u32, hi: u32| Span::new(BytePos(lo), BytePos(hi), NO_EXPANSION);
+
+        // `a.js` is 0..2 and `b.js` is 3..7.
+        assert_eq!(sm.try_next_point(sp(0, 1)), Some(sp(1, 2)));
+        assert_eq!(sm.try_next_point(sp(0, 2)), None);
+        assert_eq!(sm.next_point(sp(0, 2)), sp(2, 2));
+
+        // Zero-length spans
+        assert_eq!(sm.try_next_point(sp(0, 0)), Some(sp(0, 1)));
+        assert_eq!(sm.try_next_point(sp(3, 3)), Some(sp(3, 4)));
+        assert_eq!(sm.try_next_point(sp(2, 2)), None);
+
+        // The multibyte character at the end of `b.js`
+        assert_eq!(sm.try_next_point(sp(3, 4)), Some(sp(4, 7)));
+        assert_eq!(sm.span_to_snippet(sp(4, 7)).unwrap(), "€");
+        assert_eq!(sm.try_next_point(sp(4, 7)), None);
+        assert_eq!(sm.next_point(sp(4, 7)), sp(7, 7));
+
+        // Inside of a multibyte character and past the end
+        assert_eq!(sm.try_next_point(sp(3, 5)), None);
+        assert_eq!(sm.try_next_point(sp(7, 8)), None);
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn build_source_map_dedups_sources() {
+        struct Config;
+
+        impl SourceMapGenConfig for Config {
+            fn file_name_to_source(&self, f: &FileName) -> String {
+                match f {
+                    FileName::Custom(s) => s.clone(),
+                    _ => f.to_string(),
+                }
+            }
+        }
+
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let src = "let a = 1;\nlet b = 2;";
+        let real = sm.new_source_file(PathBuf::from("/src/a.js").into(), src.into());
+        let custom = sm.new_source_file(FileName::Custom("/src/a.js".into()), src.into());
+
+        let mut mappings = vec![
+            (real.start_pos, LineCol { line: 0, col: 0 }),
+            (custom.start_pos + BytePos(11), LineCol { line: 1, col: 0 }),
+            (real.start_pos + BytePos(4), LineCol { line: 2, col: 0 }),
+        ];
+        let map = sm.build_source_map_with_config(&mut mappings, None, Config);
+        let json = sources_of(&map);
+
+        assert_eq!(json["sources"], serde_json::json!(["/src/a.js"]));
+        assert_eq!(json["sourcesContent"], serde_json::json!([src]));
+
+        let tokens = map
+            .tokens()
+            .map(|t| (t.get_src_id(), t.get_src_line(), t.get_src_col()))
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec![(0, 0, 0), (0, 1, 0), (0, 0, 4)]);
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn build_source_map_with_source_name_prefix() {
+        struct Config;
+
+        impl SourceMapGenConfig for Config {
+            fn file_name_to_source(&self, f: &FileName) -> String {
+                match f {
+                    FileName::Custom(s) => s.clone(),
+                    _ => f.to_string(),
+                }
+            }
+        }
+
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let files = vec![
+            sm.new_source_file(PathBuf::from("/src/app.ts").into(), "a".into()),
+            sm.new_source_file(FileName::Custom("/src/lib.ts".into()), "b".into()),
+            sm.new_source_file(
+                FileName::Custom("data:text/javascript,c".into()),
+                "c".into(),
+            ),
+            sm.new_source_file(FileName::Custom("https://cdn/d.js".into()), "d".into()),
+            sm.new_source_file(FileName::Anon, "e".into()),
+        ];
+        let mut mappings = files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                (
+                    f.start_pos,
+                    LineCol {
+                        line: i as u32,
+                        col: 0,
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let build = |mappings: &[(BytePos, LineCol)], orig, prefix: &str| {
+            let options = SourceMapOptions {
+                source_name_prefix: Some(prefix.into()),
+                ..Default::default()
+            };
+            sm.build_source_map_with_options(mappings.iter().copied(), orig, Config, &options)
+        };
+        let mut modern = sources_of(&build(&mappings, None, "modern://"));
+        let mut legacy = sources_of(&build(&mappings, None, "legacy://"));
+
+        assert_eq!(
+            modern["sources"],
+            serde_json::json!([
+                "modern:///src/app.ts",
+                "modern:///src/lib.ts",
+                "data:text/javascript,c",
+                "https://cdn/d.js",
+                "<anon>"
+            ])
+        );
+        assert_eq!(
+            legacy["sources"],
+            serde_json::json!([
+                "legacy:///src/app.ts",
+                "legacy:///src/lib.ts",
+                "data:text/javascript,c",
+                "https://cdn/d.js",
+                "<anon>"
+            ])
+        );
+
+        modern.as_object_mut().unwrap().remove("sources");
+        legacy.as_object_mut().unwrap().remove("sources");
+        assert_eq!(modern, legacy);
+
+        // Sources of the original source map
+        let orig = {
+            let mut builder = SourceMapBuilder::new(None);
+            let src_id = builder.add_source("orig.ts");
+            builder.add_raw(0, 0, 3, 4, Some(src_id), None);
+            builder.into_sourcemap()
+        };
+        let mappings = vec![(files[0].start_pos, LineCol { line: 0, col: 0 })];
+        let map = build(&mappings, Some(&orig), "modern://");
+
+        assert_eq!(
+            sources_of(&map)["sources"],
+            serde_json::json!(["modern://orig.ts", "modern:///src/app.ts"])
+        );
+        assert_eq!(
+            map.get_token(0).unwrap().get_source(),
+            Some("modern://orig.ts")
+        );
+    }
+
+    #[test]
+    fn start_and_end_points_at_file_boundaries() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let poo = sm.new_source_file(PathBuf::from("poo.js").into(), "a💩".into());
+        let delta = sm.new_source_file(PathBuf::from("delta.js").into(), "∆b".into());
+        let single = sm.new_source_file(PathBuf::from("single.js").into(), "c".into());
+        let whole = |f: &SourceFile| Span::new(f.start_pos, f.end_pos, NO_EXPANSION);
+        let snippet = |sp: Span| sm.span_to_snippet(sp).unwrap();
+
+        // File ending in a 4-byte character, `hi` equals to `end_pos`
+        assert_eq!(snippet(sm.end_point(whole(&poo))), "💩");
+        assert_eq!(snippet(sm.start_point(whole(&poo))), "a");
+
+        // File starting with a 3-byte character
+        assert_eq!(snippet(sm.start_point(whole(&delta))), "∆");
+        assert_eq!(snippet(sm.end_point(whole(&delta))), "b");
+
+        assert_eq!(sm.start_point(whole(&single)), whole(&single));
+        assert_eq!(sm.end_point(whole(&single)), whole(&single));
+    }
+
+    #[test]
+    fn start_and_end_points_of_zero_width_spans() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        sm.new_source_file(PathBuf::from("a.js").into(), "a€b".into());
+        let sp = |lo: u32, hi: u32| Span::new(BytePos(lo), BytePos(hi), NO_EXPANSION);
+
+        // `a.js` is 0..5
+        for &pos in &[0, 1, 4, 5] {
+            assert_eq!(sm.start_point(sp(pos, pos)), sp(pos, pos));
+            assert_eq!(sm.end_point(sp(pos, pos)), sp(pos, pos));
+        }
+        assert_eq!(sm.start_point(DUMMY_SP), DUMMY_SP);
+        assert_eq!(sm.end_point(DUMMY_SP), DUMMY_SP);
+
+        assert_eq!(sm.start_point(sp(1, 5)), sp(1, 4));
+        assert_eq!(sm.end_point(sp(0, 4)), sp(1, 4));
+        assert_eq!(sm.end_point(sp(4, 5)), sp(4, 5));
+
+        // Spans shorter than the character
+        assert_eq!(sm.start_point(sp(1, 2)), sp(1, 2));
+        assert_eq!(sm.end_point(sp(3, 4)), sp(3, 4));
+    }
+
+    #[test]
+    fn doctest_offsets() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        assert_eq!(sm.doctest_offset_line(3), 3);
+
+        let sm = sm.with_doctest_offsets(vec![(1..5, 10), (5..9, -2)]);
+
+        assert_eq!(sm.doctest_offset_line(0), 0);
+        assert_eq!(sm.doctest_offset_line(1), 11);
+        assert_eq!(sm.doctest_offset_line(4), 14);
+        assert_eq!(sm.doctest_offset_line(5), 3);
+        assert_eq!(sm.doctest_offset_line(8), 6);
+        assert_eq!(sm.doctest_offset_line(9), 9);
+    }
+
+    #[test]
+    fn display_col_of() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        // `€` is 3 bytes and 1 column wide, `漢` is 3 bytes and 2 columns wide.
+        sm.new_source_file(PathBuf::from("a.js").into(), "x\na\t€漢b".into());
+        let col = |pos: u32, tab_width: usize| sm.display_col_of(BytePos(pos), tab_width);
+
+        assert_eq!(col(2, 4), Some(0));
+        assert_eq!(col(3, 4), Some(1));
+        // After the tab
+        assert_eq!(col(4, 4), Some(4));
+        assert_eq!(col(4, 8), Some(8));
+        assert_eq!(col(4, 1), Some(2));
+        // After `€` and `漢`
+        assert_eq!(col(7, 4), Some(5));
+        assert_eq!(col(10, 4), Some(7));
+        assert_eq!(col(10, 1), Some(5));
+        // End of the file
+        assert_eq!(col(11, 4), Some(8));
+
+        // Inside of `€` and past the end
+        assert_eq!(col(5, 4), None);
+        assert_eq!(col(12, 4), None);
+
+        // `// \t漢.js\n` is loaded on demand.
+        let sm = SourceMap::with_file_loader(
+            Box::new(MemoryFileLoader::default()),
+            FilePathMapping::empty(),
+        );
+        let fm = sm.load_file_metadata_only(Path::new("\t漢.js")).unwrap();
+        let col = |offset: u32| sm.display_col_of(fm.start_pos + BytePos(offset), 4);
+        assert_eq!(col(4), Some(4));
+        assert_eq!(col(7), Some(6));
+        assert_eq!(col(5), None);
+    }
+
+    #[test]
+    fn utf16_round_trip() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        sm.new_source_file(PathBuf::from("blork.rs").into(), "first".into());
+        let src = "t¢e∆s💩t";
+        let f = sm.new_source_file(PathBuf::from("blork2.rs").into(), src.into());
+
+        let mut units = 0;
+        for (idx, c) in src.char_indices().chain(Some((src.len(), ' '))) {
+            let pos = f.start_pos + BytePos(idx as u32);
+            assert_eq!(sm.bytepos_to_utf16(pos), Some(units));
+            assert_eq!(sm.utf16_to_bytepos(&f, units), Some(pos));
+            units += c.len_utf16() as u32;
+        }
+        assert_eq!(units - 1, 8);
+
+        // Inside of `💩`
+        let poo = f.start_pos + BytePos(src.find('💩').unwrap() as u32);
+        assert_eq!(sm.bytepos_to_utf16(poo + BytePos(2)), Some(5));
+        assert_eq!(sm.utf16_to_bytepos(&f, 6), Some(poo));
+        // Inside of `∆`
+        let delta = f.start_pos + BytePos(src.find('∆').unwrap() as u32);
+        assert_eq!(sm.bytepos_to_utf16(delta + BytePos(1)), Some(3));
+
+        assert_eq!(sm.utf16_to_bytepos(&f, 9), None);
+        assert_eq!(sm.bytepos_to_utf16(f.end_pos + BytePos(1)), None);
+    }
+
+    #[derive(Default)]
+    struct MemoryFileLoader {
+        reads: std::sync::Arc<AtomicUsize>,
+    }
+
+    impl FileLoader for MemoryFileLoader {
+        fn file_exists(&self, _: &Path) -> bool {
+            true
+        }
+
+        fn abs_path(&self, path: &Path) -> Option<PathBuf> {
+            Some(Path::new("/project").join(path))
+        }
+
+        fn read_file(&self, path: &Path) -> io::Result<String> {
+            self.reads.fetch_add(1, SeqCst);
+            Ok(format!("// {}\n", path.display()))
+        }
+    }
+
+    #[test]
+    fn load_file_cached() {
+        let loader = MemoryFileLoader::default();
+        let reads = loader.reads.clone();
+        let sm = SourceMap::with_file_loader(Box::new(loader), FilePathMapping::empty());
+
+        let rel = sm.load_file_cached(Path::new("src/a.js")).unwrap();
+        let abs = sm.load_file_cached(Path::new("/project/src/a.js")).unwrap();
+        assert!(Lrc::ptr_eq(&rel, &abs));
+        assert_eq!(*rel.name, PathBuf::from("src/a.js").into());
+        assert_eq!(reads.load(SeqCst), 1);
+
+        let other = sm.load_file_cached(Path::new("/other/a.js")).unwrap();
+        assert!(!Lrc::ptr_eq(&rel, &other));
+        assert_eq!(reads.load(SeqCst), 2);
+
+        let removed = sm.invalidate_path(Path::new("/project/src/a.js")).unwrap();
+        assert!(Lrc::ptr_eq(&rel, &removed));
+        assert!(sm.invalidate_path(Path::new("src/a.js")).is_none());
+
+        let reloaded = sm.load_file_cached(Path::new("src/a.js")).unwrap();
+        assert!(!Lrc::ptr_eq(&rel, &reloaded));
+        assert_eq!(reads.load(SeqCst), 3);
+        // The invalidated file is still a part of the source map.
+        assert_eq!(
+            sm.lookup_char_pos(rel.start_pos).file.start_pos,
+            rel.start_pos
+        );
+    }
+
+    #[test]
+    fn load_file_cached_remaps_path() {
+        let mapping = FilePathMapping::new(vec![("/project".into(), "~".into())]);
+        let sm = SourceMap::with_file_loader(Box::new(MemoryFileLoader::default()), mapping);
+
+        let fm = sm.load_file_cached(Path::new("/project/a.js")).unwrap();
+        assert_eq!(*fm.name, PathBuf::from("~/a.js").into());
+        assert!(fm.name_was_remapped);
+        assert!(Lrc::ptr_eq(
+            &fm,
+            &sm.load_file_cached(Path::new("a.js")).unwrap()
+        ));
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let sm = SourceMap::with_file_loader(
+            Box::new(MemoryFileLoader::default()),
+            FilePathMapping::empty(),
+        );
+        let paths = (0..1000)
+            .map(|i| PathBuf::from(format!("{}.js", i)))
+            .collect::<Vec<_>>();
+        for path in &paths {
+            sm.load_file_cached(path).unwrap();
+        }
+        for path in &paths[10..] {
+            sm.invalidate_path(path).unwrap();
+        }
+
+        sm.shrink_to_fit();
+
+        let files = sm.files.borrow();
+        assert!(files.source_files.capacity() < 1100);
+        assert!(files.stable_id_to_source_file.capacity() < 2000);
+        let loaded = sm.loaded_files.borrow();
+        assert_eq!(loaded.len(), 10);
+        assert!(loaded.capacity() < 20);
+    }
+
+    #[test]
+    fn snippet_window_in_middle() {
+        let line = format!("{}target{}", "€".repeat(100), "가".repeat(50));
+        let window = SnippetWindow::new(&line, 100, 106, 20);
+
+        assert_eq!(window.line, "…€€€€€€€target가가…");
+        assert!(window.truncated_left);
+        assert!(window.truncated_right);
+        // The left ellipsis and 7 `€`s
+        assert_eq!(window.start_col, 8);
+        assert_eq!(window.end_col, 14);
+    }
+
+    #[test]
+    fn snippet_window_at_start() {
+        let line = format!("target{}", "b".repeat(200));
+        let window = SnippetWindow::new(&line, 0, 6, 20);
+
+        assert_eq!(window.line, format!("target{}…", "b".repeat(13)));
+        assert!(!window.truncated_left);
+        assert!(window.truncated_right);
+        assert_eq!((window.start_col, window.end_col), (0, 6));
+    }
+
+    #[test]
+    fn snippet_window_at_end() {
+        let line = format!("{}target", "가".repeat(100));
+        let window = SnippetWindow::new(&line, 200, 206, 20);
+
+        // `가` takes two columns, so only 6 of them fit.
+        assert_eq!(window.line, "…가가가가가가target");
+        assert!(window.truncated_left);
+        assert!(!window.truncated_right);
+        assert_eq!((window.start_col, window.end_col), (13, 19));
+    }
+
+    #[test]
+    fn snippet_window_fits() {
+        let window = SnippetWindow::new("let a = 1;", 4, 5, 20);
+
+        assert_eq!(window.line, "let a = 1;");
+        assert!(!window.truncated_left && !window.truncated_right);
+        assert_eq!((window.start_col, window.end_col), (4, 5));
+    }
+
+    #[test]
+    fn window_around_span() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let src = format!("first\n{}target{}\nlast", "a".repeat(100), "b".repeat(100));
+        let f = sm.new_source_file(PathBuf::from("blork.rs").into(), src.clone());
+        let lo = f.start_pos + BytePos(src.find("target").unwrap() as u32);
+
+        let window = sm
+            .window_around(Span::new(lo, lo + BytePos(6), NO_EXPANSION), 20)
+            .unwrap();
+        assert_eq!(window.line, "…aaaaaaatargetbbbbb…");
+        assert_eq!((window.start_col, window.end_col), (8, 14));
+
+        // A span ending on the next line is clamped to the end of the line.
+        let window = sm
+            .window_around(Span::new(lo, f.end_pos, NO_EXPANSION), 20)
+            .unwrap();
+        assert_eq!(window.line, "…targetbbbbbbbbbbbb…");
+        assert_eq!((window.start_col, window.end_col), (1, 19));
+
+        // Spans outside of the file, or ending in the next one.
+        let g = sm.new_source_file(PathBuf::from("next.rs").into(), "next".into());
+        let past_end = Span::new(g.end_pos + BytePos(1), g.end_pos + BytePos(2), NO_EXPANSION);
+        assert_eq!(sm.window_around(past_end, 20), None);
+        let across = Span::new(lo, g.start_pos + BytePos(2), NO_EXPANSION);
+        assert_eq!(sm.window_around(across, 20), None);
+    }
+
+    #[test]
+    fn anon_source_files() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let a = sm.new_anon_source_file("let a = 1;".into());
+        let b = sm.new_anon_source_file("let b = 2;".into());
+
+        assert_ne!(a.name, b.name);
+        assert!(a.name.is_anon() && b.name.is_anon());
+        assert_eq!(a.name.to_string(), "<anon:0>");
+        assert_ne!(StableSourceFileId::new(&a), StableSourceFileId::new(&b));
+
+        let b_id = StableSourceFileId::new(&b);
+        let found = sm.source_file_by_stable_id(b_id).unwrap();
+        assert!(Lrc::ptr_eq(&found, &b));
+        assert!(Lrc::ptr_eq(&sm.get_source_file(&a.name).unwrap(), &a));
+
+        let span = |f: &SourceFile| {
+            Span::new(
+                f.start_pos + BytePos(4),
+                f.start_pos + BytePos(5),
+                NO_EXPANSION,
+            )
+        };
+        assert_eq!(sm.span_to_snippet(span(&a)).unwrap(), "a");
+        assert_eq!(sm.span_to_snippet(span(&b)).unwrap(), "b");
+
+        // A custom name which looks the same is a different file.
+        let custom = FileName::Custom("anon:0".into());
+        assert!(!custom.is_anon());
+        let c = sm.new_source_file(custom.clone(), "let c = 3;".into());
+        assert!(Lrc::ptr_eq(&sm.get_source_file(&a.name).unwrap(), &a));
+        assert!(Lrc::ptr_eq(&sm.get_source_file(&custom).unwrap(), &c));
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn build_source_map_of_anon_source_files() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let a = sm.new_anon_source_file("a".into());
+        let b = sm.new_anon_source_file("b".into());
+
+        let mappings = vec![
+            (a.start_pos, LineCol { line: 0, col: 0 }),
+            (b.start_pos, LineCol { line: 1, col: 0 }),
+        ];
+        let options = SourceMapOptions {
+            source_name_prefix: Some("webpack://".into()),
+            ..Default::default()
+        };
+        let map = sm.build_source_map_with_options(
+            mappings.iter().copied(),
+            None,
+            DefaultSourceMapGenConfig,
+            &options,
+        );
+        let json = sources_of(&map);
+
+        assert_eq!(json["sources"], serde_json::json!(["<anon:0>", "<anon:1>"]));
+        assert_eq!(json["sourcesContent"], serde_json::json!(["a", "b"]));
+    }
+
+    #[test]
+    fn freeze() {
+        let sm = init_source_map_mbc();
+        let frozen = sm.freeze();
+
+        assert_eq!(frozen.files().len(), 2);
+        for f in frozen.files() {
+            let offsets = f
+                .src
+                .char_indices()
+                .map(|(idx, _)| idx)
+                .chain(Some(f.src.len()));
+            for pos in offsets.map(|idx| f.start_pos + BytePos(idx as u32)) {
+                let live = sm.lookup_char_pos(pos);
+                let loc = frozen.lookup_char_pos(pos);
+                assert_eq!(live.file.start_pos, loc.file.start_pos);
+                assert_eq!(
+                    (live.line, live.col, live.col_display),
+                    (loc.line, loc.col, loc.col_display)
+                );
+            }
+        }
+        assert!(frozen
+            .try_lookup_source_file(frozen.files()[1].end_pos + BytePos(1))
+            .is_none());
+
+        let span = Span::new(BytePos(1), BytePos(31), NO_EXPANSION);
+        assert_eq!(frozen.span_to_snippet(span), sm.span_to_snippet(span));
+        assert_eq!(
+            frozen.span_to_snippet(span).unwrap(),
+            "ir€st €€€€ line.\nsec"
+        );
+        assert_eq!(
+            frozen.span_to_lines(span).unwrap().lines,
+            sm.span_to_lines(span).unwrap().lines
+        );
+
+        let blork2 = frozen
+            .get_source_file(&PathBuf::from("blork2.rs").into())
+            .unwrap();
+        assert_eq!(blork2.start_pos, sm.files()[1].start_pos);
+        assert!(frozen
+            .get_source_file(&PathBuf::from("blork3.rs").into())
+            .is_none());
+    }
+
+    #[test]
+    fn freeze_and_thaw() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let a = sm.new_source_file(PathBuf::from("a.js").into(), "let a;".into());
+        let anon = sm.new_anon_source_file("anon".into());
+        let frozen = sm.freeze();
+
+        // Files added after freezing are not a part of the snapshot.
+        sm.new_source_file(PathBuf::from("b.js").into(), "let b;".into());
+        assert_eq!(frozen.files().len(), 2);
+
+        let thawed = SourceMap::thaw(frozen.clone());
+        let c = thawed.new_source_file(PathBuf::from("c.js").into(), "let c;".into());
+        assert!(c.start_pos > anon.end_pos);
+        assert_eq!(thawed.files().len(), 3);
+
+        let span = |f: &SourceFile| {
+            Span::new(
+                f.start_pos + BytePos(4),
+                f.start_pos + BytePos(5),
+                NO_EXPANSION,
+            )
+        };
+        assert_eq!(thawed.span_to_snippet(span(&a)).unwrap(), "a");
+        assert_eq!(thawed.span_to_snippet(span(&c)).unwrap(), "c");
+        assert_eq!(thawed.lookup_char_pos(c.start_pos).file.name, c.name);
+        assert!(Lrc::ptr_eq(
+            &thawed
+                .source_file_by_stable_id(StableSourceFileId::new(&a))
+                .unwrap(),
+            &a
+        ));
+
+        // Anonymous files keep getting distinct names.
+        let anon2 = thawed.new_anon_source_file("anon".into());
+        assert_ne!(anon.name, anon2.name);
+
+        // The snapshot is not affected by the thawed source map.
+        assert_eq!(frozen.files().len(), 2);
+        assert!(frozen.try_lookup_source_file(c.start_pos).is_none());
+    }
+
+    #[test]
+    fn interned_file_names() {
+        let sm = SourceMap::new(FilePathMapping::empty())
+            .with_interner(DedupFileNameInterner::default());
+        let a = sm.new_source_file(PathBuf::from("a.js").into(), "a".into());
+        let b = sm.new_source_file(PathBuf::from("b.js").into(), "b".into());
+        let a2 = sm.new_source_file(PathBuf::from("a.js").into(), "a".into());
+
+        assert!(Lrc::ptr_eq(&a.name, &a2.name));
+        assert!(!Lrc::ptr_eq(&a.name, &b.name));
+        assert_eq!(StableSourceFileId::new(&a), StableSourceFileId::new(&a2));
+
+        // The interner is kept by a thawed source map.
+        let sm = SourceMap::thaw(sm.freeze());
+        let a3 = sm.new_source_file(PathBuf::from("a.js").into(), "a".into());
+        assert!(Lrc::ptr_eq(&a.name, &a3.name));
+    }
+
+    #[test]
+    fn file_names_are_not_interned_by_default() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let a = sm.new_source_file(PathBuf::from("a.js").into(), "a".into());
+        let a2 = sm.new_source_file(PathBuf::from("a.js").into(), "a".into());
+
+        assert!(!Lrc::ptr_eq(&a.name, &a2.name));
+        assert_eq!(a.name, a2.name);
+    }
+
+    #[test]
+    fn span_to_lines_lossy() {
+        let sm = init_source_map();
+        let lines = |sp: Span| {
+            let lines = sm.span_to_lines_lossy(sp).unwrap();
+            (lines.file.start_pos, lines.lines)
+        };
+
+        let sp = Span::new(BytePos(2), BytePos(16), NO_EXPANSION);
+        let inverted = Span {
+            lo: sp.hi,
+            hi: sp.lo,
+            ..sp
+        };
+        assert!(sm.span_to_lines(inverted).is_err());
+        let expected = sm.span_to_lines(sp).unwrap();
+        assert_eq!(lines(inverted), (BytePos(0), expected.lines.clone()));
+        assert_eq!(lines(sp), (BytePos(0), expected.lines));
+
+        // `blork.rs` is 0..23 and `blork2.rs` is 25..48.
+        let sp = Span::new(BytePos(12), BytePos(30), NO_EXPANSION);
+        assert!(sm.span_to_lines(sp).is_err());
+        let expected = sm
+            .span_to_lines(Span::new(BytePos(12), BytePos(23), NO_EXPANSION))
+            .unwrap();
+        assert_eq!(lines(sp), (BytePos(0), expected.lines));
+
+        assert!(sm.span_to_lines_lossy(DUMMY_SP).is_none());
+        assert!(sm
+            .span_to_lines_lossy(Span::new(BytePos(49), BytePos(50), NO_EXPANSION))
+            .is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "serde-impl")]
+    fn from_serialized() {
+        let sm = init_source_map_mbc();
+        sm.new_source_file(PathBuf::from("empty.rs").into(), String::new());
+        sm.new_source_file(FileName::Custom("가나다".into()), "\t가\n나다\r\n".into());
+
+        let json = serde_json::to_string(&*sm.files()).unwrap();
+        let files: Vec<SourceFile> = serde_json::from_str(&json).unwrap();
+        let restored = SourceMap::from_serialized(FilePathMapping::empty(), files);
+
+        let files = sm.files().clone();
+        assert_eq!(restored.files().len(), files.len());
+        for f in files {
+            let r = restored.lookup_source_file(f.start_pos);
+            assert_eq!(
+                (&r.name, r.start_pos, r.end_pos, &r.src),
+                (&f.name, f.start_pos, f.end_pos, &f.src)
+            );
+            assert_eq!(r.lines, f.lines);
+            assert_eq!(r.multibyte_chars, f.multibyte_chars);
+            assert_eq!(r.non_narrow_chars, f.non_narrow_chars);
+            assert_eq!(StableSourceFileId::new(&r), StableSourceFileId::new(&f));
+
+            let offsets = f
+                .src
+                .char_indices()
+                .map(|(idx, _)| idx)
+                .chain(Some(f.src.len()));
+            for pos in offsets.map(|idx| f.start_pos + BytePos(idx as u32)) {
+                let (a, b) = (sm.lookup_char_pos(pos), restored.lookup_char_pos(pos));
+                assert_eq!(
+                    (a.line, a.col, a.col_display),
+                    (b.line, b.col, b.col_display)
+                );
+
+                let sp = Span::new(f.start_pos, pos, NO_EXPANSION);
+                assert_eq!(restored.span_to_snippet(sp), sm.span_to_snippet(sp));
+            }
+        }
+
+        let last = sm.files().last().unwrap().end_pos;
+        let added = restored.new_source_file(PathBuf::from("added.rs").into(), "a".into());
+        assert!(added.start_pos > last);
+    }
+
+    #[test]
+    #[cfg(all(feature = "serde-impl", feature = "sourcemap"))]
+    fn build_source_map_from_serialized() {
+        let sm = init_source_map_mbc();
+        let json = serde_json::to_string(&*sm.files()).unwrap();
+        let restored = SourceMap::from_serialized(
+            FilePathMapping::empty(),
+            serde_json::from_str(&json).unwrap(),
+        );
+
+        let mappings = vec![
+            (BytePos(0), LineCol { line: 0, col: 0 }),
+            (BytePos(9), LineCol { line: 0, col: 4 }),
+            (BytePos(34), LineCol { line: 1, col: 0 }),
+            (BytePos(57), LineCol { line: 1, col: 5 }),
+        ];
+        let build = |sm: &SourceMap| {
+            let mut buf = vec![];
+            sm.build_source_map(&mut mappings.clone())
+                .to_writer(&mut buf)
+                .unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        assert_eq!(build(&restored), build(&sm));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlap")]
+    fn from_serialized_overlapping_files() {
+        let a = SourceFile::new(
+            FileName::Anon,
+            false,
+            FileName::Anon,
+            "abc".into(),
+            BytePos(0),
+        );
+        let b = SourceFile::new(
+            FileName::Anon,
+            false,
+            FileName::Anon,
+            "abc".into(),
+            BytePos(3),
+        );
+        SourceMap::from_serialized(FilePathMapping::empty(), vec![b, a]);
+    }
+
+    #[test]
+    fn ascii_fast_path() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let ascii = sm.new_source_file(
+            PathBuf::from("ascii.rs").into(),
+            "fn main() {\n\tfoo();\n}\n".into(),
+        );
+        let mbc = sm.new_source_file(PathBuf::from("mbc.rs").into(), "'€'".into());
+        assert!(ascii.is_ascii());
+        assert!(!mbc.is_ascii());
+
+        let mut slow = (*ascii).clone();
+        slow.is_ascii = false;
+
+        for pos in (ascii.start_pos.0..=ascii.end_pos.0).map(BytePos) {
+            assert_eq!(
+                SourceMap::bytepos_to_file_charpos_with(&ascii, pos),
+                SourceMap::bytepos_to_file_charpos_with(&slow, pos)
+            );
+            let (a, b) = (
+                SourceMap::lookup_char_pos_in(ascii.clone(), pos),
+                SourceMap::lookup_char_pos_in(Lrc::new(slow.clone()), pos),
+            );
+            assert_eq!(
+                (a.line, a.col, a.col_display),
+                (b.line, b.col, b.col_display)
+            );
+            let sp = Span::new(ascii.start_pos, pos, NO_EXPANSION);
+            assert_eq!(sm.find_width_of_character_at_span(sp, true), 1);
+            assert_eq!(sm.find_width_of_character_at_span(sp, false), 1);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn build_source_map_with_skipped_pos() {
+        struct Config(Range<BytePos>);
+
+        impl SourceMapGenConfig for Config {
+            fn file_name_to_source(&self, f: &FileName) -> String {
+                f.to_string()
+            }
+
+            fn skip_pos(&self, pos: BytePos) -> bool {
+                self.0.contains(&pos)
+            }
+        }
+
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let prelude = sm.new_source_file(PathBuf::from("prelude.js").into(), "init();".into());
+        let bundle = sm.new_source_file(
+            PathBuf::from("bundle.js").into(),
+            "a();\nhelper();\nhelper2();\nb();\n".into(),
+        );
+        // Skips `helper();\nhelper2();\n`
+        let skipped = bundle.start_pos + BytePos(5)..bundle.start_pos + BytePos(26);
+
+        let mut mappings = vec![(prelude.start_pos, LineCol { line: 0, col: 0 })];
+        for (i, &line) in bundle.lines.iter().take(4).enumerate() {
+            for col in 0..3 {
+                mappings.push((
+                    line + BytePos(col),
+                    LineCol {
+                        line: i as u32 + 1,
+                        col,
+                    },
+                ));
+            }
+        }
+
+        let map = sm.build_source_map_with_config(&mut mappings, None, Config(skipped.clone()));
+        assert_eq!(map.get_source_count(), 2);
+        let tokens = map
+            .tokens()
+            .map(|t| (t.get_src_line(), t.get_src_col()))
+            .collect::<Vec<_>>();
+        // Lines 1 and 2 of bundle.js are skipped.
+        assert_eq!(
+            tokens,
+            vec![(0, 0), (0, 0), (0, 1), (0, 2), (3, 0), (3, 1), (3, 2)]
+        );
+
+        let config = Config(prelude.start_pos..skipped.end);
+        let map = sm.build_source_map_with_config(&mut mappings, None, config);
+        assert_eq!(map.get_source_count(), 1);
+        assert_eq!(map.get_source(0), Some("bundle.js"));
+        assert_eq!(map.get_token_count(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn extended_source_map_with_debug_id() {
+        let id = "85314830-023f-4cf1-a267-535f4e37bb17";
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let fm = sm.new_source_file(PathBuf::from("a.js").into(), "foo();".into());
+        let mappings = vec![(fm.start_pos, LineCol { line: 0, col: 0 })];
+        let build = |orig, debug_id: Option<&str>| {
+            let options = SourceMapOptions {
+                debug_id: debug_id.map(Into::into),
+                ..Default::default()
+            };
+            let map = sm.build_extended_source_map(
+                mappings.iter().copied(),
+                orig,
+                DefaultSourceMapGenConfig,
+                &options,
+            );
+            let mut buf = vec![];
+            map.to_writer(&mut buf).unwrap();
+            (
+                map,
+                serde_json::from_slice::<serde_json::Value>(&buf).unwrap(),
+            )
+        };
+
+        let (map, json) = build(None, Some(id));
+        assert_eq!(map.debug_id.as_deref(), Some(id));
+        assert_eq!(json["debugId"], id);
+        assert_eq!(json["sources"], serde_json::json!(["a.js"]));
+        assert_eq!(json["mappings"], sources_of(&map.map)["mappings"]);
+
+        let (map, json) = build(None, None);
+        assert_eq!(map.debug_id, None);
+        assert_eq!(json, sources_of(&map.map));
+
+        let orig = sourcemap::SourceMap::from_slice(
+            br#"{"version":3,"sources":["a.ts"],"names":[],"mappings":"AAAA"}"#,
+        )
+        .unwrap();
+        let (_, json) = build(Some(&orig), Some(id));
+        assert_eq!(json["debugId"], id);
+        assert_eq!(json["sources"], serde_json::json!(["a.ts", "a.js"]));
+
+        let (_, json) = build(None, Some("a\"b\\c\n"));
+        assert_eq!(json["debugId"], "a\"b\\c\n");
+
+        assert_eq!(
+            debug_id_comment(id, CommentKind::Line),
+            "//# debugId=85314830-023f-4cf1-a267-535f4e37bb17"
+        );
+        assert_eq!(
+            debug_id_comment(id, CommentKind::Block),
+            "/*# debugId=85314830-023f-4cf1-a267-535f4e37bb17 */"
+        );
+    }
+
+    #[test]
+    fn new_source_file_from_existing() {
+        let sm = init_source_map();
+        let old = sm.lookup_source_file(BytePos(30));
+        let sp = Span::new(BytePos(30), BytePos(35), NO_EXPANSION);
+        let snippet = sm.span_to_snippet(sp).unwrap();
+
+        let banner = "/* banner € */\n";
+        let (new, delta) = sm
+            .new_source_file_from_existing(&old, format!("{}{}", banner, old.src))
+            .unwrap();
+        assert_eq!(new.name, old.name);
+        assert_eq!(&new.src[banner.len()..], &**old.src);
+
+        let rebase = |pos: BytePos| BytePos((pos.0 as i64 + delta) as u32);
+        let rebased = Span::new(rebase(sp.lo), rebase(sp.hi), NO_EXPANSION);
+        assert_eq!(sm.span_to_snippet(rebased).unwrap(), snippet);
+
+        let (old_loc, new_loc) = (sm.lookup_char_pos(sp.lo), sm.lookup_char_pos(rebased.lo));
+        assert_eq!(new_loc.line, old_loc.line + 1);
+        assert_eq!(new_loc.col, old_loc.col);
+        assert_eq!(rebase(old.end_pos), new.end_pos);
+
+        // The BOM is not part of the prepended text.
+        let (new, bom_delta) = sm
+            .new_source_file_from_existing(&old, format!("\u{feff}{}{}", banner, old.src))
+            .unwrap();
+        assert!(new.had_bom);
+        assert_eq!(&new.src[..banner.len()], banner);
+        let rebased = Span::new(
+            BytePos((sp.lo.0 as i64 + bom_delta) as u32),
+            BytePos((sp.hi.0 as i64 + bom_delta) as u32),
+            NO_EXPANSION,
+        );
+        assert_eq!(sm.span_to_snippet(rebased).unwrap(), snippet);
+
+        assert!(sm
+            .new_source_file_from_existing(&old, banner.into())
+            .is_none());
+        assert!(sm
+            .new_source_file_from_existing(&old, format!("{}{}!", banner, old.src))
+            .is_none());
+    }
+
+    #[test]
+    fn src_hash() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let a = sm.new_source_file(PathBuf::from("a.js").into(), "foo(€);".into());
+        let b = sm.new_source_file(PathBuf::from("b.js").into(), "foo(€);".into());
+        let c = sm.new_source_file(PathBuf::from("a.js").into(), "foo(€)!".into());
+
+        assert_eq!(a.src_hash(), b.src_hash());
+        assert_ne!(a.src_hash(), c.src_hash());
+        assert_eq!(a.src_hash(), a.src_hash());
+
+        let name = FileName::from(PathBuf::from("a.js"));
+        let found = sm.source_file_by_name_and_hash(&name, c.src_hash());
+        assert_eq!(found.map(|f| f.start_pos), Some(c.start_pos));
+        let found = sm.source_file_by_name_and_hash(&name, a.src_hash());
+        assert_eq!(found.map(|f| f.start_pos), Some(a.start_pos));
+        let name = FileName::from(PathBuf::from("c.js"));
+        assert!(sm
+            .source_file_by_name_and_hash(&name, a.src_hash())
+            .is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn build_source_map_from_iter() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let mut mappings = vec![];
+        for i in 0..3 {
+            let src = "const foo = bar(baz, '€');\n".repeat(1000);
+            let fm = sm.new_source_file(FileName::Custom(format!("file{}.js", i)), src);
+
+            for (line, &lo) in fm.lines.iter().take(1000).enumerate() {
+                for &col in &[0, 6, 12, 16, 21, 25] {
+                    let line = (i * 1000 + line) as u32;
+                    mappings.push((lo + BytePos(col), LineCol { line, col }));
+                }
+            }
+        }
+
+        let from_iter = sm.build_source_map_from_iter(
+            mappings.iter().copied(),
+            None,
+            DefaultSourceMapGenConfig,
+        );
+        let from_vec = sm.build_source_map(&mut mappings);
+        assert_eq!(from_iter.get_token_count(), 18000);
+        assert_eq!(sources_of(&from_iter), sources_of(&from_vec));
+    }
+
+    #[test]
+    fn span_within_one_file() {
+        let sm = init_source_map();
+        let span = |lo, hi| Span::new(BytePos(lo), BytePos(hi), NO_EXPANSION);
+        let file_of = |sp| sm.span_within_one_file(sp).map(|f| (*f.name).clone());
+
+        assert_eq!(file_of(span(1, 5)), Some(PathBuf::from("blork.rs").into()));
+        assert_eq!(
+            file_of(span(25, 48)),
+            Some(PathBuf::from("blork2.rs").into())
+        );
+        // The byte after a file belongs to the file.
+        assert_eq!(
+            file_of(span(20, 23)),
+            Some(PathBuf::from("blork.rs").into())
+        );
+        assert_eq!(
+            file_of(span(23, 23)),
+            Some(PathBuf::from("blork.rs").into())
+        );
+
+        // Straddles the byte between blork.rs and empty.rs.
+        assert_eq!(file_of(span(20, 24)), None);
+        assert_eq!(file_of(span(23, 25)), None);
+        assert_eq!(file_of(span(24, 30)), None);
+        assert_eq!(file_of(span(1, 30)), None);
+
+        assert_eq!(file_of(DUMMY_SP), None);
+        assert_eq!(file_of(span(48, 100)), None);
+        assert_eq!(file_of(span(100, 101)), None);
+    }
+
+    #[test]
+    fn source_file_char_cursor() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        sm.new_source_file(PathBuf::from("before.js").into(), "€".into());
+        let src = "a€b💩\nc가d\n\n💩💩x\t€";
+        let fm = sm.new_source_file(PathBuf::from("cursor.js").into(), src.into());
+
+        let expected = src
+            .char_indices()
+            .map(|(idx, _)| idx)
+            .chain(Some(src.len()))
+            .map(|idx| {
+                let line_start = src[..idx].rfind('\n').map_or(0, |i| i + 1);
+                (
+                    fm.start_pos + BytePos(idx as u32),
+                    CharPos(src[..idx].chars().count()),
+                    src[line_start..idx].encode_utf16().count() as u32,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut cursor = SourceFileCharCursor::new(&fm);
+        for &(pos, char_pos, utf16_col) in expected.iter().chain(expected.iter().rev()) {
+            assert_eq!(cursor.char_pos(pos), char_pos, "{:?}", pos);
+            assert_eq!(cursor.utf16_col(pos), utf16_col, "{:?}", pos);
+            assert_eq!(SourceMap::bytepos_to_file_charpos_with(&fm, pos), char_pos);
+        }
+
+        // Jumps around.
+        let mut cursor = SourceFileCharCursor::new(&fm);
+        for &i in &[12, 3, 7, 0, 14, 14, 1, 9] {
+            let (pos, char_pos, utf16_col) = expected[i];
+            assert_eq!(cursor.utf16_col(pos), utf16_col, "{:?}", pos);
+            assert_eq!(cursor.char_pos(pos), char_pos, "{:?}", pos);
+        }
+
+        let empty = sm.new_source_file(PathBuf::from("empty.js").into(), String::new());
+        let mut cursor = SourceFileCharCursor::new(&empty);
+        assert_eq!(cursor.utf16_col(empty.start_pos), 0);
+        assert_eq!(cursor.char_pos(empty.start_pos), CharPos(0));
+    }
+
+    #[test]
+    fn on_file_added() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let lines = std::sync::Arc::new(AtomicUsize::new(0));
+
+        let first = {
+            let seen = seen.clone();
+            sm.on_file_added(Box::new(move |f| seen.lock().unwrap().push(f.start_pos)))
+        };
+        let second = {
+            let lines = lines.clone();
+            sm.on_file_added(Box::new(move |f| {
+                lines.fetch_add(f.count_lines(), SeqCst);
+            }))
+        };
+        assert_ne!(first, second);
+
+        let a = sm.new_source_file(PathBuf::from("a.js").into(), "a;\nb;".into());
+        let b = sm.new_anon_source_file("c;".into());
+        assert_eq!(*seen.lock().unwrap(), vec![a.start_pos, b.start_pos]);
+        assert_eq!(lines.load(SeqCst), 3);
+
+        assert!(sm.unsubscribe(first));
+        assert!(!sm.unsubscribe(first));
+        sm.new_source_file(PathBuf::from("c.js").into(), "d;".into());
+        assert_eq!(seen.lock().unwrap().len(), 2);
+        assert_eq!(lines.load(SeqCst), 4);
+    }
+
+    #[test]
+    fn on_file_added_panic() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let id = sm.on_file_added(Box::new(|f| {
+            if f.src.is_empty() {
+                panic!("empty file")
+            }
+        }));
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        {
+            let seen = seen.clone();
+            sm.on_file_added(Box::new(move |f| seen.lock().unwrap().push(f.start_pos)));
+        }
+
+        let added = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            sm.new_source_file(PathBuf::from("a.js").into(), String::new())
+        }));
+        assert_eq!(
+            added.unwrap_err().downcast_ref::<&str>(),
+            Some(&"empty file")
+        );
+        let a = sm.get_source_file(&PathBuf::from("a.js").into()).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![a.start_pos]);
+
+        let b = sm.new_source_file(PathBuf::from("b.js").into(), "b".into());
+        assert_eq!(sm.lookup_char_pos(b.start_pos).file.start_pos, b.start_pos);
+        assert!(sm.unsubscribe(id));
+        sm.new_source_file(PathBuf::from("c.js").into(), String::new());
+    }
+
+    #[test]
+    #[cfg(feature = "concurrent")]
+    fn on_file_added_from_multiple_threads() {
+        let sm = SourceMap::with_file_loader(
+            Box::new(MemoryFileLoader::default()),
+            FilePathMapping::empty(),
+        );
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        {
+            let seen = seen.clone();
+            sm.on_file_added(Box::new(move |f| seen.lock().unwrap().push(f.start_pos)));
+        }
+
+        let load = |prefix: &str| {
+            for i in 0..100 {
+                let path = PathBuf::from(format!("{}{}.js", prefix, i));
+                sm.load_file(&path).unwrap();
+            }
+        };
+        rayon::join(|| load("a"), || load("b"));
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        let files = sm.files().iter().map(|f| f.start_pos).collect::<Vec<_>>();
+        assert_eq!(seen, files);
+    }
+
+    #[test]
+    #[cfg(feature = "concurrent")]
+    fn load_file_cached_from_multiple_threads() {
+        let sm = SourceMap::with_file_loader(
+            Box::new(MemoryFileLoader::default()),
+            FilePathMapping::empty(),
+        );
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        {
+            let seen = seen.clone();
+            sm.on_file_added(Box::new(move |f| seen.lock().unwrap().push(f.start_pos)));
+        }
+
+        let load = || {
+            (0..100)
+                .map(|i| {
+                    let f = sm
+                        .load_file_cached(Path::new(&format!("{}.js", i)))
+                        .unwrap();
+                    // The file is in the source map when it's returned.
+                    assert_eq!(sm.lookup_char_pos(f.start_pos).file.start_pos, f.start_pos);
+                    f
+                })
+                .collect::<Vec<_>>()
+        };
+        let (a, b) = rayon::join(load, load);
+
+        assert!(a.iter().zip(&b).all(|(a, b)| Lrc::ptr_eq(a, b)));
+        // Files loaded by the thread which lost a race are not added.
+        assert_eq!(sm.files().len(), 100);
+        assert_eq!(seen.lock().unwrap().len(), 100);
+    }
+
+    #[test]
+    #[cfg(feature = "concurrent")]
+    fn frozen_source_map_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<FrozenSourceMap>();
+    }
+
+    #[test]
+    fn is_same_file() {
+        let sm = init_source_map();
+
+        assert!(sm.is_same_file(BytePos(1), BytePos(20)));
+        assert!(sm.is_same_file(BytePos(30), BytePos(25)));
+        // blork.rs and blork2.rs
+        assert!(!sm.is_same_file(BytePos(1), BytePos(30)));
+        assert!(!sm.is_same_file(BytePos(30), BytePos(1)));
+        // The byte after blork.rs and empty.rs
+        assert!(sm.is_same_file(BytePos(0), BytePos(23)));
+        assert!(!sm.is_same_file(BytePos(23), BytePos(24)));
+
+        assert!(!sm.is_same_file(BytePos(30), BytePos(100)));
+        assert!(!sm.is_same_file(BytePos(100), BytePos(100)));
+    }
+
+    #[test]
+    fn changed_line_ranges() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let file = |src: &str| sm.new_source_file(FileName::Anon, src.into());
+        let changed = |old: &str, new: &str| super::changed_line_ranges(&file(old), &file(new));
+
+        let old = "a\nb\nc\nd";
+        assert_eq!(changed(old, old), vec![]);
+        // Insertion
+        assert_eq!(changed(old, "a\nb\nx\ny\nc\nd"), vec![2..4]);
+        assert_eq!(changed(old, "x\na\nb\nc\nd"), vec![0..1]);
+        assert_eq!(changed(old, "a\nb\nc\nd\ne"), vec![4..5]);
+        // Deletion
+        assert_eq!(changed(old, "a\nd"), vec![1..1]);
+        assert_eq!(changed(old, "b\nc\nd"), vec![0..0]);
+        // In-place edit
+        assert_eq!(changed(old, "a\nB\nc\nd"), vec![1..2]);
+        assert_eq!(changed(old, "a\nb\nc\nd!"), vec![3..4]);
+        // Repeated lines
+        assert_eq!(changed("a\na\na", "a\na"), vec![2..2]);
+    }
+
+    #[test]
+    fn line_hashes() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let a = sm.new_source_file(FileName::Anon, "foo\n€\nfoo".into());
+        let b = sm.new_source_file(FileName::Anon, "€\nfoo\n".into());
+
+        assert_eq!(a.line_hashes().len(), 3);
+        assert_eq!(a.line_hashes()[0], a.line_hashes()[2]);
+        assert_ne!(a.line_hashes()[0], a.line_hashes()[1]);
+        assert_eq!(&a.line_hashes()[1..], &b.line_hashes()[..2]);
+        assert_eq!(b.line_hashes().len(), 2);
+    }
+
+    #[test]
+    fn span_extend_to_prev_str() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let extend = |src: &str, accept_newlines: bool| {
+            let fm = sm.new_source_file(FileName::Anon, src.into());
+            let lo = fm.start_pos + BytePos(src.rfind('(').unwrap() as u32);
+            let sp = Span::new(lo, lo + BytePos(1), NO_EXPANSION);
+            let sp = sm.span_extend_to_prev_str(sp, "fn", accept_newlines);
+            sm.span_to_snippet(sp).unwrap()
+        };
+
+        assert_eq!(extend("fn my_fn()", false), "my_fn(");
+        assert_eq!(extend("fn\tmy_fn()", false), "my_fn(");
+        assert_eq!(extend("fn \u{a0} my_fn()", false), "my_fn(");
+        assert_eq!(extend("\u{a0}\u{a0}fn\u{a0}가나()", false), "가나(");
+        // The pattern followed by a line break
+        assert_eq!(extend("fn\nmy_fn()", false), "my_fn(");
+        assert_eq!(extend("fn\r\nmy_fn()", false), "my_fn(");
+        assert_eq!(extend("pub fn\r\n    my_fn()", false), "my_fn(");
+        // `fn` in `my_fn` is not delimited.
+        assert_eq!(extend("let x = my_fn()", false), "(");
+        assert_eq!(extend("fn a() {}\r\nmy_fn()", false), "(");
+        assert_eq!(extend("fn a() {}\r\nmy_fn()", true), "a() {}\r\nmy_fn(");
+    }
+
+    #[test]
+    fn first_non_whitespace_pos() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let src = "\tfoo \u{a0}\u{3000}bar\r\n \t\n";
+        let fm = sm.new_source_file(FileName::Anon, src.into());
+        let span = |lo: usize, hi: usize| {
+            Span::new(
+                fm.start_pos + BytePos(lo as u32),
+                fm.start_pos + BytePos(hi as u32),
+                NO_EXPANSION,
+            )
+        };
+        let bar = src.find("bar").unwrap();
+
+        assert_eq!(
+            sm.first_non_whitespace_pos(span(0, 4)),
+            Some(fm.start_pos + BytePos(1))
+        );
+        assert_eq!(
+            sm.first_non_whitespace_pos(span(2, 4)),
+            Some(fm.start_pos + BytePos(2))
+        );
+        // Non-breaking and ideographic spaces
+        assert_eq!(
+            sm.first_non_whitespace_pos(span(4, src.len())),
+            Some(fm.start_pos + BytePos(bar as u32))
+        );
+        assert_eq!(sm.first_non_whitespace_pos(span(4, bar)), None);
+        assert_eq!(sm.first_non_whitespace_pos(span(bar + 3, src.len())), None);
+        assert_eq!(sm.first_non_whitespace_pos(span(1, 1)), None);
+        assert_eq!(sm.first_non_whitespace_pos(DUMMY_SP), None);
+    }
+
+    #[test]
+    fn mapping_drifts_of_quote_switched_literal() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let src = "let a = 'it\\'s', b = 'x'; foo(a, 'y');";
+        let fm = sm.new_source_file(PathBuf::from("a.js").into(), src.into());
+        let generated = "let a=\"it's\",b=\"x\";foo(a,\"y\");";
+
+        let pos = |s: &str| fm.start_pos + BytePos(src.find(s).unwrap() as u32);
+        let col = |s: &str| LineCol {
+            line: 0,
+            col: generated.find(s).unwrap() as u32,
+        };
+        let mut mappings = vec![
+            (pos("a ="), col("a=")),
+            (pos("'it"), col("\"it")),
+            (pos("b ="), col("b=")),
+            (pos("'x'"), col("\"x\"")),
+            (pos("foo"), col("foo")),
+            (pos("'y'"), col("\"y\"")),
+        ];
+        let expected = vec![MappingDrift {
+            pos: pos("'it"),
+            generated: col("\"it"),
+            original_len: 7,
+            generated_len: 6,
+        }];
+
+        assert_eq!(sm.mapping_drifts(&mappings, generated), expected);
+
+        // The order of mappings doesn't matter.
+        mappings.reverse();
+        assert_eq!(sm.mapping_drifts(&mappings, generated), expected);
+    }
+
+    #[test]
+    fn generate_fn_name_span() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let src = "fn foo(a: T) {}\nfunction 가나(b: U) {}\nfn (c: V) {}\nfn bar";
+        let fm = sm.new_source_file(PathBuf::from("a.js").into(), src.into());
+        let span_of = |s: &str| {
+            let lo = fm.start_pos + BytePos(src.find(s).unwrap() as u32);
+            Span::new(lo, lo + BytePos(s.len() as u32), NO_EXPANSION)
+        };
+        let name = |span: Option<Span>| span.map(|span| sm.span_to_snippet(span).unwrap());
+
+        assert_eq!(
+            name(sm.generate_fn_name_span(span_of("T"), "fn")),
+            Some("foo".into())
+        );
+        assert_eq!(
+            name(sm.generate_fn_name_span(span_of("U"), "function")),
+            Some("가나".into())
+        );
+        // No identifier after the keyword.
+        assert_eq!(sm.generate_fn_name_span(span_of("V"), "fn"), None);
+        // The identifier runs until the end of the file.
+        assert_eq!(sm.generate_fn_name_span(span_of("ar"), "fn"), None);
+        // The keyword is at the end of the file.
+        let fm = sm.new_source_file(PathBuf::from("b.js").into(), "export function".into());
+        let eof = Span::new(fm.end_pos, fm.end_pos, NO_EXPANSION);
+        assert_eq!(sm.generate_fn_name_span(eof, "function"), None);
+    }
+
+    #[test]
+    fn generate_local_type_param_snippet() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let src = [
+            "fn foo(a: T) {}",
+            "fn bar<F: Fn() -> u32>(f: F, b: U) {}",
+            "function baz<T extends () => void>(c: V) {}",
+            "fn 가나 (d: W) {}",
+            "fn qux<T(e: X) {}",
+            "fn (f: Y) {}",
+            "fn quux<T>",
+        ]
+        .join("\n");
+        let fm = sm.new_source_file(PathBuf::from("a.ts").into(), src.clone());
+        // The span of the first character of `s`.
+        let span_of = |s: &str| {
+            let lo = fm.start_pos + BytePos(src.find(s).unwrap() as u32);
+            Span::new(lo, lo + BytePos(1), NO_EXPANSION)
+        };
+        let suggest = |s: &str, keyword: &str| {
+            sm.generate_local_type_param_snippet(span_of(s), keyword)
+                .map(|(span, snippet)| (sm.span_to_snippet(span).unwrap(), snippet))
+        };
+
+        assert_eq!(suggest("T)", "fn"), Some(("foo".into(), "foo<T>".into())));
+        assert_eq!(
+            suggest("U)", "fn"),
+            Some((
+                "bar<F: Fn() -> u32>".into(),
+                "bar<F: Fn() -> u32, U>".into()
+            ))
+        );
+        assert_eq!(
+            suggest("V)", "function"),
+            Some((
+                "baz<T extends () => void>".into(),
+                "baz<T extends () => void, V>".into()
+            ))
+        );
+        assert_eq!(suggest("W)", "fn"), Some(("가나".into(), "가나<W>".into())));
+        // Unclosed generics.
+        assert_eq!(suggest("X)", "fn"), None);
+        // No identifier after the keyword.
+        assert_eq!(suggest("Y)", "fn"), None);
+        // No parameters.
+        assert_eq!(suggest("T>", "fn"), None);
+        // The keyword is not found.
+        assert_eq!(suggest("W)", "function"), None);
+    }
+
+    #[test]
+    fn def_span_js() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let head = |src: &str| {
+            let fm = sm.new_source_file(PathBuf::from("a.ts").into(), src.into());
+            let sp = Span::new(fm.start_pos, fm.end_pos, NO_EXPANSION);
+            sm.span_to_snippet(sm.def_span_js(sp)).unwrap()
+        };
+
+        assert_eq!(head("const f = ({a}) => {}"), "const f = ({a}) =>");
+        assert_eq!(
+            head("function g(x: {y: number}) {}"),
+            "function g(x: {y: number})"
+        );
+        assert_eq!(
+            head("function h(a = '{', b = `${ {c: 1}.c }{`) { return a; }"),
+            "function h(a = '{', b = `${ {c: 1}.c }{`)"
+        );
+        assert_eq!(
+            head("function i(a = \"\\\"{\") {}"),
+            "function i(a = \"\\\"{\")"
+        );
+        assert_eq!(head("class A {}"), "class A");
+        // Multiple lines.
+        assert_eq!(head("function j(\n) {}"), "function j(\n) {}");
+    }
+
+    #[test]
+    fn def_span_until() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let src = "const f = (a = 1) => a;";
+        let fm = sm.new_source_file(PathBuf::from("a.js").into(), src.into());
+        let sp = Span::new(fm.start_pos, fm.end_pos, NO_EXPANSION);
+
+        let stop_at_eq = |c: char, state: &DefSpanState| c == '=' && state.depth() == 1;
+        assert_eq!(
+            sm.span_to_snippet(sm.def_span_until(sp, stop_at_eq))
+                .unwrap(),
+            "const f = (a"
+        );
+        assert_eq!(sm.def_span_until(sp, |_, _| false), sp);
+    }
+
+    #[test]
+    fn try_span_to_unmapped_path() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let fm = sm.new_source_file(PathBuf::from("a.js").into(), "foo".into());
+        let sp = Span::new(fm.start_pos, fm.end_pos, NO_EXPANSION);
+        assert_eq!(
+            sm.try_span_to_unmapped_path(sp),
+            Some(PathBuf::from("a.js").into())
+        );
+        assert_eq!(sm.try_span_to_unmapped_path(sp), fm.unmapped_path);
+
+        // Files imported from elsewhere may lack the unmapped path.
+        let mut imported = SourceFile::new(
+            FileName::Custom("imported".into()),
+            false,
+            FileName::Custom("imported".into()),
+            "bar".into(),
+            BytePos(0),
+        );
+        imported.unmapped_path = None;
+        let sm = SourceMap::from_serialized(FilePathMapping::empty(), vec![imported]);
+        let sp = Span::new(BytePos(0), BytePos(3), NO_EXPANSION);
+        assert_eq!(sm.try_span_to_unmapped_path(sp), None);
+
+        let outside = Span::new(BytePos(100), BytePos(101), NO_EXPANSION);
+        assert_eq!(sm.try_span_to_unmapped_path(outside), None);
+    }
+
+    #[test]
+    fn base_dir() {
+        let base = Path::new("/project");
+        let sm = SourceMap::new(FilePathMapping::empty()).with_base_dir(base.into());
+        let inside = sm.new_source_file(PathBuf::from("/project/src/a.js").into(), "a;".into());
+        let outside = sm.new_source_file(PathBuf::from("/other/b.js").into(), "b;".into());
+        let custom = sm.new_source_file(FileName::Custom("c.js".into()), "c;".into());
+        let span = |f: &SourceFile| Span::new(f.start_pos, f.start_pos + BytePos(1), NO_EXPANSION);
+
+        assert_eq!(sm.base_dir(), Some(base));
+        assert_eq!(
+            sm.relativize(Path::new("/project/src/a.js")),
+            Path::new("src/a.js")
+        );
+        assert_eq!(
+            sm.relativize(Path::new("/project-2/a.js")),
+            Path::new("/project-2/a.js")
+        );
+
+        assert_eq!(sm.span_to_string(span(&inside)), "src/a.js:1:1: 1:2");
+        assert_eq!(sm.span_to_string(span(&outside)), "/other/b.js:1:1: 1:2");
+        assert_eq!(sm.span_to_string(span(&custom)), "<c.js>:1:1: 1:2");
+        assert_eq!(sm.mk_substr_filename(span(&inside)), "<src/a.js:1:1>");
+        assert_eq!(sm.mk_substr_filename(span(&outside)), "</other/b.js:1:1>");
+
+        // Only rendering is affected.
+        assert_eq!(*inside.name, PathBuf::from("/project/src/a.js").into());
+        assert_eq!(
+            sm.span_to_unmapped_path(span(&inside)),
+            PathBuf::from("/project/src/a.js").into()
+        );
+
+        let thawed = SourceMap::thaw(sm.freeze());
+        assert_eq!(thawed.span_to_string(span(&inside)), "src/a.js:1:1: 1:2");
+    }
+
+    #[test]
+    fn doctest_offset_col() {
+        let host = FileName::Real(PathBuf::from("index.html"));
+        let sm = SourceMap::new(FilePathMapping::empty()).with_doctest_offset(host.clone(), 9, 4);
+        let src = "let a;\nlet b;";
+        let fm = sm.new_source_file(FileName::Anon, src.into());
+        let span_of = |s: &str| {
+            let lo = fm.start_pos + BytePos(src.find(s).unwrap() as u32);
+            Span::new(lo, lo + BytePos(s.len() as u32), NO_EXPANSION)
+        };
+
+        let loc = sm.lookup_char_pos_adj(span_of("a").lo());
+        assert_eq!(loc.filename, host);
+        assert_eq!((loc.line, loc.col), (10, CharPos(8)));
+        assert_eq!(sm.span_to_string(span_of("a")), "index.html:10:9: 10:10");
+        // Only the first line is indented.
+        assert_eq!(sm.span_to_string(span_of("b")), "index.html:11:5: 11:6");
+
+        assert_eq!(sm.doctest_offset_col(1, 2), 6);
+        assert_eq!(sm.doctest_offset_col(2, 2), 2);
+        assert_eq!(SourceMap::default().doctest_offset_col(1, 2), 2);
+    }
+
+    #[test]
+    fn span_to_mapped_filename() {
+        let sm = SourceMap::new(FilePathMapping::new(vec![(
+            PathBuf::from("/builder-a"),
+            PathBuf::from("/src"),
+        )]));
+        let fm = sm.new_source_file(PathBuf::from("/builder-a/x.js").into(), "x;".into());
+        let anon = sm.new_source_file(FileName::Anon, "y;".into());
+        let span = |f: &SourceFile| Span::new(f.start_pos, f.end_pos, NO_EXPANSION);
+        let mapping =
+            FilePathMapping::new(vec![(PathBuf::from("/builder-a"), PathBuf::from("/cache"))]);
+
+        assert_eq!(
+            *sm.span_to_mapped_filename(span(&fm), &mapping),
+            PathBuf::from("/cache/x.js").into()
+        );
+        assert_eq!(
+            *sm.span_to_mapped_filename(span(&fm), &FilePathMapping::empty()),
+            PathBuf::from("/builder-a/x.js").into()
+        );
+        assert_eq!(
+            *sm.span_to_mapped_filename(span(&anon), &mapping),
+            FileName::Anon
+        );
+        // The mapping of the source map is still applied to the name.
+        assert_eq!(
+            sm.span_to_filename(span(&fm)),
+            PathBuf::from("/src/x.js").into()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn build_source_map_with_unmapped_path() {
+        struct Config(FilePathMapping);
+
+        impl SourceMapGenConfig for Config {
+            fn file_name_to_source(&self, f: &FileName) -> String {
+                f.to_string()
+            }
+
+            fn file_name_to_source_with_unmapped(
+                &self,
+                f: &FileName,
+                unmapped_path: Option<&FileName>,
+            ) -> String {
+                match unmapped_path {
+                    Some(FileName::Real(path)) => {
+                        self.0.map_prefix(path.clone()).0.display().to_string()
+                    }
+                    _ => f.to_string(),
+                }
+            }
+        }
+
+        let sm = SourceMap::new(FilePathMapping::new(vec![(
+            PathBuf::from("/builder-a"),
+            PathBuf::from("/src"),
+        )]));
+        let fm = sm.new_source_file(PathBuf::from("/builder-a/x.js").into(), "x;".into());
+        let mut mappings = vec![(fm.start_pos, LineCol { line: 0, col: 0 })];
+
+        let map = sm.build_source_map_with_config(&mut mappings, None, DefaultSourceMapGenConfig);
+        assert_eq!(map.get_source(0), Some("/src/x.js"));
+
+        let config = Config(FilePathMapping::new(vec![(
+            PathBuf::from("/builder-a"),
+            PathBuf::from("/cache"),
+        )]));
+        let map = sm.build_source_map_with_config(&mut mappings, None, &config);
+        assert_eq!(map.get_source(0), Some("/cache/x.js"));
+    }
+
+    #[test]
+    fn load_file_metadata_only() {
+        let loader = MemoryFileLoader::default();
+        let reads = loader.reads.clone();
+        let sm = SourceMap::with_file_loader(Box::new(loader), FilePathMapping::empty())
+            .with_loaded_sources_capacity(1);
+
+        let a = sm.load_file_metadata_only(Path::new("a.js")).unwrap();
+        let b = sm.load_file_metadata_only(Path::new("b.js")).unwrap();
+        assert_eq!(reads.load(SeqCst), 2);
+        assert!(!a.has_src());
+        assert_eq!(a.byte_length(), 8);
+
+        let loaded = sm.load_file(Path::new("a.js")).unwrap();
+        assert_eq!(a.src_hash(), loaded.src_hash());
+        assert_eq!(a.line_hashes(), loaded.line_hashes());
+        reads.store(0, SeqCst);
+
+        // Lookups don't read the file.
+        let span = |f: &SourceFile| Span::new(f.start_pos + BytePos(3), f.end_pos, NO_EXPANSION);
+        let loc = sm.lookup_char_pos(a.start_pos + BytePos(3));
+        assert_eq!((loc.line, loc.col), (1, CharPos(3)));
+        assert_eq!(sm.span_to_string(span(&b)), "b.js:1:4: 1:9");
+        assert_eq!(reads.load(SeqCst), 0);
+
+        assert_eq!(sm.span_to_snippet(span(&a)).unwrap(), "a.js\n");
+        assert_eq!(sm.span_to_snippet(span(&a)).unwrap(), "a.js\n");
+        assert_eq!(reads.load(SeqCst), 1);
+
+        let snippets = sm.snippets_of_spans(&[span(&b), span(&b)]);
+        assert_eq!(&**snippets[0].as_ref().unwrap(), "b.js\n");
+        assert_eq!(&**snippets[1].as_ref().unwrap(), "b.js\n");
+        assert_eq!(reads.load(SeqCst), 2);
+
+        // The source of `a` was dropped to load `b`.
+        sm.with_snippets_of_spans(&[span(&a)], |_, snippet| {
+            assert_eq!(snippet.unwrap(), "a.js\n")
+        });
+        assert_eq!(reads.load(SeqCst), 3);
+    }
+
+    #[test]
+    fn load_file_metadata_only_matches_load_file() {
+        struct Loader(&'static str);
+
+        impl FileLoader for Loader {
+            fn file_exists(&self, _: &Path) -> bool {
+                true
+            }
+
+            fn abs_path(&self, path: &Path) -> Option<PathBuf> {
+                Some(path.to_path_buf())
+            }
+
+            fn read_file(&self, _: &Path) -> io::Result<String> {
+                Ok(self.0.into())
+            }
+
+            fn open_file(&self, _: &Path) -> io::Result<Box<dyn BufRead>> {
+                // A tiny buffer makes lines span several reads.
+                Ok(Box::new(io::BufReader::with_capacity(2, self.0.as_bytes())))
+            }
+        }
+
+        for src in &[
+            "",
+            "\u{feff}",
+            "\u{feff}a;\nb;",
+            "let 가 = 'ｘ';\n\tb;\n",
+            "\n\n€\r\n",
+        ] {
+            let sm = SourceMap::with_file_loader(Box::new(Loader(src)), FilePathMapping::empty());
+            let meta = sm.load_file_metadata_only(Path::new("a.js")).unwrap();
+            let full = sm.load_file(Path::new("a.js")).unwrap();
+
+            let offsets = |f: &SourceFile, pos: &[BytePos]| {
+                pos.iter().map(|&p| p - f.start_pos).collect::<Vec<_>>()
+            };
+            assert_eq!(meta.byte_length(), full.byte_length(), "{:?}", src);
+            assert_eq!(offsets(&meta, &meta.lines), offsets(&full, &full.lines));
+            assert_eq!(
+                meta.multibyte_chars
+                    .iter()
+                    .map(|c| (c.pos - meta.start_pos, c.bytes))
+                    .collect::<Vec<_>>(),
+                full.multibyte_chars
+                    .iter()
+                    .map(|c| (c.pos - full.start_pos, c.bytes))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                offsets(
+                    &meta,
+                    &meta
+                        .non_narrow_chars
+                        .iter()
+                        .map(|c| c.pos())
+                        .collect::<Vec<_>>()
+                ),
+                offsets(
+                    &full,
+                    &full
+                        .non_narrow_chars
+                        .iter()
+                        .map(|c| c.pos())
+                        .collect::<Vec<_>>()
+                )
+            );
+            assert_eq!(meta.had_bom, full.had_bom);
+            assert_eq!(meta.is_ascii, full.is_ascii);
+            assert_eq!(meta.src_hash(), full.src_hash());
+            assert_eq!(meta.line_hashes(), full.line_hashes());
+
+            let span = Span::new(meta.start_pos, meta.end_pos, NO_EXPANSION);
+            let lines = sm.span_to_lines(span).unwrap().lines;
+            let full_span = Span::new(full.start_pos, full.end_pos, NO_EXPANSION);
+            let full_lines = sm.span_to_lines(full_span).unwrap().lines;
+            assert_eq!(lines.len(), full_lines.len());
+            for (line, full_line) in lines.iter().zip(&full_lines) {
+                assert_eq!(line.line_index, full_line.line_index);
+                assert_eq!(line.start_col, full_line.start_col);
+                assert_eq!(line.end_col, full_line.end_col);
+            }
+        }
+    }
+
+    #[test]
+    fn for_each_file_reentrant() {
+        let loader = MemoryFileLoader::default();
+        let sm = SourceMap::with_file_loader(Box::new(loader), FilePathMapping::empty());
+        sm.load_file(Path::new("a.js")).unwrap();
+        sm.load_file(Path::new("b.js")).unwrap();
+
+        let mut visited = vec![];
+        sm.for_each_file(|f| {
+            visited.push(f.name.to_string());
+            let fm = sm.load_file(Path::new(&format!("{}.map", f.name))).unwrap();
+            assert_eq!(sm.lookup_char_pos(fm.start_pos).file.name, fm.name);
+        });
+
+        assert_eq!(visited, vec!["a.js", "b.js"]);
+        assert_eq!(sm.files_snapshot().len(), 4);
+        assert_eq!(sm.files_snapshot()[3].name.to_string(), "b.js.map");
+    }
+
+    #[test]
+    fn classify_pos() {
+        let sm = init_source_map();
+        assert!(
+            matches!(sm.classify_pos(BytePos(0)), PosKind::InFile(f) if f.start_pos == BytePos(0))
+        );
+        assert!(
+            matches!(sm.classify_pos(BytePos(23)), PosKind::InFile(f) if f.start_pos == BytePos(0))
+        );
+        assert!(
+            matches!(sm.classify_pos(BytePos(24)), PosKind::InFile(f) if f.start_pos == BytePos(24))
+        );
+        assert!(
+            matches!(sm.classify_pos(BytePos(25)), PosKind::InFile(f) if f.start_pos == BytePos(25))
+        );
+        assert!(matches!(sm.classify_pos(BytePos(49)), PosKind::PastEnd));
+        assert!(matches!(
+            sm.classify_pos(BytePos(u32::MAX)),
+            PosKind::Reserved
+        ));
+        assert!(matches!(
+            SourceMap::default().classify_pos(BytePos(0)),
+            PosKind::Dummy
+        ));
+
+        // Leave gaps before and between the files.
+        let file = |name: &str, start_pos: u32| {
+            SourceFile::new(
+                Lrc::new(PathBuf::from(name).into()),
+                false,
+                PathBuf::from(name).into(),
+                "first\nsecond".into(),
+                BytePos(start_pos),
+            )
+        };
+        let sm = SourceMap::from_serialized(
+            FilePathMapping::empty(),
+            vec![file("a.rs", 12), file("b.rs", 30)],
+        );
+        assert!(matches!(sm.classify_pos(BytePos(0)), PosKind::Dummy));
+        assert!(matches!(sm.classify_pos(BytePos(11)), PosKind::BeforeFirst));
+        assert!(
+            matches!(sm.classify_pos(BytePos(24)), PosKind::InFile(f) if f.start_pos == BytePos(12))
+        );
+        match sm.classify_pos(BytePos(25)) {
+            PosKind::FileGap { before, after } => {
+                assert_eq!(before.start_pos, BytePos(12));
+                assert_eq!(after.start_pos, BytePos(30));
+            }
+            kind => panic!("{:?}", kind),
+        }
+        assert!(matches!(
+            sm.classify_pos(BytePos(29)),
+            PosKind::FileGap { .. }
+        ));
+        assert!(matches!(sm.classify_pos(BytePos(43)), PosKind::PastEnd));
+        assert!(sm.try_lookup_source_file(BytePos(25)).is_none());
+
+        let mappings = [
+            (BytePos(12), LineCol { line: 0, col: 0 }),
+            (BytePos(25), LineCol { line: 0, col: 6 }),
+            (BytePos(30), LineCol { line: 1, col: 0 }),
+            (BytePos(50), LineCol { line: 1, col: 6 }),
+            (BytePos(u32::MAX), LineCol { line: 1, col: 7 }),
+        ];
+        let unresolved = sm.unresolved_mapping_positions(&mappings);
+        assert_eq!(unresolved.len(), 2);
+        assert!(matches!(
+            unresolved[0],
+            (BytePos(25), PosKind::FileGap { .. })
+        ));
+        assert!(matches!(unresolved[1], (BytePos(50), PosKind::PastEnd)));
+
+        #[cfg(feature = "sourcemap")]
+        {
+            let map = sm.build_source_map(&mut mappings.to_vec());
+            assert_eq!(map.get_token_count(), 2);
+        }
+    }
+
+    #[test]
+    fn span_to_snippet_all_boundaries() {
+        let sm = init_source_map();
+        let sp = |lo: u32, hi: u32| Span::new(BytePos(lo), BytePos(hi), NO_EXPANSION);
+        let files = sm.files().clone();
+
+        for lo in 0..50 {
+            for hi in lo..50 {
+                let file = files
+                    .iter()
+                    .find(|f| f.start_pos <= BytePos(lo) && BytePos(hi) <= f.end_pos);
+                let res = sm.span_to_snippet(sp(lo, hi));
+                match file {
+                    Some(f) => {
+                        let lo = (BytePos(lo) - f.start_pos).to_usize();
+                        let hi = (BytePos(hi) - f.start_pos).to_usize();
+                        assert_eq!(res.unwrap(), f.src[lo..hi]);
+                    }
+                    // The span is outside of files or crosses a file boundary.
+                    None => assert!(
+                        matches!(
+                            res,
+                            Err(SpanSnippetError::IllFormedSpan(..))
+                                | Err(SpanSnippetError::DistinctSources(..))
+                        ),
+                        "{}..{}: {:?}",
+                        lo,
+                        hi,
+                        res
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn new_imported_source_file() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let lines = vec![BytePos(0), BytePos(10), BytePos(25)];
+        let f = sm.new_imported_source_file(PathBuf::from("a.ts").into(), lines, 30, None, None);
+        assert!(!f.has_src());
+        assert_eq!(f.byte_length(), 30);
+        assert_eq!(
+            f.lines,
+            vec![
+                f.start_pos,
+                f.start_pos + BytePos(10),
+                f.start_pos + BytePos(25)
+            ]
+        );
+
+        let loc = sm.lookup_char_pos(f.start_pos + BytePos(12));
+        assert_eq!((loc.line, loc.col), (2, CharPos(2)));
+        let loc = sm.lookup_char_pos(f.end_pos);
+        assert_eq!((loc.line, loc.col), (3, CharPos(5)));
+
+        let sp = Span::new(f.start_pos, f.start_pos + BytePos(3), NO_EXPANSION);
+        assert_eq!(
+            sm.span_to_snippet(sp),
+            Err(SpanSnippetError::SourceNotAvailable {
+                filename: PathBuf::from("a.ts").into()
+            })
+        );
+        assert_eq!(f.get_line(1), None);
+        assert!(f.line_hashes().is_empty());
+
+        let original = sm.new_source_file(PathBuf::from("c.ts").into(), "let c;\n".into());
+        let c = sm.new_imported_source_file(
+            PathBuf::from("c.ts").into(),
+            vec![BytePos(0)],
+            7,
+            None,
+            Some(original.src_hash()),
+        );
+        assert_eq!(c.src_hash(), original.src_hash());
+        assert_ne!(f.src_hash(), c.src_hash());
+
+        let src = "let a = 1;\nlet b = 2;\n";
+        let g = sm.new_imported_source_file(
+            PathBuf::from("b.ts").into(),
+            vec![BytePos(0), BytePos(11)],
+            22,
+            Some(src.into()),
+            None,
+        );
+        assert!(g.has_src());
+        let sp = Span::new(
+            g.start_pos + BytePos(11),
+            g.start_pos + BytePos(21),
+            NO_EXPANSION,
+        );
+        assert_eq!(sm.span_to_snippet(sp).unwrap(), "let b = 2;");
+
+        #[cfg(feature = "sourcemap")]
+        {
+            let mut mappings = vec![
+                (f.start_pos + BytePos(12), LineCol { line: 0, col: 0 }),
+                (g.start_pos + BytePos(15), LineCol { line: 0, col: 4 }),
+            ];
+            let map = sm.build_source_map(&mut mappings);
+
+            let token = map.lookup_token(0, 0).unwrap();
+            assert_eq!(token.get_source(), Some("a.ts"));
+            assert_eq!((token.get_src_line(), token.get_src_col()), (1, 2));
+            assert_eq!(map.get_source_contents(0), None);
+
+            let token = map.lookup_token(0, 4).unwrap();
+            assert_eq!(token.get_source(), Some("b.ts"));
+            assert_eq!((token.get_src_line(), token.get_src_col()), (1, 4));
+            assert_eq!(map.get_source_contents(1), Some(src));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid line table for a.ts")]
+    fn new_imported_source_file_unsorted_lines() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let lines = vec![BytePos(0), BytePos(10), BytePos(5)];
+        sm.new_imported_source_file(PathBuf::from("a.ts").into(), lines, 30, None, None);
+    }
+
+    #[test]
+    fn with_prev_source_up_to() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let f = sm.new_source_file(PathBuf::from("a.js").into(), "€€€x".into());
+        let x = Span::new(f.start_pos + BytePos(9), f.end_pos, NO_EXPANSION);
+
+        let prev = |max| {
+            sm.with_prev_source_up_to(x, max, |s| s.to_string())
+                .unwrap()
+        };
+        assert_eq!(prev(0), "");
+        // 5 bytes before `x` is in the middle of the second `€`.
+        assert_eq!(prev(4), "€");
+        assert_eq!(prev(6), "€€");
+        assert_eq!(prev(100), "€€€");
+    }
+
+    #[test]
+    fn span_to_margin_long_lines() {
+        let long = " ".repeat(MARGIN_LOOKBACK * 2);
+        let src = [
+            "a",
+            "    b",
+            &format!("{}c", long),
+            "\t€€ d e",
+            &format!("{}€ f", long),
+            "",
+            "  g",
+        ]
+        .join("\n");
+
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let f = sm.new_source_file(PathBuf::from("a.js").into(), src.clone());
+
+        for (idx, _) in src.char_indices() {
+            let pos = f.start_pos + BytePos(idx as u32);
+            let sp = Span::new(pos, pos, NO_EXPANSION);
+
+            let prev_source = sm.span_to_prev_source(sp).unwrap();
+            let last_line = prev_source.split('\n').last().unwrap();
+            let expected = last_line.len() - last_line.trim_start().len();
+            assert_eq!(sm.span_to_margin(sp), Some(expected), "at {}", idx);
+        }
+
+        let at = |c: char| {
+            let pos = f.start_pos + BytePos(src.find(c).unwrap() as u32);
+            Span::new(pos, pos, NO_EXPANSION)
+        };
+        assert_eq!(sm.span_to_margin(at('b')), Some(4));
+        assert_eq!(sm.span_to_margin(at('c')), Some(MARGIN_LOOKBACK * 2));
+        assert_eq!(sm.span_to_margin(at('e')), Some(1));
+        assert_eq!(sm.span_to_margin(at('f')), Some(MARGIN_LOOKBACK * 2));
+        assert_eq!(sm.span_to_margin(at('g')), Some(2));
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn build_source_map_with_options() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let a = sm.new_source_file(
+            PathBuf::from("/project/src/a.js").into(),
+            "let a = 1;\n".into(),
+        );
+        let b = sm.new_source_file(PathBuf::from("/other/b.js").into(), "b();\n".into());
+        let anon = sm.new_source_file(FileName::Anon, "c();\n".into());
+        let mappings = vec![
+            (a.start_pos, LineCol { line: 0, col: 0 }),
+            (a.start_pos + BytePos(4), LineCol { line: 0, col: 4 }),
+            (b.start_pos, LineCol { line: 1, col: 0 }),
+            (anon.start_pos, LineCol { line: 2, col: 0 }),
+        ];
+        let json = |map: sourcemap::SourceMap| {
+            let mut buf = vec![];
+            map.to_writer(&mut buf).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        let with_options = |orig, options: &SourceMapOptions| {
+            json(sm.build_source_map_with_options(
+                mappings.iter().copied(),
+                orig,
+                DefaultSourceMapGenConfig,
+                options,
+            ))
+        };
+
+        let options = SourceMapOptions {
+            source_root: Some("/project".into()),
+            source_name_prefix: Some("app://".into()),
+            ..Default::default()
+        };
+        let with_prefix =
+            serde_json::from_str::<serde_json::Value>(&with_options(None, &options)).unwrap();
+        assert_eq!(with_prefix["sourceRoot"], "/project");
+        assert_eq!(
+            with_prefix["sources"],
+            serde_json::json!(["app://src/a.js", "app:///other/b.js", "<anon>"])
+        );
+
+        // The other methods use the default options.
+        let orig = sm.build_source_map(&mut mappings.clone());
+        for &orig in &[None, Some(&orig)] {
+            assert_eq!(
+                json(sm.build_source_map_from(&mut mappings.clone(), orig)),
+                with_options(orig, &Default::default())
+            );
+            assert_eq!(
+                json(sm.build_source_map_with_config(
+                    &mut mappings.clone(),
+                    orig,
+                    DefaultSourceMapGenConfig
+                )),
+                with_options(orig, &Default::default())
+            );
+        }
+
+        let map = sm.build_source_map_with_options(
+            mappings.iter().copied(),
+            Some(&orig),
+            DefaultSourceMapGenConfig,
+            &SourceMapOptions {
+                inline_sources_content: false,
+                ..Default::default()
+            },
+        );
+        assert!(map.get_source_count() > 0);
+        assert!(map.source_contents().all(|contents| contents.is_none()));
+    }
+
+    #[test]
+    #[cfg(feature = "sourcemap")]
+    fn incremental_source_map_builder() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let a = sm.new_source_file(
+            PathBuf::from("a.js").into(),
+            "let 가 = '€';\nfoo(가);\n".into(),
+        );
+        let b = sm.new_source_file(PathBuf::from("b.js").into(), "bar();\n".into());
+        let pos = |f: &SourceFile, offset: usize| f.start_pos + BytePos(offset as u32);
+        let lc = |line, col| LineCol { line, col };
+
+        // Mappings of two outputs, which are generated at the same time.
+        let first = vec![
+            (pos(&a, 0), lc(0, 0)),
+            (pos(&a, 4), lc(0, 4)),
+            (pos(&b, 0), lc(1, 0)),
+            (pos(&a, 17), lc(2, 0)),
+            (pos(&a, 21), lc(2, 4)),
+        ];
+        let second = vec![
+            (pos(&b, 0), lc(0, 0)),
+            (pos(&a, 10), lc(0, 6)),
+            (BytePos(u32::MAX), lc(0, 8)),
+            (pos(&a, 17), lc(1, 0)),
+        ];
+
+        let json = |map: sourcemap::SourceMap| {
+            let mut buf = vec![];
+            map.to_writer(&mut buf).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        let new_builder = || {
+            IncrementalSourceMapBuilder::new(
+                &sm,
+                None,
+                DefaultSourceMapGenConfig,
+                Default::default(),
+            )
+        };
+
+        let mut builders = (new_builder(), new_builder());
+        for i in 0..max(first.len(), second.len()) {
+            if let Some(&(pos, lc)) = first.get(i) {
+                builders.0.add_mapping(pos, lc);
+            }
+            if let Some(&(pos, lc)) = second.get(i) {
+                builders.1.add_mapping(pos, lc);
+            }
+        }
+        let (first_map, second_map) = (builders.0.finish(), builders.1.finish());
+
+        let token = first_map.lookup_token(2, 4).unwrap();
+        assert_eq!(token.get_source(), Some("a.js"));
+        assert_eq!((token.get_src_line(), token.get_src_col()), (1, 4));
+        let token = second_map.lookup_token(0, 6).unwrap();
+        assert_eq!((token.get_src_line(), token.get_src_col()), (0, 8));
+
+        assert_eq!(
+            json(first_map),
+            json(sm.build_source_map(&mut first.clone()))
+        );
+        assert_eq!(
+            json(second_map),
+            json(sm.build_source_map(&mut second.clone()))
+        );
+    }
+
+    #[test]
+    fn decode_source_with_bom() {
+        let text = "a€😀\nb";
+        let utf16 = |bom: &[u8], to_bytes: fn(u16) -> [u8; 2]| {
+            let mut bytes = bom.to_vec();
+            for unit in text.encode_utf16() {
+                bytes.extend_from_slice(&to_bytes(unit));
+            }
+            bytes
+        };
+        let cases = vec![
+            (text.as_bytes().to_vec(), false),
+            ([&[0xef, 0xbb, 0xbf][..], text.as_bytes()].concat(), true),
+            (utf16(&[0xff, 0xfe], u16::to_le_bytes), true),
+            (utf16(&[0xfe, 0xff], u16::to_be_bytes), true),
+        ];
+
+        let sm = SourceMap::new(FilePathMapping::empty());
+        for (bytes, had_bom) in cases {
+            let f = sm.new_source_file(FileName::Anon, decode_source(bytes).unwrap());
+            assert_eq!(*f.src, text);
+            assert_eq!(f.had_bom, had_bom);
+
+            // The BOM does not shift positions.
+            let b = f.start_pos + BytePos(text.find('b').unwrap() as u32);
+            let loc = sm.lookup_char_pos(b);
+            assert_eq!((loc.line, loc.col), (2, CharPos(0)));
+        }
+
+        // Odd length, a lone surrogate and invalid UTF-8.
+        assert!(decode_source(vec![0xff, 0xfe, 0x61]).is_err());
+        assert!(decode_source(vec![0xff, 0xfe, 0x00, 0xd8]).is_err());
+        assert!(decode_source(vec![0x61, 0x80]).is_err());
+    }
+
+    #[test]
+    fn span_helpers_keep_char_boundaries() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        sm.new_source_file(PathBuf::from("prefix.js").into(), "x".into());
+        let src = "€ a€ {b} €\n  😀c ";
+        let f = sm.new_source_file(PathBuf::from("a.js").into(), src.into());
+        let len = src.len() as u32;
+        let sp = |lo: u32, hi: u32| {
+            Span::new(
+                f.start_pos + BytePos(lo),
+                f.start_pos + BytePos(hi),
+                NO_EXPANSION,
+            )
+        };
+
+        type Helper<'a> = Box<dyn Fn(Span) -> Span + 'a>;
+        let helpers: Vec<(&str, Helper)> = vec![
+            (
+                "extend_to_prev_char",
+                Box::new(|s| sm.span_extend_to_prev_char(s, ' ')),
+            ),
+            (
+                "extend_to_prev_str",
+                Box::new(|s| sm.span_extend_to_prev_str(s, "€", true)),
+            ),
+            ("until_char", Box::new(|s| sm.span_until_char(s, '{'))),
+            ("through_char", Box::new(|s| sm.span_through_char(s, '€'))),
+            (
+                "until_whitespace",
+                Box::new(|s| sm.span_until_whitespace(s)),
+            ),
+            ("trim", Box::new(|s| sm.span_trim(s))),
+            ("def_span_js", Box::new(|s| sm.def_span_js(s))),
+            ("start_point", Box::new(|s| sm.start_point(s))),
+            ("end_point", Box::new(|s| sm.end_point(s))),
+        ];
+
+        for lo in 0..=len {
+            for hi in lo..=len {
+                let orig = sp(lo, hi);
+                for (name, helper) in &helpers {
+                    let res = helper(orig);
+                    if res == orig {
+                        continue;
+                    }
+
+                    let (res_lo, res_hi) = ((res.lo() - f.start_pos).0, (res.hi() - f.start_pos).0);
+                    assert!(
+                        res_hi <= len
+                            && src.is_char_boundary(res_lo as usize)
+                            && src.is_char_boundary(res_hi as usize),
+                        "{}({}..{}) = {}..{}",
+                        name,
+                        lo,
+                        hi,
+                        res_lo,
+                        res_hi
+                    );
+                }
+            }
+        }
+
+        // Multibyte characters right before the span.
+        let a = src.find('a').unwrap() as u32;
+        let extended = sm.span_extend_to_prev_char(sp(a + 1, a + 4), ' ');
+        assert_eq!(sm.span_to_snippet(extended).unwrap(), "a€");
+        let extended = sm.span_extend_to_prev_str(sp(a + 1, a + 4), "€", false);
+        assert_eq!(sm.span_to_snippet(extended).unwrap(), "a€");
+
+        // Position 1 is in the middle of the first `€`.
+        assert!(matches!(
+            sm.span_to_snippet(sp(1, 3)),
+            Err(SpanSnippetError::MalformedForSourcemap(..))
+        ));
+        assert_eq!(sm.span_extend_to_prev_char(sp(1, 3), ' '), sp(1, 3));
+        assert_eq!(sm.span_trim(sp(1, 5)), sp(1, 5));
+        assert_eq!(sm.span_trim(sp(3, 5)), sp(4, 5));
+        assert!(sm.snippets_of_spans(&[sp(1, 3)])[0].is_err());
+    }
+
+    #[test]
+    fn file_ranges() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        assert_eq!(sm.used_pos_space(), 0);
+
+        let sm = init_source_map();
+        assert_eq!(sm.used_pos_space(), 49);
+
+        let name = |name: &str| -> FileName { PathBuf::from(name).into() };
+        assert_eq!(
+            sm.file_range(&name("blork.rs")),
+            Some(BytePos(0)..BytePos(23))
+        );
+        assert_eq!(
+            sm.file_range(&name("empty.rs")),
+            Some(BytePos(24)..BytePos(24))
+        );
+        assert_eq!(
+            sm.file_range(&name("blork2.rs")),
+            Some(BytePos(25)..BytePos(48))
+        );
+        assert_eq!(sm.file_range(&name("missing.rs")), None);
+
+        let f = sm.new_source_file(name("next.rs"), "abc".into());
+        assert_eq!(f.byte_range(), BytePos(49)..BytePos(52));
+        assert_eq!(sm.used_pos_space(), 53);
+
+        let span = f.full_span();
+        assert_eq!((span.lo(), span.hi()), (f.start_pos, f.end_pos));
+        assert_eq!(sm.span_to_snippet(span).unwrap(), "abc");
+        let empty = sm.get_source_file(&name("empty.rs")).unwrap();
+        assert_eq!(sm.span_to_snippet(empty.full_span()).unwrap(), "");
+    }
+
+    #[test]
+    fn distinct_sources_locations() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let a = sm.new_source_file(PathBuf::from("a.js").into(), "let €;\nfoo();".into());
+        let b = sm.new_source_file(PathBuf::from("b.js").into(), "bar();\nbaz();".into());
+        let sp = Span::new(
+            a.start_pos + BytePos(11),
+            b.start_pos + BytePos(9),
+            NO_EXPANSION,
+        );
+
+        let expected = DistinctSources {
+            begin: (PathBuf::from("a.js").into(), a.start_pos),
+            end: (PathBuf::from("b.js").into(), b.start_pos),
+            begin_loc: Some((2, CharPos(2))),
+            end_loc: Some((2, CharPos(2))),
+        };
+        let message = "span starts at a.js:2:3 but ends at b.js:2:3, which is a different file";
+        assert_eq!(expected.to_string(), message);
+
+        let err = sm.span_to_lines(sp).err().unwrap();
+        assert_eq!(err, SpanLinesError::DistinctSources(expected.clone()));
+        assert_eq!(err.to_string(), message);
+
+        let err = sm.span_to_snippet(sp).unwrap_err();
+        assert_eq!(err, SpanSnippetError::DistinctSources(expected.clone()));
+        assert_eq!(err.to_string(), message);
+
+        assert_eq!(
+            sm.snippets_of_spans(&[sp]),
+            vec![Err(SpanSnippetError::DistinctSources(expected.clone()))]
+        );
+
+        let without_locations = DistinctSources {
+            begin_loc: None,
+            end_loc: None,
+            ..expected
+        };
+        assert_eq!(
+            without_locations.to_string(),
+            "span starts at a.js but ends at b.js, which is a different file"
+        );
+    }
+
+    #[test]
+    fn lookup_char_pos_many() {
+        let sm = init_source_map_mbc();
+        sm.new_source_file(PathBuf::from("empty.rs").into(), String::new());
+        sm.new_source_file(PathBuf::from("wide.rs").into(), "가나\t다\n€\n".to_string());
+
+        let mut positions = vec![];
+        for f in sm.files().iter() {
+            let mut offsets = f.src.char_indices().map(|(idx, _)| idx).collect::<Vec<_>>();
+            offsets.push(f.src.len());
+            positions.extend(offsets.iter().map(|&idx| f.start_pos + BytePos(idx as u32)));
+        }
+        // Unsorted, with duplicates and positions outside of files.
+        positions.reverse();
+        positions.extend(positions.clone().iter().step_by(3));
+        positions.insert(5, BytePos(u32::MAX - 1));
+        positions.push(BytePos(0));
+
+        let key = |loc: &Loc| (loc.file.start_pos, loc.line, loc.col, loc.col_display);
+        let keys = |locs: Vec<Option<Loc>>| {
+            locs.iter()
+                .map(|loc| loc.as_ref().map(key))
+                .collect::<Vec<_>>()
+        };
+
+        let locs = keys(sm.lookup_char_pos_many(&positions));
+        assert_eq!(locs, keys(sm.freeze().lookup_char_pos_many(&positions)));
+        assert_eq!(locs.len(), positions.len());
+        for (&pos, loc) in positions.iter().zip(&locs) {
+            match sm.try_lookup_source_file(pos) {
+                Some(_) => assert_eq!(*loc, Some(key(&sm.lookup_char_pos(pos))), "{:?}", pos),
+                None => assert_eq!(*loc, None),
+            }
+        }
+        assert_eq!(locs[5], None);
+    }
+
+    #[test]
+    fn fork_and_adopt() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let a = sm.new_source_file(PathBuf::from("a.js").into(), "let a;".into());
+        let a_span = Span::new(
+            a.start_pos + BytePos(4),
+            a.end_pos - BytePos(1),
+            NO_EXPANSION,
+        );
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        {
+            let seen = seen.clone();
+            sm.on_file_added(Box::new(move |f| seen.lock().unwrap().push(f.start_pos)));
+        }
+
+        let first = sm.fork();
+        let second = sm.fork();
+        assert_eq!(first.span_to_snippet(a_span).unwrap(), "a");
+
+        // Files of forks and of the parent are interleaved, but never overlap.
+        let b = first.new_source_file(PathBuf::from("b.tsx").into(), "<b />;".into());
+        let c = second.new_source_file(PathBuf::from("c.ts").into(), "let c;".into());
+        let d = sm.new_source_file(PathBuf::from("d.js").into(), "let d;".into());
+        let e = first.new_anon_source_file("let e;".into());
+        let f = second.new_anon_source_file("let f;".into());
+        let files = [&a, &b, &c, &d, &e, &f];
+        for w in files.windows(2) {
+            assert!(w[0].end_pos < w[1].start_pos);
+        }
+        assert_ne!(e.name, f.name);
+
+        assert!(sm.try_lookup_source_file(b.start_pos).is_none());
+        assert!(first.try_lookup_source_file(c.start_pos).is_none());
+        assert!(first.try_lookup_source_file(d.start_pos).is_none());
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        // The discarded fork leaves nothing behind.
+        drop(second);
+        assert_eq!(sm.files().len(), 2);
+
+        let b_span = Span::new(b.start_pos, b.end_pos, NO_EXPANSION);
+        sm.adopt(first);
+        assert_eq!(sm.span_to_snippet(b_span).unwrap(), "<b />;");
+        assert_eq!(sm.span_to_snippet(a_span).unwrap(), "a");
+        assert_eq!(
+            sm.files().iter().map(|f| f.start_pos).collect::<Vec<_>>(),
+            vec![a.start_pos, b.start_pos, d.start_pos, e.start_pos]
+        );
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![d.start_pos, b.start_pos, e.start_pos]
+        );
+        assert!(Lrc::ptr_eq(&sm.get_source_file(&e.name).unwrap(), &e));
+    }
+
+    #[test]
+    #[cfg(feature = "concurrent")]
+    fn concurrent_forks() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        let (first, second) = (sm.fork(), sm.fork());
+
+        let add_files = |sm: &SourceMap, prefix: &str| {
+            for i in 0..100 {
+                let name = PathBuf::from(format!("{}{}.js", prefix, i));
+                sm.new_source_file(name.into(), "x;\n".repeat(i));
+            }
+        };
+        rayon::join(|| add_files(&first, "a"), || add_files(&second, "b"));
+        sm.adopt(first);
+        sm.adopt(second);
+
+        let files = sm.files();
+        assert_eq!(files.len(), 200);
+        for w in files.windows(2) {
+            assert!(w[0].end_pos < w[1].start_pos);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not a fork of this source map")]
+    fn adopt_unrelated() {
+        let sm = SourceMap::new(FilePathMapping::empty());
+        sm.adopt(sm.fork().fork());
+        sm.adopt(SourceMap::new(FilePathMapping::empty()));
+    }
+
+    #[test]
+    fn lookup_panics_at_caller() {
+        fn panic_message(op: impl FnOnce()) -> String {
+            let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(op)).unwrap_err();
+            err.downcast::<String>().map(|msg| *msg).unwrap()
+        }
+
+        let sm = init_source_map();
+        let caller = |line: u32| format!("looked up at {}:{}:", file!(), line);
+
+        let line = line!();
+        let msg = panic_message(|| drop(sm.lookup_char_pos(BytePos(49))));
+        assert!(msg.contains(&caller(line + 1)), "{}", msg);
+        assert!(msg.starts_with("position 49 does not resolve to a source location"));
+        assert!(msg.contains("after blork2.rs (25..=48)"), "{}", msg);
+
+        let line = line!();
+        let msg = panic_message(|| drop(sm.freeze().lookup_byte_offset(BytePos(49))));
+        assert!(msg.contains(&caller(line + 1)), "{}", msg);
+
+        let blork = sm.lookup_source_file(BytePos(0));
+        let line = line!();
+        let msg = panic_message(|| drop(sm.lookup_char_pos_with(blork.clone(), BytePos(30))));
+        assert!(msg.contains(&caller(line + 1)), "{}", msg);
+        assert!(
+            msg.contains("position 30 is not in blork.rs (0..=23)"),
+            "{}",
+            msg
+        );
+
+        let file = |name: &str, start_pos: u32| {
+            let name = FileName::from(PathBuf::from(name));
+            Lrc::new(SourceFile::new(
+                name.clone(),
+                false,
+                name,
+                "abc".into(),
+                BytePos(start_pos),
+            ))
+        };
+        let files = vec![file("a.rs", 10), file("b.rs", 20)];
+        let msg = panic_message(|| drop(unresolved_pos(&files, BytePos(15), || false)));
+        assert!(
+            msg.contains("between a.rs (10..=13) and b.rs (20..=23)"),
+            "{}",
+            msg
+        );
+        let msg = panic_message(|| drop(unresolved_pos(&files, BytePos(5), || false)));
+        assert!(msg.contains("before a.rs (10..=13)"), "{}", msg);
+        let msg = panic_message(|| drop(unresolved_pos(&[], BytePos(5), || true)));
+        assert!(msg.contains("there are no files"), "{}", msg);
+
+        // With `SWC_DEBUG_SPANS`, the nearest file is used instead.
+        assert_eq!(
+            unresolved_pos(&files, BytePos(15), || true).start_pos,
+            BytePos(10)
+        );
+        assert_eq!(
+            unresolved_pos(&files, BytePos(5), || true).start_pos,
+            BytePos(10)
+        );
+        assert_eq!(
+            unresolved_pos(&files, BytePos(30), || true).start_pos,
+            BytePos(20)
+        );
+        assert_eq!(
+            check_pos_in_file(&files[0], BytePos(15), || true),
+            BytePos(13)
+        );
+        assert_eq!(
+            check_pos_in_file(&files[1], BytePos(15), || true),
+            BytePos(20)
+        );
     }
 
     /// Test failing to merge two spans on different lines
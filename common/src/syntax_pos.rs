@@ -1,11 +1,15 @@
 pub use self::hygiene::{Mark, SyntaxContext};
-use crate::{rustc_data_structures::stable_hasher::StableHasher, sync::Lrc};
+use crate::{
+    rustc_data_structures::stable_hasher::StableHasher,
+    sync::{Lrc, OnceCell},
+};
 use serde::{Deserialize, Serialize};
 use std::{
     borrow::Cow,
     cmp, fmt,
     hash::{Hash, Hasher},
-    ops::{Add, Sub},
+    io::{self, BufRead},
+    ops::{Add, Range, Sub},
     path::PathBuf,
     sync::Mutex,
 };
@@ -89,8 +93,14 @@ pub static GLOBALS: ::scoped_tls::ScopedKey<Globals> = ::scoped_tls::ScopedKey {
 
 /// Differentiates between real files and common virtual files.
 #[derive(Debug, Eq, PartialEq, Clone, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde-impl", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "plugin-base",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub enum FileName {
-    Real(PathBuf),
+    Real(#[cfg_attr(feature = "plugin-base", with(EncodePathBuf))] PathBuf),
     /// A macro.  This includes the full name of the macro, so that there are no
     /// clashes.
     Macros(String),
@@ -104,6 +114,58 @@ pub enum FileName {
     ProcMacroSourceCode,
     /// Custom sources for explicit parser calls from plugins and drivers
     Custom(String),
+    /// A file created by [crate::SourceMap::new_anon_source_file], numbered
+    /// so that the files don't share a name.
+    NumberedAnon(usize),
+}
+
+/// Archives a [PathBuf] as a string with `rkyv`, replacing invalid UTF-8
+/// sequences.
+#[cfg(feature = "plugin-base")]
+#[derive(Debug, Clone, Copy)]
+pub struct EncodePathBuf;
+
+#[cfg(feature = "plugin-base")]
+impl rkyv::with::ArchiveWith<PathBuf> for EncodePathBuf {
+    type Archived = rkyv::string::ArchivedString;
+    type Resolver = rkyv::string::StringResolver;
+
+    unsafe fn resolve_with(
+        field: &PathBuf,
+        pos: usize,
+        resolver: Self::Resolver,
+        out: *mut Self::Archived,
+    ) {
+        rkyv::string::ArchivedString::resolve_from_str(
+            &field.to_string_lossy(),
+            pos,
+            resolver,
+            out,
+        );
+    }
+}
+
+#[cfg(feature = "plugin-base")]
+impl<S> rkyv::with::SerializeWith<PathBuf, S> for EncodePathBuf
+where
+    S: rkyv::ser::Serializer + ?Sized,
+{
+    fn serialize_with(field: &PathBuf, serializer: &mut S) -> Result<Self::Resolver, S::Error> {
+        rkyv::string::ArchivedString::serialize_from_str(&field.to_string_lossy(), serializer)
+    }
+}
+
+#[cfg(feature = "plugin-base")]
+impl<D> rkyv::with::DeserializeWith<rkyv::string::ArchivedString, PathBuf, D> for EncodePathBuf
+where
+    D: rkyv::Fallible + ?Sized,
+{
+    fn deserialize_with(
+        field: &rkyv::string::ArchivedString,
+        _: &mut D,
+    ) -> Result<PathBuf, D::Error> {
+        Ok(PathBuf::from(field.as_str()))
+    }
 }
 
 impl std::fmt::Display for FileName {
@@ -116,6 +178,7 @@ impl std::fmt::Display for FileName {
             FileName::Anon => write!(fmt, "<anon>"),
             FileName::ProcMacroSourceCode => write!(fmt, "<proc-macro source code>"),
             FileName::Custom(ref s) => write!(fmt, "<{}>", s),
+            FileName::NumberedAnon(n) => write!(fmt, "<anon:{}>", n),
         }
     }
 }
@@ -136,6 +199,7 @@ impl FileName {
             | FileName::MacroExpansion
             | FileName::ProcMacroSourceCode
             | FileName::Custom(_)
+            | FileName::NumberedAnon(_)
             | FileName::QuoteExpansion => false,
         }
     }
@@ -147,10 +211,17 @@ impl FileName {
             | FileName::MacroExpansion
             | FileName::ProcMacroSourceCode
             | FileName::Custom(_)
+            | FileName::NumberedAnon(_)
             | FileName::QuoteExpansion => false,
             FileName::Macros(_) => true,
         }
     }
+
+    /// Returns `true` for [FileName::Anon] and the names created by
+    /// [crate::SourceMap::new_anon_source_file].
+    pub fn is_anon(&self) -> bool {
+        matches!(self, FileName::Anon | FileName::NumberedAnon(_))
+    }
 }
 
 /// A collection of spans. Spans have two orthogonal attributes:
@@ -201,6 +272,25 @@ impl Span {
         Span::new(self.lo, self.hi, ctxt)
     }
 
+    /// Returns `self` with the syntax context of `other`.
+    #[inline]
+    pub fn with_ctxt_of(&self, other: Span) -> Span {
+        self.with_ctxt(other.ctxt)
+    }
+
+    /// Returns an empty span at `pos`, with the empty syntax context.
+    ///
+    /// ```text
+    /// source:        foo(bar)
+    /// empty_at(pos):     | (lo == hi == pos)
+    /// ```
+    ///
+    /// `Span::empty_at(BytePos(0))` is the dummy span.
+    #[inline]
+    pub fn empty_at(pos: BytePos) -> Span {
+        Span::new(pos, pos, SyntaxContext::empty())
+    }
+
     /// Returns `true` if this is a dummy span with any hygienic context.
     #[inline]
     pub fn is_dummy(self) -> bool {
@@ -279,7 +369,21 @@ impl Span {
     }
 
     /// Return a `Span` between the end of `self` to the beginning of `end`.
+    ///
+    /// ```text
+    /// source:            foo( bar )
+    /// self:              ^^^
+    /// end:                    ^^^
+    /// self.between(end):    ^^
+    /// ```
+    ///
+    /// Returns the dummy span if either span is the dummy span. If `end`
+    /// starts before `self` ends, the gap is reversed, as in [Span::new].
     pub fn between(self, end: Span) -> Span {
+        if self.is_dummy() || end.is_dummy() {
+            return DUMMY_SP;
+        }
+
         let span = self;
         Span::new(
             span.hi,
@@ -294,7 +398,20 @@ impl Span {
 
     /// Return a `Span` between the beginning of `self` to the beginning of
     /// `end`.
+    ///
+    /// ```text
+    /// source:          foo( bar )
+    /// self:            ^^^
+    /// end:                  ^^^
+    /// self.until(end): ^^^^^
+    /// ```
+    ///
+    /// Returns the dummy span if either span is the dummy span.
     pub fn until(self, end: Span) -> Span {
+        if self.is_dummy() || end.is_dummy() {
+            return DUMMY_SP;
+        }
+
         let span = self;
         Span::new(
             span.lo,
@@ -307,6 +424,34 @@ impl Span {
         )
     }
 
+    /// Returns the first `len` bytes of `self`, or `self` if it's shorter.
+    ///
+    /// ```text
+    /// source:             foo(bar)
+    /// self:               ^^^^^^^^
+    /// self.leading_of(3): ^^^
+    /// ```
+    ///
+    /// `hi` is exclusive, so the result is empty if `len` is zero.
+    pub fn leading_of(self, len: usize) -> Span {
+        let len = cmp::min(len, (self.hi - self.lo).to_usize());
+        self.with_hi(self.lo + BytePos::from_usize(len))
+    }
+
+    /// Returns the last `len` bytes of `self`, or `self` if it's shorter.
+    ///
+    /// ```text
+    /// source:              foo(bar)
+    /// self:                ^^^^^^^^
+    /// self.trailing_of(4):     ^^^^
+    /// ```
+    ///
+    /// The result is empty at `self.hi()` if `len` is zero.
+    pub fn trailing_of(self, len: usize) -> Span {
+        let len = cmp::min(len, (self.hi - self.lo).to_usize());
+        self.with_lo(self.hi - BytePos::from_usize(len))
+    }
+
     pub fn from_inner_byte_pos(self, start: usize, end: usize) -> Span {
         let span = self;
         Span::new(
@@ -499,6 +644,12 @@ pub const NO_EXPANSION: SyntaxContext = SyntaxContext::empty();
 
 /// Identifies an offset of a multi-byte character in a SourceFile
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde-impl", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "plugin-base",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub struct MultiByteChar {
     /// The absolute offset of the character in the SourceMap
     pub pos: BytePos,
@@ -508,6 +659,12 @@ pub struct MultiByteChar {
 
 /// Identifies an offset of a non-narrow character in a SourceFile
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde-impl", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "plugin-base",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub enum NonNarrowChar {
     /// Represents a zero-width character
     ZeroWidth(BytePos),
@@ -570,12 +727,26 @@ impl Sub<BytePos> for NonNarrowChar {
 }
 
 /// A single source in the SourceMap.
+///
+/// With the `serde-impl` feature, files can be serialized and restored with
+/// [crate::SourceMap::from_serialized]. With the `plugin-base` feature, they
+/// can be archived with `rkyv`, read in place with [SourceFile::archived] and
+/// restored with [SourceFile::from_archived].
 #[derive(Clone)]
+#[cfg_attr(feature = "serde-impl", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "plugin-base",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub struct SourceFile {
     /// The name of the file that the source came from. Source that doesn't
     /// originate from files has names between angle brackets by convention,
     /// e.g. `<anon>`
-    pub name: FileName,
+    ///
+    /// Files with the same name may share it, see
+    /// [crate::source_map::FileNameInterner].
+    pub name: Lrc<FileName>,
     /// True if the `name` field above has been modified by
     /// `--remap-path-prefix`
     pub name_was_remapped: bool,
@@ -586,8 +757,15 @@ pub struct SourceFile {
     pub crate_of_origin: u32,
     /// The complete source code
     pub src: Lrc<String>,
-    /// The source code's hash
-    pub src_hash: u128,
+    /// The source code's hash, computed by [SourceFile::src_hash] on the first
+    /// use.
+    #[cfg_attr(feature = "serde-impl", serde(skip))]
+    #[cfg_attr(feature = "plugin-base", with(rkyv::with::Skip))]
+    src_hash: OnceCell<u128>,
+    /// Hashes of lines, computed by [SourceFile::line_hashes] on the first use.
+    #[cfg_attr(feature = "serde-impl", serde(skip))]
+    #[cfg_attr(feature = "plugin-base", with(rkyv::with::Skip))]
+    line_hashes: OnceCell<Vec<u64>>,
     /// The start position of this source in the `SourceMap`
     pub start_pos: BytePos,
     /// The end position of this source in the `SourceMap`
@@ -598,6 +776,19 @@ pub struct SourceFile {
     pub multibyte_chars: Vec<MultiByteChar>,
     /// Width of characters that are not narrow in the source code
     pub non_narrow_chars: Vec<NonNarrowChar>,
+    /// True if the source code consists of ASCII characters only, which makes
+    /// byte offsets and character offsets the same.
+    #[cfg_attr(feature = "serde-impl", serde(default))]
+    pub(crate) is_ascii: bool,
+    /// True if a BOM (`U+FEFF`) was removed from the start of the source code.
+    ///
+    /// Positions are relative to the source code without the BOM. This does
+    /// not record the encoding of the file: [crate::source_map::FileLoader]s
+    /// which decode UTF-16 keep its BOM as `U+FEFF`, so it is set for both
+    /// UTF-8 and UTF-16 files. Writing a file back byte-for-byte requires the
+    /// caller to know the original encoding.
+    #[cfg_attr(feature = "serde-impl", serde(default))]
+    pub had_bom: bool,
     /// A hash of the filename, used for speeding up the incr. comp. hashing.
     pub name_hash: u128,
 }
@@ -610,24 +801,16 @@ impl fmt::Debug for SourceFile {
 
 impl SourceFile {
     pub fn new(
-        name: FileName,
+        name: impl Into<Lrc<FileName>>,
         name_was_remapped: bool,
         unmapped_path: FileName,
         mut src: String,
         start_pos: BytePos,
     ) -> SourceFile {
-        remove_bom(&mut src);
+        let name = name.into();
+        let had_bom = remove_bom(&mut src);
 
-        let src_hash = {
-            let mut hasher: StableHasher<u128> = StableHasher::new();
-            hasher.write(src.as_bytes());
-            hasher.finish()
-        };
-        let name_hash = {
-            let mut hasher: StableHasher<u128> = StableHasher::new();
-            name.hash(&mut hasher);
-            hasher.finish()
-        };
+        let name_hash = name_hash(&name);
         let end_pos = start_pos.to_usize() + src.len();
 
         let (lines, multibyte_chars, non_narrow_chars) =
@@ -639,16 +822,225 @@ impl SourceFile {
             unmapped_path: Some(unmapped_path),
             crate_of_origin: 0,
             src: Lrc::new(src),
-            src_hash,
+            src_hash: OnceCell::new(),
+            line_hashes: OnceCell::new(),
             start_pos,
             end_pos: Pos::from_usize(end_pos),
             lines,
+            is_ascii: multibyte_chars.is_empty(),
+            had_bom,
             multibyte_chars,
             non_narrow_chars,
             name_hash,
         }
     }
 
+    /// Creates a file without source code from its line table. See
+    /// [crate::SourceMap::new_imported_source_file].
+    ///
+    /// `lines` are absolute positions. The file is treated as ASCII.
+    pub(crate) fn new_imported(
+        name: Lrc<FileName>,
+        name_was_remapped: bool,
+        unmapped_path: FileName,
+        lines: Vec<BytePos>,
+        len: u32,
+        start_pos: BytePos,
+        src_hash: Option<u128>,
+    ) -> SourceFile {
+        let name_hash = name_hash(&name);
+        let src_hash_cell = OnceCell::new();
+        if let Some(src_hash) = src_hash {
+            let _ = src_hash_cell.set(src_hash);
+        }
+
+        SourceFile {
+            name,
+            name_was_remapped,
+            unmapped_path: Some(unmapped_path),
+            crate_of_origin: 0,
+            src: Lrc::new(String::new()),
+            src_hash: src_hash_cell,
+            line_hashes: OnceCell::new(),
+            start_pos,
+            end_pos: start_pos + BytePos(len),
+            lines,
+            multibyte_chars: vec![],
+            non_narrow_chars: vec![],
+            is_ascii: true,
+            had_bom: false,
+            name_hash,
+        }
+    }
+
+    /// Creates a file without source code by reading `src` one line at a time,
+    /// for [crate::SourceMap::load_file_metadata_only].
+    ///
+    /// The positions and hashes are the same as the ones of a file created by
+    /// [SourceFile::new] with the whole source code. `start_pos` receives the
+    /// length of the source code, without the BOM.
+    pub(crate) fn new_metadata_only(
+        name: Lrc<FileName>,
+        name_was_remapped: bool,
+        unmapped_path: FileName,
+        src: &mut dyn BufRead,
+        start_pos: impl FnOnce(usize) -> BytePos,
+    ) -> io::Result<SourceFile> {
+        let mut lines = vec![];
+        let mut multibyte_chars = vec![];
+        let mut non_narrow_chars = vec![];
+        let mut line_hashes = vec![];
+        let mut src_hasher: StableHasher<u128> = StableHasher::new();
+        let mut had_bom = false;
+        let mut len = 0;
+
+        let mut line = String::new();
+        let mut is_first_line = true;
+        loop {
+            line.clear();
+            if src.read_line(&mut line)? == 0 {
+                break;
+            }
+            if is_first_line {
+                is_first_line = false;
+                had_bom = remove_bom(&mut line);
+                if line.is_empty() {
+                    continue;
+                }
+            }
+
+            // Positions are relative to the file until its start is known.
+            let (line_start, mb, nn) =
+                analyze_source_file::analyze_source_file(&line, BytePos::from_usize(len));
+            lines.extend(line_start);
+            multibyte_chars.extend(mb);
+            non_narrow_chars.extend(nn);
+
+            src_hasher.write(line.as_bytes());
+            let mut hasher: StableHasher<u64> = StableHasher::new();
+            hasher.write(line.strip_suffix('\n').unwrap_or(&line).as_bytes());
+            line_hashes.push(hasher.finish());
+
+            len += line.len();
+        }
+
+        let start_pos = start_pos(len);
+        for line in &mut lines {
+            *line = *line + start_pos;
+        }
+        for c in &mut multibyte_chars {
+            c.pos = c.pos + start_pos;
+        }
+        for c in &mut non_narrow_chars {
+            *c = *c + start_pos;
+        }
+
+        let src_hash = OnceCell::new();
+        let _ = src_hash.set(src_hasher.finish());
+        let line_hashes_cell = OnceCell::new();
+        let _ = line_hashes_cell.set(line_hashes);
+
+        Ok(SourceFile {
+            name_hash: name_hash(&name),
+            name,
+            name_was_remapped,
+            unmapped_path: Some(unmapped_path),
+            crate_of_origin: 0,
+            src: Lrc::new(String::new()),
+            src_hash,
+            line_hashes: line_hashes_cell,
+            start_pos,
+            end_pos: start_pos + BytePos::from_usize(len),
+            lines,
+            is_ascii: multibyte_chars.is_empty(),
+            had_bom,
+            multibyte_chars,
+            non_narrow_chars,
+        })
+    }
+
+    /// Validates a file archived with `rkyv`, e.g. by
+    /// `rkyv::to_bytes::<_, 1024>(&*file)`, and reads it in place.
+    ///
+    /// `bytes` may come from an untrusted source, but they must be aligned
+    /// like [rkyv::AlignedVec]. Nothing is copied, including the source text.
+    #[cfg(feature = "plugin-base")]
+    pub fn archived(bytes: &[u8]) -> Result<&ArchivedSourceFile, String> {
+        rkyv::check_archived_root::<SourceFile>(bytes)
+            .map_err(|err| format!("invalid archived source file: {}", err))
+    }
+
+    /// Restores a file archived with `rkyv`.
+    ///
+    /// Like [SourceFile::archived], `bytes` are validated before use. The
+    /// source text is copied out of `bytes`, so use [SourceFile::archived] if
+    /// the file is only read.
+    #[cfg(feature = "plugin-base")]
+    pub fn from_archived(bytes: &[u8]) -> Result<Lrc<SourceFile>, String> {
+        use rkyv::{de::deserializers::SharedDeserializeMap, Deserialize};
+
+        let file: SourceFile = SourceFile::archived(bytes)?
+            .deserialize(&mut SharedDeserializeMap::new())
+            .map_err(|err| format!("failed to deserialize source file: {}", err))?;
+
+        Ok(Lrc::new(file))
+    }
+
+    /// Returns a 128-bit hash of the source code, which can be used to check
+    /// if the file has changed since the last build.
+    ///
+    /// The hash is computed once, on the first call. It does not depend on the
+    /// platform.
+    pub fn src_hash(&self) -> u128 {
+        *self.src_hash.get_or_init(|| {
+            let mut hasher: StableHasher<u128> = StableHasher::new();
+            hasher.write(self.src.as_bytes());
+            hasher.finish()
+        })
+    }
+
+    /// Returns `true` if [SourceFile::src] contains the whole source code,
+    /// which is not the case for files created by
+    /// [crate::SourceMap::load_file_metadata_only].
+    pub fn has_src(&self) -> bool {
+        self.src.len() == self.byte_length() as usize
+    }
+
+    /// Returns a hash of each line, without the line break, in the order of
+    /// [SourceFile::lines].
+    ///
+    /// The hashes are computed once, on the first call. They do not depend on
+    /// the platform. See [crate::source_map::changed_line_ranges].
+    ///
+    /// Files imported without source code have no line hashes.
+    pub fn line_hashes(&self) -> &[u64] {
+        self.line_hashes.get_or_init(|| {
+            if !self.has_src() {
+                return vec![];
+            }
+
+            let ends = self
+                .lines
+                .iter()
+                .skip(1)
+                .map(|&next| (next - self.start_pos).to_usize())
+                .chain(Some(self.src.len()));
+
+            self.lines
+                .iter()
+                .zip(ends)
+                .map(|(&start, end)| {
+                    let line = &self.src[(start - self.start_pos).to_usize()..end];
+                    let line = line.strip_suffix('\n').unwrap_or(line);
+
+                    let mut hasher: StableHasher<u64> = StableHasher::new();
+                    hasher.write(line.as_bytes());
+                    hasher.finish()
+                })
+                .collect()
+        })
+    }
+
     /// Return the BytePos of the beginning of the current line.
     pub fn line_begin_pos(&self, pos: BytePos) -> BytePos {
         let line_index = self.lookup_line(pos).unwrap();
@@ -657,6 +1049,9 @@ impl SourceFile {
 
     /// Get a line from the list of pre-computed line-beginnings.
     /// The line number here is 0-based.
+    ///
+    /// Returns `None` if the source code is not available, see
+    /// [SourceFile::has_src].
     pub fn get_line(&self, line_number: usize) -> Option<Cow<'_, str>> {
         fn get_until_newline(src: &str, begin: usize) -> &str {
             // We can't use `lines.get(line_number+1)` because we might
@@ -669,6 +1064,10 @@ impl SourceFile {
             }
         }
 
+        if !self.has_src() {
+            return None;
+        }
+
         let begin = {
             let line = if let Some(line) = self.lines.get(line_number) {
                 line
@@ -682,6 +1081,11 @@ impl SourceFile {
         Some(Cow::from(get_until_newline(&self.src, begin)))
     }
 
+    /// Returns true if the source code consists of ASCII characters only.
+    pub fn is_ascii(&self) -> bool {
+        self.is_ascii
+    }
+
     pub fn is_real_file(&self) -> bool {
         self.name.is_real()
     }
@@ -689,6 +1093,17 @@ impl SourceFile {
     pub fn byte_length(&self) -> u32 {
         self.end_pos.0 - self.start_pos.0
     }
+
+    /// Returns the positions of the source code, i.e. `start_pos..end_pos`.
+    pub fn byte_range(&self) -> Range<BytePos> {
+        self.start_pos..self.end_pos
+    }
+
+    /// Returns a span covering the whole file.
+    pub fn full_span(&self) -> Span {
+        Span::new(self.start_pos, self.end_pos, SyntaxContext::empty())
+    }
+
     pub fn count_lines(&self) -> usize {
         self.lines.len()
     }
@@ -730,10 +1145,50 @@ impl SourceFile {
     }
 }
 
-/// Remove utf-8 BOM if any.
-fn remove_bom(src: &mut String) {
+/// Lookups of a file read in place by [SourceFile::archived].
+#[cfg(feature = "plugin-base")]
+impl ArchivedSourceFile {
+    /// The source code, borrowed from the archive.
+    pub fn src(&self) -> &str {
+        self.src.as_str()
+    }
+
+    /// Same as [SourceFile::lookup_line].
+    pub fn lookup_line(&self, pos: BytePos) -> Option<usize> {
+        self.lines
+            .partition_point(|line| line.0 <= pos.0)
+            .checked_sub(1)
+    }
+
+    /// Same as [SourceFile::get_line], but borrows the line from the archive.
+    pub fn get_line(&self, line_number: usize) -> Option<&str> {
+        if self.src.len() != (self.end_pos.0 - self.start_pos.0) as usize {
+            return None;
+        }
+
+        let begin = (self.lines.get(line_number)?.0 - self.start_pos.0) as usize;
+        let line = &self.src()[begin..];
+        match line.find('\n') {
+            Some(end) => Some(&line[..end]),
+            None => Some(line),
+        }
+    }
+}
+
+/// Hashes the name of a file for [SourceFile::name_hash].
+fn name_hash(name: &FileName) -> u128 {
+    let mut hasher: StableHasher<u128> = StableHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Remove utf-8 BOM if any, and returns true if it was removed.
+pub(crate) fn remove_bom(src: &mut String) -> bool {
     if src.starts_with("\u{feff}") {
         src.drain(..3);
+        true
+    } else {
+        false
     }
 }
 
@@ -753,8 +1208,25 @@ pub trait Pos {
 #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 #[serde(transparent)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(
+    feature = "plugin-base",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub struct BytePos(pub u32);
 
+impl BytePos {
+    /// Returns true if the position is reserved for comments of synthesized
+    /// code.
+    ///
+    /// It does not belong to any file, and mappings at it are not added to
+    /// source maps.
+    #[inline]
+    pub const fn is_reserved_for_comments(self) -> bool {
+        self.0 == u32::MAX
+    }
+}
+
 /// A character offset. Because of multibyte utf8 characters, a byte offset
 /// is not equivalent to a character offset. The SourceMap will convert BytePos
 /// values to CharPos values as necessary.
@@ -931,8 +1403,16 @@ pub enum SpanSnippetError {
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct DistinctSources {
+    /// The file containing the start of the span, and its start position.
     pub begin: (FileName, BytePos),
+    /// The file containing the end of the span, and its start position.
     pub end: (FileName, BytePos),
+    /// The 1-based line and the column of the start of the span in
+    /// [DistinctSources::begin].
+    pub begin_loc: Option<(usize, CharPos)>,
+    /// The 1-based line and the column of the end of the span in
+    /// [DistinctSources::end].
+    pub end_loc: Option<(usize, CharPos)>,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -943,6 +1423,63 @@ pub struct MalformedSourceMapPositions {
     pub end_pos: BytePos,
 }
 
+impl fmt::Display for SpanLinesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpanLinesError::IllFormedSpan(sp) => write!(f, "ill-formed span {:?}", sp),
+            SpanLinesError::DistinctSources(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for SpanLinesError {}
+
+impl fmt::Display for SpanSnippetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpanSnippetError::IllFormedSpan(sp) => write!(f, "ill-formed span {:?}", sp),
+            SpanSnippetError::DistinctSources(err) => err.fmt(f),
+            SpanSnippetError::MalformedForSourcemap(err) => err.fmt(f),
+            SpanSnippetError::SourceNotAvailable { filename } => {
+                write!(f, "source of {} is not available", filename)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SpanSnippetError {}
+
+impl fmt::Display for DistinctSources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "span starts at ")?;
+        write_location(f, &self.begin.0, self.begin_loc)?;
+        write!(f, " but ends at ")?;
+        write_location(f, &self.end.0, self.end_loc)?;
+        write!(f, ", which is a different file")
+    }
+}
+
+fn write_location(
+    f: &mut fmt::Formatter<'_>,
+    name: &FileName,
+    loc: Option<(usize, CharPos)>,
+) -> fmt::Result {
+    match loc {
+        Some((line, col)) => write!(f, "{}:{}:{}", name, line, col.0 + 1),
+        None => write!(f, "{}", name),
+    }
+}
+
+impl fmt::Display for MalformedSourceMapPositions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "positions {:?}..{:?} are invalid for {}, which has {} bytes",
+            self.begin_pos, self.end_pos, self.name, self.source_len
+        )
+    }
+}
+
 // Given a slice of line start positions and a position, returns the index of
 // the line the position is on. Returns -1 if the position is located before
 // the first line.
@@ -977,4 +1514,48 @@ mod tests {
     fn size_of_span() {
         assert_eq!(std::mem::size_of::<Span>(), 12);
     }
+
+    #[test]
+    #[cfg(feature = "plugin-base")]
+    fn archived_source_file() {
+        use super::{FileName, SourceFile};
+        use std::path::PathBuf;
+
+        let file = SourceFile::new(
+            FileName::from(PathBuf::from("emoji.js")),
+            false,
+            FileName::Anon,
+            "let 🦀 = '👋🏽';\n\n// ✨\r\nfoo(🦀)\n".into(),
+            BytePos(7),
+        );
+        let bytes = rkyv::to_bytes::<_, 1024>(&file).unwrap();
+
+        let archived = SourceFile::archived(&bytes).unwrap();
+        assert_eq!(archived.src(), &**file.src);
+        assert!(bytes.as_ptr_range().contains(&archived.src().as_ptr()));
+        for pos in (file.start_pos.0..=file.end_pos.0).map(BytePos) {
+            assert_eq!(archived.lookup_line(pos), file.lookup_line(pos));
+        }
+        for line in 0..=file.count_lines() {
+            assert_eq!(archived.get_line(line), file.get_line(line).as_deref());
+        }
+
+        let restored = SourceFile::from_archived(&bytes).unwrap();
+
+        assert_eq!(restored.name, file.name);
+        assert_eq!(restored.src, file.src);
+        assert_eq!(
+            (restored.start_pos, restored.end_pos),
+            (file.start_pos, file.end_pos)
+        );
+        assert_eq!(restored.lines, file.lines);
+        assert_eq!(restored.multibyte_chars, file.multibyte_chars);
+        assert_eq!(restored.non_narrow_chars, file.non_narrow_chars);
+        for pos in (file.start_pos.0..=file.end_pos.0).map(BytePos) {
+            assert_eq!(restored.lookup_line(pos), file.lookup_line(pos));
+        }
+
+        assert!(SourceFile::archived(&bytes[..bytes.len() / 2]).is_err());
+        assert!(SourceFile::from_archived(&bytes[..bytes.len() / 2]).is_err());
+    }
 }
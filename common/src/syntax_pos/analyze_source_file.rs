@@ -15,6 +15,10 @@ use unicode_width::UnicodeWidthChar;
 ///
 /// This function will use an SSE2 enhanced implementation if hardware support
 /// is detected at runtime.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "trace", skip(src, source_file_start_pos), fields(len = src.len()))
+)]
 pub fn analyze_source_file(
     src: &str,
     source_file_start_pos: BytePos,
@@ -0,0 +1,74 @@
+#![cfg(feature = "sourcemap")]
+//! Compares the peak memory usage of building a source map from a buffered
+//! `Vec` of mappings and from an iterator.
+//!
+//! This is a separate test binary because it replaces the global allocator.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    mem::size_of,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+};
+use swc_common::{
+    source_map::DefaultSourceMapGenConfig, BytePos, FileName, FilePathMapping, LineCol, SourceMap,
+};
+
+struct CountingAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), SeqCst) + layout.size();
+            PEAK.fetch_max(current, SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Returns the peak number of bytes allocated by `f` at once.
+fn peak_of(f: impl FnOnce()) -> usize {
+    let base = CURRENT.load(SeqCst);
+    PEAK.store(base, SeqCst);
+    f();
+    PEAK.load(SeqCst) - base
+}
+
+#[test]
+fn iter_does_not_buffer_mappings() {
+    const LINES: u32 = 200_000;
+
+    let cm = SourceMap::new(FilePathMapping::empty());
+    let fm = cm.new_source_file(
+        FileName::Custom("input.js".into()),
+        "foo();\n".repeat(LINES as usize),
+    );
+    let mappings =
+        || (0..LINES).map(|line| (fm.start_pos + BytePos(line * 7), LineCol { line, col: 0 }));
+
+    let from_vec = peak_of(|| {
+        let mut mappings = mappings().collect::<Vec<_>>();
+        cm.build_source_map(&mut mappings);
+    });
+    let from_iter = peak_of(|| {
+        cm.build_source_map_from_iter(mappings(), None, DefaultSourceMapGenConfig);
+    });
+    println!(
+        "peak memory for {} mappings: vec = {} bytes, iter = {} bytes",
+        LINES, from_vec, from_iter
+    );
+
+    let buffered = size_of::<(BytePos, LineCol)>() * LINES as usize;
+    assert!(from_iter + buffered <= from_vec);
+}
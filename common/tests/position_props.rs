@@ -0,0 +1,142 @@
+//! Property tests for the conversions between `BytePos`, `CharPos` and
+//! line/column.
+
+use proptest::{prelude::*, sample::Index};
+use swc_common::{
+    sync::Lrc, BytePos, FileName, FilePathMapping, SourceFile, SourceMap, Span, DUMMY_SP,
+};
+
+/// Pieces which are hard to handle while converting positions.
+fn piece() -> impl Strategy<Value = &'static str> {
+    prop::sample::select(vec![
+        "a", "ab", " ", "\t", "\n", "\r\n", "\r", "é", "€", "😀", "\u{7}", "\u{200b}", "漢字",
+    ])
+}
+
+fn source() -> impl Strategy<Value = String> {
+    prop::collection::vec(piece(), 0..64).prop_map(|pieces| pieces.concat())
+}
+
+/// Registers `src` after a file with multibyte characters, so that the file
+/// does not start at `BytePos(0)`.
+fn init(src: String) -> (SourceMap, Lrc<SourceFile>) {
+    let cm = SourceMap::new(FilePathMapping::empty());
+    cm.new_source_file(FileName::Custom("prefix.js".into()), "€\n😀".into());
+    let fm = cm.new_source_file(FileName::Custom("test.js".into()), src);
+
+    (cm, fm)
+}
+
+/// Char boundaries of the file, including the end of the file.
+fn boundaries(fm: &SourceFile) -> Vec<BytePos> {
+    fm.src
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(Some(fm.src.len()))
+        .map(|i| fm.start_pos + BytePos(i as u32))
+        .collect()
+}
+
+fn span(lo: BytePos, hi: BytePos) -> Span {
+    Span::new(lo, hi, DUMMY_SP.ctxt)
+}
+
+proptest! {
+    #[test]
+    fn lookup_char_pos_is_monotonic(src in source()) {
+        let (cm, fm) = init(src);
+
+        let mut prev = None;
+        for pos in boundaries(&fm) {
+            let loc = cm.lookup_char_pos(pos);
+            prop_assert_eq!(loc.file.start_pos, fm.start_pos);
+
+            let cur = (loc.line, loc.col);
+            if let Some(prev) = prev {
+                prop_assert!(prev < cur, "{:?} >= {:?} at {:?}", prev, cur, pos);
+            }
+            prev = Some(cur);
+        }
+    }
+
+    #[test]
+    fn char_pos_counts_chars(src in source()) {
+        let (cm, fm) = init(src);
+
+        for pos in boundaries(&fm) {
+            let loc = cm.lookup_char_pos(pos);
+            // An empty file has no lines.
+            let line_start = fm.lines.get(loc.line - 1).copied().unwrap_or(fm.start_pos);
+            let text = &fm.src[(line_start - fm.start_pos).0 as usize..(pos - fm.start_pos).0 as usize];
+
+            prop_assert_eq!(loc.col.0, text.chars().count());
+        }
+    }
+
+    #[test]
+    fn span_to_lines_tiles_span(src in source(), a in any::<Index>(), b in any::<Index>()) {
+        let (cm, fm) = init(src);
+        let positions = boundaries(&fm);
+        let (lo, hi) = {
+            let a = positions[a.index(positions.len())];
+            let b = positions[b.index(positions.len())];
+            (a.min(b), a.max(b))
+        };
+
+        let lines = cm.span_to_lines(span(lo, hi)).unwrap();
+        if fm.src.is_empty() {
+            prop_assert!(lines.lines.is_empty());
+            return Ok(());
+        }
+
+        let lo = cm.lookup_char_pos(lo);
+        let hi = cm.lookup_char_pos(hi);
+
+        prop_assert_eq!(lines.lines.len(), hi.line - lo.line + 1);
+        for (i, line) in lines.lines.iter().enumerate() {
+            prop_assert_eq!(line.line_index, lo.line - 1 + i);
+            prop_assert!(line.start_col <= line.end_col);
+
+            if i != 0 {
+                prop_assert_eq!(line.start_col.0, 0);
+            }
+            if i + 1 != lines.lines.len() {
+                let len = fm.get_line(line.line_index).unwrap().chars().count();
+                prop_assert_eq!(line.end_col.0, len);
+            }
+        }
+        prop_assert_eq!(lines.lines[0].start_col, lo.col);
+        prop_assert_eq!(lines.lines.last().unwrap().end_col, hi.col);
+    }
+
+    #[test]
+    fn snippet_of_lines_matches_get_line(src in source(), a in any::<Index>(), b in any::<Index>()) {
+        let (cm, fm) = init(src);
+        if fm.lines.is_empty() {
+            return Ok(());
+        }
+
+        let (first, last) = {
+            let a = a.index(fm.lines.len());
+            let b = b.index(fm.lines.len());
+            (a.min(b), a.max(b))
+        };
+
+        let lo = fm.lines[first];
+        let hi = match fm.lines.get(last + 1) {
+            // Exclude the `\n` of the last line.
+            Some(&next) => next - BytePos(1),
+            // A trailing `\n` does not start a new line.
+            None if fm.src.ends_with('\n') => fm.end_pos - BytePos(1),
+            None => fm.end_pos,
+        };
+
+        let snippet = cm.span_to_snippet(span(lo, hi)).unwrap();
+        let expected = (first..=last)
+            .map(|i| fm.get_line(i).unwrap().into_owned())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        prop_assert_eq!(snippet, expected);
+    }
+}
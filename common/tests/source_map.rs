@@ -1,7 +1,7 @@
 #![cfg(feature = "concurrent")]
 
 use rayon::{prelude::*, ThreadPoolBuilder};
-use swc_common::{FileName, FilePathMapping, SourceMap};
+use swc_common::{BytePos, FileName, FilePathMapping, SourceMap};
 
 #[test]
 fn stress() {
@@ -36,3 +36,35 @@ export class Product extends TimestampedEntity {
         );
     })
 }
+
+#[test]
+fn concurrent_add_and_lookup() {
+    let cm = SourceMap::new(FilePathMapping::empty());
+    let first = cm.new_source_file(FileName::Custom("first".into()), "// first\n".into());
+
+    (0..2000).into_par_iter().for_each(|i| {
+        let name = FileName::Custom(i.to_string());
+        let fm = cm.new_source_file(name.clone(), format!("// {}\nfoo();\n", i).repeat(i % 7));
+
+        for &pos in &[fm.start_pos, fm.end_pos] {
+            assert_eq!(*cm.lookup_char_pos(pos).file.name, name);
+        }
+        assert_eq!(cm.lookup_char_pos(first.start_pos).file.name, first.name);
+        assert_eq!(
+            cm.lookup_byte_offset(fm.end_pos).pos,
+            fm.end_pos - fm.start_pos
+        );
+    });
+
+    let files = cm.files().clone();
+    assert_eq!(files.len(), 2001);
+    for w in files.windows(2) {
+        assert!(w[0].end_pos < w[1].start_pos);
+    }
+    for f in files.iter() {
+        assert_eq!(cm.try_lookup_source_file(f.start_pos).unwrap().name, f.name);
+    }
+    assert!(cm
+        .try_lookup_source_file(files.last().unwrap().end_pos + BytePos(1))
+        .is_none());
+}
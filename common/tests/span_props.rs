@@ -0,0 +1,96 @@
+//! Property tests for the constructors and combinators of `Span`.
+
+use proptest::prelude::*;
+use swc_common::{BytePos, Span, DUMMY_SP};
+
+/// Spans with small positions, so that they often touch or overlap, and
+/// sometimes the dummy span.
+fn span() -> impl Strategy<Value = Span> {
+    prop_oneof![
+        1 => Just(DUMMY_SP),
+        8 => (0u32..64, 0u32..64).prop_map(|(lo, hi)| Span::new(BytePos(lo), BytePos(hi), DUMMY_SP.ctxt)),
+    ]
+}
+
+fn len(sp: Span) -> usize {
+    (sp.hi() - sp.lo()).0 as usize
+}
+
+proptest! {
+    #[test]
+    fn empty_at_is_empty(pos in 0u32..64) {
+        let sp = Span::empty_at(BytePos(pos));
+
+        prop_assert_eq!(sp.lo(), BytePos(pos));
+        prop_assert_eq!(sp.hi(), BytePos(pos));
+        prop_assert_eq!(sp.is_dummy(), pos == 0);
+    }
+
+    #[test]
+    fn between_is_the_gap(a in span(), b in span()) {
+        let sp = a.between(b);
+        prop_assert!(sp.lo() <= sp.hi());
+
+        if a.is_dummy() || b.is_dummy() {
+            prop_assert!(sp.is_dummy());
+        } else if a.hi() <= b.lo() {
+            prop_assert_eq!((sp.lo(), sp.hi()), (a.hi(), b.lo()));
+        }
+    }
+
+    #[test]
+    fn until_ends_at_start_of_end(a in span(), b in span()) {
+        let sp = a.until(b);
+        prop_assert!(sp.lo() <= sp.hi());
+
+        if a.is_dummy() || b.is_dummy() {
+            prop_assert!(sp.is_dummy());
+        } else if a.lo() <= b.lo() {
+            prop_assert_eq!((sp.lo(), sp.hi()), (a.lo(), b.lo()));
+        }
+    }
+
+    #[test]
+    fn leading_of_is_prefix(a in span(), n in 0usize..80) {
+        let sp = a.leading_of(n);
+
+        prop_assert!(sp.lo() <= sp.hi());
+        prop_assert!(a.contains(sp));
+        prop_assert_eq!(sp.lo(), a.lo());
+        prop_assert_eq!(len(sp), n.min(len(a)));
+        prop_assert_eq!(sp.ctxt(), a.ctxt());
+        prop_assert_eq!(sp.is_dummy(), a.is_dummy() || (a.lo() == BytePos(0) && n == 0));
+    }
+
+    #[test]
+    fn trailing_of_is_suffix(a in span(), n in 0usize..80) {
+        let sp = a.trailing_of(n);
+
+        prop_assert!(sp.lo() <= sp.hi());
+        prop_assert!(a.contains(sp));
+        prop_assert_eq!(sp.hi(), a.hi());
+        prop_assert_eq!(len(sp), n.min(len(a)));
+        prop_assert_eq!(sp.ctxt(), a.ctxt());
+        if a.is_dummy() {
+            prop_assert!(sp.is_dummy());
+        }
+    }
+
+    #[test]
+    fn leading_and_trailing_of_tile_span(a in span(), n in 0usize..80) {
+        let n = n.min(len(a));
+        let head = a.leading_of(n);
+        let tail = a.trailing_of(len(a) - n);
+
+        prop_assert_eq!(head.hi(), tail.lo());
+        prop_assert!(head.to(tail).source_equal(a));
+    }
+
+    #[test]
+    fn with_ctxt_of_keeps_positions(a in span(), b in span()) {
+        let sp = a.with_ctxt_of(b);
+
+        prop_assert!(sp.source_equal(a));
+        prop_assert_eq!(sp.ctxt(), b.ctxt());
+    }
+}
@@ -0,0 +1,121 @@
+#![cfg(feature = "tracing")]
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+};
+use swc_common::{BytePos, FileName, FilePathMapping, SourceMap, Span, DUMMY_SP};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Event, Metadata, Subscriber,
+};
+
+/// Records the name and fields of every span.
+#[derive(Clone, Default)]
+struct Capture {
+    next_id: Arc<AtomicU64>,
+    spans: Arc<Mutex<Vec<(&'static str, Vec<(String, String)>)>>>,
+}
+
+struct FieldVisitor<'a>(&'a mut Vec<(String, String)>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .push((field.name().to_string(), format!("{:?}", value)));
+    }
+}
+
+impl Subscriber for Capture {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut fields = vec![];
+        span.record(&mut FieldVisitor(&mut fields));
+        self.spans
+            .lock()
+            .unwrap()
+            .push((span.metadata().name(), fields));
+
+        Id::from_u64(self.next_id.fetch_add(1, SeqCst) + 1)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, _: &Event<'_>) {}
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+impl Capture {
+    fn fields_of(&self, name: &str) -> Vec<Vec<(String, String)>> {
+        self.spans
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(n, _)| *n == name)
+            .map(|(_, fields)| fields.clone())
+            .collect()
+    }
+}
+
+fn field(name: &str, value: &str) -> (String, String) {
+    (name.to_string(), value.to_string())
+}
+
+#[test]
+fn spans_of_source_map_operations() {
+    let capture = Capture::default();
+
+    tracing::subscriber::with_default(capture.clone(), || {
+        let cm = SourceMap::new(FilePathMapping::empty());
+        let fm = cm.new_source_file(FileName::Custom("a.js".into()), "let a;\nlet b;".into());
+
+        cm.span_to_lines(Span::new(
+            fm.start_pos,
+            fm.start_pos + BytePos(9),
+            DUMMY_SP.ctxt,
+        ))
+        .unwrap();
+
+        #[cfg(feature = "sourcemap")]
+        {
+            use swc_common::source_map::LineCol;
+
+            let mut mappings = vec![
+                (fm.start_pos, LineCol { line: 0, col: 0 }),
+                (fm.start_pos + BytePos(7), LineCol { line: 1, col: 0 }),
+            ];
+            cm.build_source_map(&mut mappings);
+        }
+    });
+
+    assert_eq!(
+        capture.fields_of("new_source_file"),
+        vec![vec![field("name", "<a.js>"), field("len", "13")]]
+    );
+    assert_eq!(
+        capture.fields_of("analyze_source_file"),
+        vec![vec![field("len", "13")]]
+    );
+    assert_eq!(
+        capture.fields_of("span_to_lines"),
+        vec![vec![field("len", "9")]]
+    );
+
+    #[cfg(feature = "sourcemap")]
+    assert_eq!(
+        capture.fields_of("build_source_map_with_config"),
+        vec![vec![field("mappings", "2")]]
+    );
+}
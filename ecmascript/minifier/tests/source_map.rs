@@ -0,0 +1,171 @@
+//! Minifies fixtures containing `throw` statements and checks that the
+//! position of each `throw` in the output resolves to the original `throw`
+//! through the source map.
+
+use std::path::PathBuf;
+use swc_common::comments::SingleThreadedComments;
+use swc_common::sync::Lrc;
+use swc_common::BytePos;
+use swc_common::FileName;
+use swc_common::Mark;
+use swc_common::SourceFile;
+use swc_common::SourceMap;
+use swc_common::DUMMY_SP;
+use swc_ecma_ast::*;
+use swc_ecma_codegen::text_writer::JsWriter;
+use swc_ecma_codegen::Emitter;
+use swc_ecma_codegen::Node as _;
+use swc_ecma_minifier::optimize;
+use swc_ecma_minifier::option::terser::TerserCompressorOptions;
+use swc_ecma_minifier::option::ExtraOptions;
+use swc_ecma_minifier::option::MinifyOptions;
+use swc_ecma_parser::lexer::input::SourceFileInput;
+use swc_ecma_parser::lexer::Lexer;
+use swc_ecma_parser::Parser;
+use swc_ecma_transforms::fixer;
+use swc_ecma_transforms::hygiene;
+use swc_ecma_transforms::resolver_with_mark;
+use swc_ecma_visit::FoldWith;
+use swc_ecma_visit::Node;
+use swc_ecma_visit::Visit;
+use swc_ecma_visit::VisitWith;
+use testing::assert_eq;
+
+#[testing::fixture("source_map/throw/**/input.js")]
+fn throw_sites(input: PathBuf) {
+    testing::run_test2(false, |cm, handler| {
+        let fm = cm.load_file(&input).expect("failed to load input.js");
+
+        let module = parse(&fm).map_err(|err| {
+            err.into_diagnostic(&handler).emit();
+        })?;
+        let expected = throw_positions(&cm, &module);
+        assert!(
+            !expected.is_empty(),
+            "{} should contain a throw statement",
+            input.display()
+        );
+
+        let top_level_mark = Mark::fresh(Mark::root());
+        let module = module.fold_with(&mut resolver_with_mark(top_level_mark));
+
+        let compress: TerserCompressorOptions =
+            serde_json::from_str("{}").expect("failed to create compressor config");
+        let comments = SingleThreadedComments::default();
+
+        let output = optimize(
+            module,
+            Some(&comments),
+            None,
+            &MinifyOptions {
+                compress: Some(compress.into_config(cm.clone())),
+                mangle: Some(Default::default()),
+                ..Default::default()
+            },
+            &ExtraOptions { top_level_mark },
+        )
+        .fold_with(&mut hygiene())
+        .fold_with(&mut fixer(None));
+
+        let (code, map) = print_with_map(cm.clone(), &output);
+        eprintln!("---- Output -----\n{}", code);
+
+        let generated = cm.new_source_file(FileName::Custom("output.js".into()), code);
+        let generated = parse(&generated).expect("failed to parse the minified code");
+        let generated = throw_positions(&cm, &generated);
+
+        let mut resolved = generated
+            .iter()
+            .map(|&(line, col)| {
+                resolve(&map, line, col).unwrap_or_else(|| {
+                    panic!(
+                        "`throw` at {}:{} of the output is not mapped",
+                        line + 1,
+                        col
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        resolved.sort();
+        resolved.dedup();
+
+        assert_eq!(resolved, expected);
+
+        Ok(())
+    })
+    .unwrap()
+}
+
+fn parse(fm: &SourceFile) -> swc_ecma_parser::PResult<Module> {
+    let lexer = Lexer::new(
+        Default::default(),
+        Default::default(),
+        SourceFileInput::from(fm),
+        None,
+    );
+
+    Parser::new_from(lexer).parse_module()
+}
+
+/// Returns the 0-based line and column of every `throw` statement, sorted.
+fn throw_positions(cm: &SourceMap, module: &Module) -> Vec<(u32, u32)> {
+    struct ThrowFinder(Vec<BytePos>);
+
+    impl Visit for ThrowFinder {
+        fn visit_throw_stmt(&mut self, n: &ThrowStmt, _: &dyn Node) {
+            self.0.push(n.span.lo);
+            n.visit_children_with(self);
+        }
+    }
+
+    let mut finder = ThrowFinder(vec![]);
+    module.visit_with(&Invalid { span: DUMMY_SP } as _, &mut finder);
+
+    let mut positions = finder
+        .0
+        .into_iter()
+        .map(|pos| {
+            let loc = cm.lookup_char_pos(pos);
+            (loc.line as u32 - 1, loc.col.0 as u32)
+        })
+        .collect::<Vec<_>>();
+    positions.sort();
+    positions
+}
+
+/// Resolves a position of the generated code to the original position.
+///
+/// Returns `None` unless a token starts exactly at the position.
+fn resolve(map: &sourcemap::SourceMap, line: u32, col: u32) -> Option<(u32, u32)> {
+    let token = map.lookup_token(line, col)?;
+    if token.get_dst_line() != line || token.get_dst_col() != col {
+        return None;
+    }
+
+    Some((token.get_src_line(), token.get_src_col()))
+}
+
+fn print_with_map(cm: Lrc<SourceMap>, module: &Module) -> (String, sourcemap::SourceMap) {
+    let mut buf = vec![];
+    let mut src_map_buf = vec![];
+
+    {
+        let mut emitter = Emitter {
+            cfg: swc_ecma_codegen::Config { minify: true },
+            cm: cm.clone(),
+            comments: None,
+            wr: Box::new(JsWriter::new(
+                cm.clone(),
+                "\n",
+                &mut buf,
+                Some(&mut src_map_buf),
+            )),
+        };
+
+        module.emit_with(&mut emitter).unwrap();
+    }
+
+    let map = cm.build_source_map(&mut src_map_buf);
+
+    (String::from_utf8(buf).unwrap(), map)
+}
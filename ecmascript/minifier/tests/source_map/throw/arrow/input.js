@@ -0,0 +1,6 @@
+export const check = (value) => {
+    if (typeof value !== 'number') {
+        throw new TypeError('not a number');
+    }
+    return value * 2;
+};
@@ -0,0 +1,7 @@
+export async function load(url) {
+    const res = await fetch(url);
+    if (!res.ok) {
+        throw new Error(`failed to load ${url}`);
+    }
+    return res.json();
+}
@@ -0,0 +1,5 @@
+export class Foo {
+    bar = (() => {
+        throw new Error('class field');
+    })();
+}
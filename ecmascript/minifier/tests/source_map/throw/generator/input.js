@@ -0,0 +1,8 @@
+export function* range(start, end) {
+    if (start > end) {
+        throw new RangeError('start > end');
+    }
+    for (let i = start; i < end; i++) {
+        yield i;
+    }
+}
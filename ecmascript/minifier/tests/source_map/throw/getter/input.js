@@ -0,0 +1,5 @@
+export const obj = {
+    get value() {
+        throw new Error('getter');
+    },
+};
@@ -0,0 +1,9 @@
+export function find(items, id) {
+    for (const item of items) {
+        if (item.id === id) {
+            return item;
+        }
+    }
+
+    throw new Error('not found: ' + id);
+}
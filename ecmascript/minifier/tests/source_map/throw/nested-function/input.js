@@ -0,0 +1,10 @@
+export function outer(a) {
+    function inner(b) {
+        if (b < 0) {
+            throw new RangeError('negative');
+        }
+        return b;
+    }
+
+    return inner(a) + inner(a + 1);
+}
@@ -0,0 +1,10 @@
+export function kind(v) {
+    switch (v) {
+        case 1:
+            return 'one';
+        case 2:
+            return 'two';
+        default:
+            throw new Error('unknown: ' + v);
+    }
+}
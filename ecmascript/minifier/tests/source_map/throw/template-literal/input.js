@@ -0,0 +1,10 @@
+export function render(name) {
+    const text = `Hello,
+${name}!
+Bye`;
+    if (text.length > 100) {
+        throw new Error(`too long:
+${text}`);
+    }
+    return text;
+}
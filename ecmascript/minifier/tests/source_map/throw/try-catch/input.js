@@ -0,0 +1,8 @@
+export function parse(json) {
+    try {
+        return JSON.parse(json);
+    } catch (e) {
+        console.error(e);
+        throw e;
+    }
+}
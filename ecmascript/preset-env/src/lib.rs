@@ -17,7 +17,7 @@ use swc_ecma_transforms::{
     compat::{bugfixes, es2015, es2016, es2017, es2018, es2020, es2021, es3},
     pass::{noop, Optional},
 };
-use swc_ecma_utils::prepend_stmts;
+use swc_ecma_utils::prepend_after_directives;
 use swc_ecma_visit::{Fold, FoldWith, VisitWith};
 
 #[macro_use]
@@ -305,7 +305,7 @@ impl Fold for Polyfills {
         if cfg!(debug_assertions) {
             let mut v = required.into_iter().collect::<Vec<_>>();
             v.sort();
-            prepend_stmts(
+            prepend_after_directives(
                 &mut m.body,
                 v.into_iter().map(|src| {
                     ModuleItem::ModuleDecl(ModuleDecl::Import(ImportDecl {
@@ -323,7 +323,7 @@ impl Fold for Polyfills {
                 }),
             );
         } else {
-            prepend_stmts(
+            prepend_after_directives(
                 &mut m.body,
                 required.into_iter().map(|src| {
                     ModuleItem::ModuleDecl(ModuleDecl::Import(ImportDecl {
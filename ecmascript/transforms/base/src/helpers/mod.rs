@@ -4,7 +4,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use swc_common::{FileName, FilePathMapping, Mark, SourceMap, DUMMY_SP};
 use swc_ecma_ast::*;
 use swc_ecma_parser::{lexer::Lexer, Parser, StringInput};
-use swc_ecma_utils::{prepend_stmts, quote_ident, quote_str, DropSpan};
+use swc_ecma_utils::{prepend_after_directives, quote_ident, quote_str, DropSpan};
 use swc_ecma_visit::{as_folder, noop_visit_mut_type, Fold, VisitMut, VisitMutWith};
 
 #[macro_export]
@@ -282,7 +282,7 @@ impl VisitMut for InjectHelpers {
     fn visit_mut_module(&mut self, module: &mut Module) {
         let helpers = self.mk_helpers();
 
-        prepend_stmts(&mut module.body, helpers.into_iter());
+        prepend_after_directives(&mut module.body, helpers);
     }
 }
 
@@ -369,6 +369,35 @@ function _throw(e) {
         )
     }
 
+    #[test]
+    fn helper_after_directives() {
+        // The `core-js` import is injected by `preset_env`, which runs before
+        // the helpers are injected.
+        crate::tests::test_transform(
+            Default::default(),
+            |_| {
+                enable_helper!(throw);
+                as_folder(InjectHelpers)
+            },
+            "#!/usr/bin/env node
+'use strict';
+'use client';
+import 'core-js/modules/es.array.iterator';
+foo();",
+            "#!/usr/bin/env node
+'use strict';
+'use client';
+function _throw(e) {
+    throw e;
+}
+import 'core-js/modules/es.array.iterator';
+foo();
+",
+            false,
+            Default::default(),
+        )
+    }
+
     #[test]
     fn name_conflict() {
         crate::tests::test_transform(
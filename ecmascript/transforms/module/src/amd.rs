@@ -1,6 +1,6 @@
 use super::util::{
     self, define_es_module, define_property, has_use_strict, initialize_to_undefined,
-    local_name_for_src, make_descriptor, use_strict, Exports, ModulePass, Scope,
+    local_name_for_src, make_descriptor, take_directives, use_strict, Exports, ModulePass, Scope,
 };
 use crate::path::{ImportResolver, NoopImportResolver};
 use anyhow::Context;
@@ -84,7 +84,7 @@ where
     }
 
     fn fold_module(&mut self, module: Module) -> Module {
-        let items = module.body;
+        let mut items = module.body;
         self.in_top_level = true;
 
         // Inserted after initializing exported names to undefined.
@@ -93,6 +93,7 @@ where
         if self.config.config.strict_mode && !has_use_strict(&items) {
             stmts.push(use_strict());
         }
+        stmts.extend(take_directives(&mut items));
 
         let mut exports = vec![];
         let mut initialized = FxHashSet::default();
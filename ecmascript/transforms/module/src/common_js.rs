@@ -1,7 +1,7 @@
 pub use super::util::Config;
 use super::util::{
     define_es_module, define_property, has_use_strict, initialize_to_undefined, make_descriptor,
-    make_require_call, use_strict, ModulePass, Scope,
+    make_require_call, take_directives, use_strict, ModulePass, Scope,
 };
 use crate::path::{ImportResolver, NoopImportResolver};
 use fxhash::FxHashSet;
@@ -70,7 +70,7 @@ where
 {
     noop_fold_type!();
 
-    fn fold_module_items(&mut self, items: Vec<ModuleItem>) -> Vec<ModuleItem> {
+    fn fold_module_items(&mut self, mut items: Vec<ModuleItem>) -> Vec<ModuleItem> {
         let mut emitted_esmodule = false;
         let mut stmts = Vec::with_capacity(items.len() + 4);
         let mut extra_stmts = Vec::with_capacity(items.len());
@@ -78,6 +78,11 @@ where
         if self.config.strict_mode && !has_use_strict(&items) {
             stmts.push(ModuleItem::Stmt(use_strict()));
         }
+        stmts.extend(
+            take_directives(&mut items)
+                .into_iter()
+                .map(ModuleItem::Stmt),
+        );
 
         let mut exports = vec![];
         let mut initialized = FxHashSet::default();
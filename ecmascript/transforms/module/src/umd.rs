@@ -6,7 +6,8 @@ use self::config::BuiltConfig;
 pub use self::config::Config;
 use super::util::{
     self, define_es_module, define_property, has_use_strict, initialize_to_undefined,
-    local_name_for_src, make_descriptor, make_require_call, use_strict, Exports, ModulePass, Scope,
+    local_name_for_src, make_descriptor, make_require_call, take_directives, use_strict, Exports,
+    ModulePass, Scope,
 };
 use crate::path::{ImportResolver, NoopImportResolver};
 use fxhash::FxHashSet;
@@ -93,7 +94,7 @@ where
 
         let filename = self.cm.span_to_filename(module.span);
 
-        let items = module.body;
+        let mut items = module.body;
 
         // Inserted after initializing exported names to undefined.
         let mut extra_stmts = vec![];
@@ -101,6 +102,7 @@ where
         if self.config.config.strict_mode && !has_use_strict(&items) {
             stmts.push(use_strict());
         }
+        stmts.extend(take_directives(&mut items));
 
         let mut exports = vec![];
         let mut initialized = FxHashSet::default();
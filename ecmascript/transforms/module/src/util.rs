@@ -846,19 +846,39 @@ pub(super) fn define_es_module(exports: Ident) -> Stmt {
 }
 
 pub(super) fn has_use_strict(stmts: &[ModuleItem]) -> bool {
-    if stmts.is_empty() {
-        return false;
-    }
+    stmts
+        .iter()
+        .take_while(|item| directive_value(item).is_some())
+        .any(|item| directive_value(item).map_or(false, |value| &**value == "use strict"))
+}
 
-    match &*stmts.first().unwrap() {
+/// Splits the directive prologue off the start of `items`.
+///
+/// Directives like `"use client"` must stay at the top of the output, so
+/// they can't be moved below the `require` calls with other statements.
+pub(super) fn take_directives(items: &mut Vec<ModuleItem>) -> Vec<Stmt> {
+    let len = items
+        .iter()
+        .take_while(|item| directive_value(item).is_some())
+        .count();
+
+    items
+        .drain(..len)
+        .filter_map(|item| match item {
+            ModuleItem::Stmt(stmt) => Some(stmt),
+            ModuleItem::ModuleDecl(..) => None,
+        })
+        .collect()
+}
+
+fn directive_value(item: &ModuleItem) -> Option<&JsWord> {
+    match item {
         ModuleItem::Stmt(Stmt::Expr(ExprStmt { expr, .. })) => match &**expr {
-            Expr::Lit(Lit::Str(Str { ref value, .. })) => return &*value == "use strict",
-            _ => {}
+            Expr::Lit(Lit::Str(Str { value, .. })) => Some(value),
+            _ => None,
         },
-        _ => {}
+        _ => None,
     }
-
-    false
 }
 
 pub(super) fn use_strict() -> Stmt {
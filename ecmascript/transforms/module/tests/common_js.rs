@@ -78,6 +78,23 @@ function _default({ name , input: inp  }) {
 "
 );
 
+test!(
+    syntax(),
+    |_| tr(Config {
+        ..Default::default()
+    }),
+    directives_before_require,
+    "'use client';
+'use strict';
+import 'foo';
+foo();",
+    "'use client';
+'use strict';
+require('foo');
+foo();
+"
+);
+
 test!(
     syntax(),
     |_| common_js(Mark::fresh(Mark::root()), Default::default(), None),
@@ -1637,22 +1637,28 @@ pub fn undefined(span: Span) -> Box<Expr> {
 /// inject `branch` after directives
 #[inline(never)]
 pub fn prepend<T: StmtLike>(stmts: &mut Vec<T>, stmt: T) {
-    let idx = stmts
+    prepend_after_directives(stmts, Some(stmt))
+}
+
+/// Inserts `stmts` after the directive prologue of `to`, keeping their order.
+///
+/// The prologue is the run of string literal expression statements at the
+/// start of `to`, so pseudo-directives like `"use client"` and `"use server"`
+/// stay before the injected statements. A shebang needs no handling, as it's
+/// stored in [Module::shebang] and always printed first.
+pub fn prepend_after_directives<T: StmtLike>(to: &mut Vec<T>, stmts: impl IntoIterator<Item = T>) {
+    let idx = to
         .iter()
         .position(|item| match item.as_stmt() {
-            Some(&Stmt::Expr(ExprStmt { ref expr, .. }))
-                if match &**expr {
-                    Expr::Lit(Lit::Str(..)) => true,
-                    _ => false,
-                } =>
-            {
-                false
-            }
+            Some(&Stmt::Expr(ExprStmt { ref expr, .. })) => match &**expr {
+                Expr::Lit(Lit::Str(..)) => false,
+                _ => true,
+            },
             _ => true,
         })
-        .unwrap_or(stmts.len());
+        .unwrap_or(to.len());
 
-    stmts.insert(idx, stmt);
+    to.splice(idx..idx, stmts);
 }
 
 /// inject `stmts` after directives
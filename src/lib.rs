@@ -88,7 +88,7 @@ impl Compiler {
         input_src_map: &InputSourceMap,
     ) -> Result<Option<sourcemap::SourceMap>, Error> {
         self.run(|| -> Result<_, Error> {
-            let name = &fm.name;
+            let name = &*fm.name;
 
             // Load original source map
             match input_src_map {
@@ -654,6 +654,44 @@ fn deno_10282_2() {
     assert_eq!(output.to_string(), "const a = `\\n`;\n");
 }
 
+#[test]
+fn injected_imports_after_directives() {
+    let output = str_with_opt(
+        "#!/usr/bin/env node
+\"use strict\";
+\"use client\";
+export class Foo {}
+new Promise(() => {});",
+        Options {
+            is_module: true,
+            config: Config {
+                env: Some(swc_ecma_preset_env::Config {
+                    mode: Some(swc_ecma_preset_env::Mode::Usage),
+                    ..Default::default()
+                }),
+                module: Some(ModuleConfig::CommonJs(Default::default())),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .to_string();
+    println!("{}", output);
+
+    assert!(
+        output.starts_with("#!/usr/bin/env node\n\"use strict\";\n\"use client\";\n"),
+        "directives should precede injected statements"
+    );
+    assert_eq!(output.matches("\"use strict\"").count(), 1);
+
+    let core_js = output.find("require(\"core-js/modules/").unwrap();
+    let helper = output.find("function _classCallCheck").unwrap();
+    let body = output.find("new Promise").unwrap();
+    assert!(core_js < helper, "polyfills should precede helpers");
+    assert!(helper < body, "helpers should precede the module body");
+}
+
 #[testing::fixture("fixture/**/input/")]
 fn tests(dir: PathBuf) {
     let output = dir.parent().unwrap().join("output");